@@ -0,0 +1,105 @@
+// Compare authorization checks against the database with checks against the in-memory ACL index.
+//
+// Run with `cargo bench --bench authz`.
+
+#[macro_use]
+extern crate diesel_migrations;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use diesel::{Connection, SqliteConnection};
+
+use tagent::conditions::Timezone;
+use tagent::db::{is_authz_db, retrieve_all_acls, save_acl, transaction_mut};
+use tagent::index::AclIndex;
+use tagent::models::{AclAction, AclDecision, AuthzPolicy, NewAclJson, PatternType, PolicyMode};
+
+embed_migrations!();
+
+const SUBJECT: &str = "jobs@admin";
+
+// a database file with `n` literal and `n` wildcard ACLs for SUBJECT, plus the ACLs of other subjects
+fn setup(n: usize) -> (tempfile::TempDir, String) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let db = dir.path().join("tagent.db").to_str().unwrap().to_string();
+    let mut conn = SqliteConnection::establish(&db).unwrap();
+    embedded_migrations::run(&conn).unwrap();
+    transaction_mut(&mut conn, |conn| {
+        for i in 0..n {
+            for (sub, path, decision) in [
+                (
+                    SUBJECT,
+                    format!("/projects/p{}/data.txt", i),
+                    AclDecision::Allow,
+                ),
+                (
+                    SUBJECT,
+                    format!("/projects/p{}/.*\\.csv", i),
+                    AclDecision::Allow,
+                ),
+                (
+                    SUBJECT,
+                    format!("/projects/p{}/secret.*", i),
+                    AclDecision::Deny,
+                ),
+                (
+                    "files@admin",
+                    format!("/projects/p{}/.*", i),
+                    AclDecision::Allow,
+                ),
+            ] {
+                let acl = NewAclJson {
+                    subject: sub.to_string(),
+                    action: AclAction::Write,
+                    decision,
+                    path,
+                    user: String::from("self"),
+                    priority: 0,
+                    pattern_type: PatternType::Regex,
+                    valid_from: None,
+                    valid_until: None,
+                    enabled: true,
+                    source_cidrs: Vec::new(),
+                    time_windows: Vec::new(),
+                };
+                save_acl(conn, &acl, "tenants@admin")?;
+            }
+        }
+        Ok(())
+    })
+    .unwrap();
+    (dir, db)
+}
+
+fn bench_authz(c: &mut Criterion) {
+    let policy = AuthzPolicy {
+        mode: PolicyMode::DenyOverrides,
+        default_decision: AclDecision::Deny,
+        path_default_decisions: Vec::new(),
+        timezone: Timezone::default(),
+    };
+    let mut group = c.benchmark_group("is_authz");
+    for n in [10, 100, 500] {
+        let (_dir, db) = setup(n);
+        let path = format!("/projects/p{}/results.csv", n - 1);
+        let mut conn = SqliteConnection::establish(&db).unwrap();
+        let index = AclIndex::new(retrieve_all_acls(&mut conn).unwrap(), Vec::new());
+
+        // the handlers used to open a new connection for every check
+        group.bench_with_input(BenchmarkId::new("db_new_connection", n), &n, |b, _| {
+            b.iter(|| {
+                let mut conn = SqliteConnection::establish(&db).unwrap();
+                is_authz_db(&mut conn, &policy, SUBJECT, "self", &path, &AclAction::Read)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("db", n), &n, |b, _| {
+            b.iter(|| is_authz_db(&mut conn, &policy, SUBJECT, "self", &path, &AclAction::Read))
+        });
+        group.bench_with_input(BenchmarkId::new("index", n), &n, |b, _| {
+            b.iter(|| index.is_authz(&policy, SUBJECT, "self", &path, &AclAction::Read))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_authz);
+criterion_main!(benches);
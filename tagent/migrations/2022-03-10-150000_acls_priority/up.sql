@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "acls"
+ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- existing ACLs keep matching their path as a regex, except for paths without a `*`, which only
+-- ever matched literally; as regexes, their `.` and other metacharacters would match more paths
+ALTER TABLE "acls"
+ADD COLUMN pattern_type TEXT NOT NULL DEFAULT "regex";
+UPDATE "acls" SET pattern_type = 'literal' WHERE instr(path, '*') = 0;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- groups and roles; ACLs refer to them with subjects of the form group:<name> and role:<name>
+CREATE TABLE "principals" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    kind TEXT NOT NULL,
+    name TEXT NOT NULL,
+    create_by TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    UNIQUE (kind, name)
+);
+
+-- member is a subject, or another group or role (e.g., group:<name>) for nested memberships
+CREATE TABLE "memberships" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    principal TEXT NOT NULL,
+    member TEXT NOT NULL,
+    create_by TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    UNIQUE (principal, member)
+);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "acls"
+DROP COLUMN enabled;
+ALTER TABLE "acls"
+DROP COLUMN valid_until;
+ALTER TABLE "acls"
+DROP COLUMN valid_from;
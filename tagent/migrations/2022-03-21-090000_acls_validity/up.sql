@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- an ACL only applies between valid_from and valid_until (RFC 3339 timestamps; NULL is unbounded) and while enabled
+ALTER TABLE "acls"
+ADD COLUMN valid_from TEXT;
+ALTER TABLE "acls"
+ADD COLUMN valid_until TEXT;
+ALTER TABLE "acls"
+ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX acls_policy_key;
+ALTER TABLE "acls"
+DROP COLUMN policy_key;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- ACLs managed by the policy file have the key of their entry in the file; ACLs created through the API have none
+ALTER TABLE "acls"
+ADD COLUMN policy_key TEXT;
+CREATE UNIQUE INDEX acls_policy_key ON "acls" (policy_key);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+-- List and Delete did not exist; keep the replacements from allowing more than before
+DELETE FROM "acls" WHERE action = 'List' AND decision = 'Allow';
+UPDATE "acls" SET action = 'Read' WHERE action = 'List';
+UPDATE "acls" SET action = 'Write' WHERE action = 'Delete' AND decision = 'Allow';
+UPDATE "acls" SET action = 'Admin' WHERE action = 'Delete';
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- actions are stored by name; normalize the names of existing actions
+UPDATE "acls" SET action = 'Read' WHERE lower(trim(action)) = 'read';
+UPDATE "acls" SET action = 'Execute' WHERE lower(trim(action)) = 'execute';
+UPDATE "acls" SET action = 'Write' WHERE lower(trim(action)) = 'write';
+UPDATE "acls" SET action = 'Admin' WHERE lower(trim(action)) = 'admin';
+-- any other action used to be checked as Write; it must not become Admin, which would let an
+-- Allow on `/` manage ACLs
+UPDATE "acls" SET action = 'Write'
+WHERE action NOT IN ('Read', 'Execute', 'Write', 'Admin');
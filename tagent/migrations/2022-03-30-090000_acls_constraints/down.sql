@@ -0,0 +1,30 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX acls_policy_key;
+ALTER TABLE "acls" RENAME TO "acls_old";
+CREATE TABLE "acls" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subject TEXT NOT NULL,
+    action TEXT NOT NULL,
+    path TEXT NOT NULL,
+    user TEXT NOT NULL,
+    create_by TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    decision TEXT NOT NULL DEFAULT "Allow",
+    priority INTEGER NOT NULL DEFAULT 0,
+    pattern_type TEXT NOT NULL DEFAULT "regex",
+    valid_from TEXT,
+    valid_until TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    policy_key TEXT
+);
+INSERT INTO "acls" (
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key
+)
+SELECT
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key
+FROM "acls_old";
+UPDATE sqlite_sequence SET seq = (SELECT seq FROM sqlite_sequence WHERE name = 'acls_old') WHERE name = 'acls';
+DROP TABLE "acls_old";
+CREATE UNIQUE INDEX acls_policy_key ON "acls" (policy_key);
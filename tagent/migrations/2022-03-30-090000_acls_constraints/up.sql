@@ -0,0 +1,39 @@
+-- Your SQL goes here
+-- SQLite cannot add constraints to an existing table, so the table is rebuilt with CHECK constraints on the columns
+-- with a fixed set of values. Existing values the constraints would reject are fixed first, the way they were
+-- treated so far: decisions other than Allow denied, unknown pattern types matched literally, and paths were
+-- matched with a leading slash. ACLs without a subject never applied to anyone and are deleted.
+UPDATE "acls" SET decision = 'Deny' WHERE decision <> 'Allow';
+UPDATE "acls" SET pattern_type = 'literal' WHERE pattern_type NOT IN ('literal', 'glob', 'regex');
+UPDATE "acls" SET path = '/' || path WHERE substr(path, 1, 1) <> '/';
+DELETE FROM "acls" WHERE trim(subject) = '';
+DROP INDEX acls_policy_key;
+ALTER TABLE "acls" RENAME TO "acls_old";
+CREATE TABLE "acls" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subject TEXT NOT NULL CHECK (trim(subject) <> ''),
+    action TEXT NOT NULL CHECK (action IN ('List', 'Read', 'Execute', 'Write', 'Delete', 'Admin')),
+    path TEXT NOT NULL CHECK (substr(path, 1, 1) = '/'),
+    user TEXT NOT NULL,
+    create_by TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    decision TEXT NOT NULL DEFAULT 'Allow' CHECK (decision IN ('Allow', 'Deny')),
+    priority INTEGER NOT NULL DEFAULT 0,
+    pattern_type TEXT NOT NULL DEFAULT 'regex' CHECK (pattern_type IN ('literal', 'glob', 'regex')),
+    valid_from TEXT,
+    valid_until TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT 1 CHECK (enabled IN (0, 1)),
+    policy_key TEXT
+);
+INSERT INTO "acls" (
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key
+)
+SELECT
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key
+FROM "acls_old";
+-- ids of deleted ACLs are not reused
+UPDATE sqlite_sequence SET seq = (SELECT seq FROM sqlite_sequence WHERE name = 'acls_old') WHERE name = 'acls';
+DROP TABLE "acls_old";
+CREATE UNIQUE INDEX acls_policy_key ON "acls" (policy_key);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- every change to an ACL, with JSON snapshots of the ACL before and after the change; a created ACL
+-- has no snapshot before, and a deleted ACL none after. `version` counts the changes of each ACL from 1.
+CREATE TABLE "acl_history" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    acl_id INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    operation TEXT NOT NULL CHECK (operation IN ('create', 'update', 'delete', 'restore')),
+    actor TEXT NOT NULL,
+    time TEXT NOT NULL,
+    before_acl TEXT,
+    after_acl TEXT,
+    UNIQUE (acl_id, version)
+);
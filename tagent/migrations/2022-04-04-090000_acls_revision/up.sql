@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- the revision of an ACL is incremented whenever the ACL is changed; it is the ACL's ETag
+ALTER TABLE "acls"
+ADD COLUMN revision INTEGER NOT NULL DEFAULT 1 CHECK (revision > 0);
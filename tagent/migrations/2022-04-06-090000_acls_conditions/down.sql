@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "acls"
+DROP COLUMN time_windows;
+ALTER TABLE "acls"
+DROP COLUMN source_cidrs;
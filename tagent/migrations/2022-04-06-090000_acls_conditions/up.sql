@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- optional conditions of an ACL: comma-separated CIDR ranges of the addresses requests must come from,
+-- and comma-separated weekly time windows, e.g., `Mon-Fri 08:00-18:00`, during which the ACL applies
+ALTER TABLE "acls"
+ADD COLUMN source_cidrs TEXT;
+ALTER TABLE "acls"
+ADD COLUMN time_windows TEXT;
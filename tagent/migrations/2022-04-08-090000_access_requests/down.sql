@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX access_requests_state;
+DROP TABLE "access_requests";
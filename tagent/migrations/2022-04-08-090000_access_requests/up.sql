@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- requests of subjects for access to a path, and how administrators decided them; an approved request refers to
+-- the ACL created for it. `valid_until` is the end of the access requested, if any.
+CREATE TABLE "access_requests" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subject TEXT NOT NULL CHECK (trim(subject) <> ''),
+    action TEXT NOT NULL CHECK (action IN ('List', 'Read', 'Execute', 'Write', 'Delete', 'Admin')),
+    path TEXT NOT NULL CHECK (substr(path, 1, 1) = '/'),
+    pattern_type TEXT NOT NULL CHECK (pattern_type IN ('literal', 'glob', 'regex')),
+    user TEXT NOT NULL,
+    justification TEXT NOT NULL CHECK (trim(justification) <> ''),
+    valid_until TEXT,
+    state TEXT NOT NULL DEFAULT 'pending' CHECK (state IN ('pending', 'approved', 'rejected')),
+    create_time TEXT NOT NULL,
+    reviewed_by TEXT,
+    review_time TEXT,
+    review_comment TEXT,
+    acl_id INTEGER
+);
+CREATE INDEX access_requests_state ON "access_requests" (state);
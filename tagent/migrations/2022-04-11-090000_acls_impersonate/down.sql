@@ -0,0 +1,34 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM "acls" WHERE action = 'Impersonate';
+DROP INDEX acls_policy_key;
+ALTER TABLE "acls" RENAME TO "acls_old";
+CREATE TABLE "acls" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subject TEXT NOT NULL CHECK (trim(subject) <> ''),
+    action TEXT NOT NULL CHECK (action IN ('List', 'Read', 'Execute', 'Write', 'Delete', 'Admin')),
+    path TEXT NOT NULL CHECK (substr(path, 1, 1) = '/'),
+    user TEXT NOT NULL,
+    create_by TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    decision TEXT NOT NULL DEFAULT 'Allow' CHECK (decision IN ('Allow', 'Deny')),
+    priority INTEGER NOT NULL DEFAULT 0,
+    pattern_type TEXT NOT NULL DEFAULT 'regex' CHECK (pattern_type IN ('literal', 'glob', 'regex')),
+    valid_from TEXT,
+    valid_until TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT 1 CHECK (enabled IN (0, 1)),
+    policy_key TEXT,
+    revision INTEGER NOT NULL DEFAULT 1 CHECK (revision > 0),
+    source_cidrs TEXT,
+    time_windows TEXT
+);
+INSERT INTO "acls" (
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key, revision, source_cidrs, time_windows
+)
+SELECT
+    id, subject, action, path, user, create_by, create_time, decision, priority, pattern_type, valid_from,
+    valid_until, enabled, policy_key, revision, source_cidrs, time_windows
+FROM "acls_old";
+UPDATE sqlite_sequence SET seq = (SELECT seq FROM sqlite_sequence WHERE name = 'acls_old') WHERE name = 'acls';
+DROP TABLE "acls_old";
+CREATE UNIQUE INDEX acls_policy_key ON "acls" (policy_key);
@@ -0,0 +1,418 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::claims::{has_variables, with_placeholders};
+use crate::models::{AclAction, AclDecision, DbAcl, PatternType, PolicyMode};
+use crate::patterns::Matcher;
+
+// Static analysis of a subject's ACLs
+// ===================================
+
+/// The kinds of problems the analysis can find in a set of ACLs.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum FindingKind {
+    // the ACL never determines a decision because an ACL with the opposite decision always takes precedence
+    Shadowed,
+    // another ACL has the same subject, action, path, pattern type, user, decision and conditions
+    ExactDuplicate,
+    // another ACL with the same decision matches every request this ACL matches
+    SemanticDuplicate,
+    // the path or user should be a regular expression but is not valid, so it only matches itself
+    InvalidRegex,
+    // the path matches every path
+    MatchesAllPaths,
+}
+
+impl fmt::Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Shadowed => write!(f, "Shadowed"),
+            Self::ExactDuplicate => write!(f, "ExactDuplicate"),
+            Self::SemanticDuplicate => write!(f, "SemanticDuplicate"),
+            Self::InvalidRegex => write!(f, "InvalidRegex"),
+            Self::MatchesAllPaths => write!(f, "MatchesAllPaths"),
+        }
+    }
+}
+
+/// A single problem found in a set of ACLs.
+///
+/// `related_acl_id` is the id of the other ACL involved in the problem, if any; e.g., the
+/// ACL that shadows `acl_id`.
+///
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AclFinding {
+    pub acl_id: i32,
+    pub kind: FindingKind,
+    pub related_acl_id: Option<i32>,
+    pub message: String,
+}
+
+impl AclFinding {
+    // whether the finding involves the ACL with id `id`
+    pub fn involves(&self, id: i32) -> bool {
+        self.acl_id == id || self.related_acl_id == Some(id)
+    }
+}
+
+// Paths used to decide whether a path pattern matches every path. Patterns are regular
+// expressions, so this is a heuristic: a pattern matching all of these paths is assumed to
+// match every path.
+const PROBE_PATHS: [&str; 6] = [
+    "/",
+    "/a",
+    "/a/b/c.txt",
+    "/.hidden",
+    "/Some Dir/ü-1.bin",
+    "/tmp/x.txt.bak",
+];
+
+// users are only treated as regular expressions when they contain a wildcard
+fn is_pattern(field: &str) -> bool {
+    field.contains('*')
+}
+
+// template variables are replaced by a placeholder; which paths a templated ACL matches depends
+// on the request, so it is only known to cover the same template
+fn path_matcher(acl: &DbAcl) -> Matcher {
+    Matcher::for_db_path(&acl.pattern_type, &with_placeholders(&acl.path))
+}
+
+fn user_matcher(user: &str) -> Matcher {
+    Matcher::for_user(&with_placeholders(user))
+}
+
+fn matches_all_paths(acl: &DbAcl) -> bool {
+    match path_matcher(acl).regex() {
+        Some(re) => PROBE_PATHS.iter().all(|p| re.is_match(p)),
+        None => false,
+    }
+}
+
+// returns the only path an ACL path matches, if it is known to match a single path
+fn single_path(acl: &DbAcl) -> Option<&str> {
+    if has_variables(&acl.path) {
+        return None;
+    }
+    let single = match acl.pattern_type {
+        PatternType::Literal => true,
+        PatternType::Glob => !acl.path.contains(['*', '?']),
+        PatternType::Regex => regex::escape(&acl.path) == acl.path,
+    };
+    if single {
+        Some(&acl.path)
+    } else {
+        None
+    }
+}
+
+// checks whether every path matched by the ACL `specific` is also matched by the ACL `general`.
+// This is exact when `specific` matches a single path; otherwise, only equal patterns and
+// patterns matching everything are known to cover each other.
+fn path_covers(general: &DbAcl, specific: &DbAcl) -> bool {
+    if (general.path == specific.path && general.pattern_type == specific.pattern_type)
+        || matches_all_paths(general)
+    {
+        return true;
+    }
+    match single_path(specific) {
+        Some(p) if !has_variables(&general.path) => path_matcher(general).is_match(p),
+        _ => false,
+    }
+}
+
+// checks whether every user matched by the ACL user `specific` is also matched by `general`
+fn user_covers(general: &str, specific: &str) -> bool {
+    if general == specific || general == ".*" {
+        return true;
+    }
+    if !is_pattern(general) || is_pattern(specific) || has_variables(general) {
+        return false;
+    }
+    user_matcher(general).is_match(specific)
+}
+
+// checks whether the conditions of `general` hold whenever those of `specific` do; this is only
+// known when `general` has no conditions or the same conditions
+fn conditions_cover(general: &DbAcl, specific: &DbAcl) -> bool {
+    match general.conditions() {
+        Some(c) if c.is_empty() => true,
+        Some(c) => specific.conditions() == Some(c),
+        None => false,
+    }
+}
+
+// checks whether the ACL `general` matches every request matched by the ACL `specific`, at every
+// time `specific` applies
+fn covers(general: &DbAcl, specific: &DbAcl) -> bool {
+    general.subject == specific.subject
+        && general.validity().covers(&specific.validity())
+        && conditions_cover(general, specific)
+        && user_covers(&general.user, &specific.user)
+        && path_covers(general, specific)
+        && AclAction::ALL
+            .iter()
+            .chain(&[AclAction::Impersonate])
+            .all(|a| !specific.matches_action(a) || general.matches_action(a))
+}
+
+/// Check whether replacing the Allow ACL `general` with the Allow ACL `specific` only narrows the
+/// grant: `general` matches every request `specific` matches, at every time it applies, and
+/// `specific` does not take precedence over more ACLs, i.e., its priority is not lower.
+///
+pub fn narrows(general: &DbAcl, specific: &DbAcl) -> bool {
+    general.decision == AclDecision::Allow
+        && specific.decision == AclDecision::Allow
+        && specific.priority >= general.priority
+        && covers(general, specific)
+}
+
+// checks whether the decision of `first` takes precedence over the decision of `second` when both match
+fn takes_precedence(mode: &PolicyMode, first: &DbAcl, second: &DbAcl) -> bool {
+    if first.decision == second.decision {
+        return false;
+    }
+    match mode {
+        PolicyMode::DenyOverrides => first.decision == AclDecision::Deny,
+        PolicyMode::AllowOverrides => first.decision == AclDecision::Allow,
+        PolicyMode::FirstMatch => (first.priority, first.id) < (second.priority, second.id),
+    }
+}
+
+fn is_exact_duplicate(a: &DbAcl, b: &DbAcl) -> bool {
+    a.subject == b.subject
+        && a.action == b.action
+        && a.path == b.path
+        && a.pattern_type == b.pattern_type
+        && a.user == b.user
+        && a.decision == b.decision
+        && a.source_cidrs == b.source_cidrs
+        && a.time_windows == b.time_windows
+}
+
+/// Analyze a set of ACLs.
+///
+/// The ACLs are expected to belong to a single subject, though ACLs for different subjects
+/// never interact. `mode` is the policy mode used to decide which ACLs take precedence.
+///
+/// Duplicates are reported on the ACL with the larger id, so that a pair of duplicates is
+/// only reported once.
+///
+pub fn analyze_acls(mode: &PolicyMode, acls: &[DbAcl]) -> Vec<AclFinding> {
+    let mut findings = Vec::new();
+    for acl in acls {
+        for (field, value, matcher) in [
+            ("path", &acl.path, path_matcher(acl)),
+            ("user", &acl.user, user_matcher(&acl.user)),
+        ] {
+            if let Some(e) = matcher.error() {
+                findings.push(AclFinding {
+                    acl_id: acl.id,
+                    kind: FindingKind::InvalidRegex,
+                    related_acl_id: None,
+                    message: format!(
+                        "the {} {} is not a valid regex and never matches; details: {}",
+                        field, value, e
+                    ),
+                });
+            }
+        }
+        if matches_all_paths(acl) {
+            findings.push(AclFinding {
+                acl_id: acl.id,
+                kind: FindingKind::MatchesAllPaths,
+                related_acl_id: None,
+                message: format!("the path {} matches every path", acl.path),
+            });
+        }
+        for other in acls {
+            if other.id == acl.id {
+                continue;
+            }
+            if takes_precedence(mode, other, acl) && covers(other, acl) {
+                findings.push(AclFinding {
+                    acl_id: acl.id,
+                    kind: FindingKind::Shadowed,
+                    related_acl_id: Some(other.id),
+                    message: format!(
+                        "the {} ACL {} is shadowed by the {} ACL {}",
+                        acl.decision, acl.id, other.decision, other.id
+                    ),
+                });
+            } else if other.id < acl.id && is_exact_duplicate(other, acl) {
+                findings.push(AclFinding {
+                    acl_id: acl.id,
+                    kind: FindingKind::ExactDuplicate,
+                    related_acl_id: Some(other.id),
+                    message: format!("ACL {} is a duplicate of ACL {}", acl.id, other.id),
+                });
+            } else if other.decision == acl.decision
+                && !is_exact_duplicate(other, acl)
+                && covers(other, acl)
+                && (other.id < acl.id || !covers(acl, other))
+            {
+                findings.push(AclFinding {
+                    acl_id: acl.id,
+                    kind: FindingKind::SemanticDuplicate,
+                    related_acl_id: Some(other.id),
+                    message: format!(
+                        "ACL {} is redundant; ACL {} matches every request it matches",
+                        acl.id, other.id
+                    ),
+                });
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn acl(id: i32, action: &str, path: &str, decision: &str) -> DbAcl {
+        DbAcl {
+            id,
+            subject: String::from("jobs@admin"),
+            action: action.parse().unwrap(),
+            path: path.to_string(),
+            user: String::from("self"),
+            create_by: String::from("tenants@admin"),
+            create_time: String::from("2022-02-25T02:22:28.537654901+00:00"),
+            decision: decision.parse().unwrap(),
+            priority: 0,
+            pattern_type: PatternType::Regex,
+            valid_from: None,
+            valid_until: None,
+            enabled: true,
+            policy_key: None,
+            revision: 1,
+            source_cidrs: None,
+            time_windows: None,
+        }
+    }
+
+    fn literal(id: i32, action: &str, path: &str, decision: &str) -> DbAcl {
+        DbAcl {
+            pattern_type: PatternType::Literal,
+            ..acl(id, action, path, decision)
+        }
+    }
+
+    fn kinds(findings: &[AclFinding], id: i32) -> Vec<FindingKind> {
+        findings
+            .iter()
+            .filter(|f| f.acl_id == id)
+            .map(|f| f.kind)
+            .collect()
+    }
+
+    #[test]
+    fn allow_is_shadowed_by_covering_deny_list() {
+        let mut acls = vec![
+            literal(1, "Write", "/data/a.txt", "Allow"),
+            acl(2, "List", "/data/.*", "Deny"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert_eq!(kinds(&findings, 1), vec![FindingKind::Shadowed]);
+        assert_eq!(findings[0].related_acl_id, Some(2));
+        // in AllowOverrides mode, the Allow takes precedence instead
+        let findings = analyze_acls(&PolicyMode::AllowOverrides, &acls);
+        assert!(kinds(&findings, 1).is_empty());
+        // a Deny of Read leaves List requests allowed
+        acls[1] = acl(2, "Read", "/data/.*", "Deny");
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert!(kinds(&findings, 1).is_empty());
+    }
+
+    #[test]
+    fn allow_is_not_shadowed_by_deny_of_higher_action() {
+        // a Deny of Write leaves Read requests allowed
+        let acls = vec![
+            acl(1, "Write", "/data/a.txt", "Allow"),
+            acl(2, "Write", "/data/a.txt", "Deny"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert!(kinds(&findings, 1).is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_reported_once() {
+        let acls = vec![
+            literal(1, "Read", "/data/a.txt", "Allow"),
+            literal(2, "Read", "/data/a.txt", "Allow"),
+            acl(3, "Write", "/data/.*", "Allow"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert_eq!(
+            kinds(&findings, 2),
+            vec![FindingKind::ExactDuplicate, FindingKind::SemanticDuplicate]
+        );
+        assert_eq!(kinds(&findings, 1), vec![FindingKind::SemanticDuplicate]);
+        assert!(kinds(&findings, 3).is_empty());
+    }
+
+    #[test]
+    fn regex_is_not_covered_by_literal_of_same_path() {
+        // the regex also matches paths such as /data/aXtxt
+        let acls = vec![
+            acl(1, "Write", "/data/a.txt", "Allow"),
+            literal(2, "Read", "/data/a.txt", "Allow"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert!(kinds(&findings, 1).is_empty());
+        assert_eq!(kinds(&findings, 2), vec![FindingKind::SemanticDuplicate]);
+    }
+
+    #[test]
+    fn time_bounded_and_disabled_acls_do_not_shadow() {
+        let deny = acl(2, "List", "/data/.*", "Deny");
+        let bounded = DbAcl {
+            valid_until: Some(String::from("2022-03-25T00:00:00+00:00")),
+            ..acl(2, "List", "/data/.*", "Deny")
+        };
+        let disabled = DbAcl {
+            enabled: false,
+            ..acl(2, "List", "/data/.*", "Deny")
+        };
+        for (other, shadowed) in [(deny, true), (bounded, false), (disabled, false)] {
+            let acls = vec![literal(1, "Write", "/data/a.txt", "Allow"), other];
+            let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+            assert_eq!(
+                kinds(&findings, 1).contains(&FindingKind::Shadowed),
+                shadowed
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_and_match_all_patterns_are_reported() {
+        let acls = vec![
+            acl(1, "Read", "/data/*(", "Allow"),
+            acl(2, "Read", "/.*", "Allow"),
+            acl(3, "Read", "/data/.*", "Allow"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        // the invalid pattern is also covered by the pattern matching every path
+        assert_eq!(
+            kinds(&findings, 1),
+            vec![FindingKind::InvalidRegex, FindingKind::SemanticDuplicate]
+        );
+        assert_eq!(kinds(&findings, 2), vec![FindingKind::MatchesAllPaths]);
+        assert_eq!(kinds(&findings, 3), vec![FindingKind::SemanticDuplicate]);
+    }
+
+    #[test]
+    fn templated_acls_only_cover_the_same_template() {
+        // /home/${sub}/a.txt is not matched for every subject, so the Deny does not shadow it
+        let acls = vec![
+            literal(1, "Write", "/home/jobs@admin/a.txt", "Allow"),
+            acl(2, "Read", "/home/${sub}/.*", "Deny"),
+            acl(3, "Read", "/home/${sub}/.*", "Deny"),
+        ];
+        let findings = analyze_acls(&PolicyMode::DenyOverrides, &acls);
+        assert!(kinds(&findings, 1).is_empty());
+        assert!(kinds(&findings, 2).is_empty());
+        assert_eq!(kinds(&findings, 3), vec![FindingKind::ExactDuplicate]);
+    }
+}
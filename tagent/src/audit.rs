@@ -0,0 +1,102 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+
+use crate::claims::ClaimContext;
+use crate::models::AuthzCheckJson;
+
+// Audit log of authorization checks
+// =================================
+
+/// An authorization check answered by the server.
+///
+/// The context of the check is kept so that the check can be evaluated again exactly as it was
+/// made, e.g., to simulate changes to ACLs.
+///
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditEntry {
+    #[serde(flatten)]
+    pub check: AuthzCheckJson,
+    pub allowed: bool,
+    #[serde(skip)]
+    pub context: ClaimContext,
+}
+
+/// The most recent authorization checks, in memory; the oldest checks are dropped once the log
+/// holds `capacity` checks.
+#[derive(Debug)]
+pub struct AuditLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, check: AuthzCheckJson, allowed: bool, context: &ClaimContext) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            check,
+            allowed,
+            context: context.clone(),
+        });
+    }
+
+    // returns up to `n` of the most recent checks, most recent first; a check made repeatedly
+    // with the same subject, user, action and path is only returned once
+    pub fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut result: Vec<AuditEntry> = Vec::new();
+        for entry in entries.iter().rev() {
+            if result.len() == n {
+                break;
+            }
+            if !result.iter().any(|e| e.check == entry.check) {
+                result.push(entry.clone());
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::AclAction;
+
+    fn check(path: &str) -> AuthzCheckJson {
+        AuthzCheckJson {
+            subject: String::from("jobs@admin"),
+            user: String::from("self"),
+            action: AclAction::Read,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn only_the_most_recent_checks_are_kept() {
+        let log = AuditLog::new(3);
+        let context = ClaimContext::for_subject("jobs@admin");
+        for path in ["/a", "/b", "/c", "/b", "/d"] {
+            log.record(check(path), true, &context);
+        }
+        let paths: Vec<String> = log.recent(10).into_iter().map(|e| e.check.path).collect();
+        assert_eq!(paths, vec!["/d", "/b", "/c"]);
+        assert_eq!(log.recent(1).len(), 1);
+
+        let disabled = AuditLog::new(0);
+        disabled.record(check("/a"), true, &context);
+        assert!(disabled.recent(10).is_empty());
+    }
+}
@@ -1,11 +1,13 @@
 use actix_web::HttpRequest;
 use jwt_simple::algorithms::RS256PublicKey;
-use jwt_simple::claims::NoCustomClaims;
 use jwt_simple::prelude::*;
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::IpAddr;
 
 // JWT claims ---
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
@@ -20,11 +22,32 @@ fn get_header_value<'a>(req: &'a HttpRequest, header_name: &str) -> Option<&'a s
     req.headers().get(header_name)?.to_str().ok()
 }
 
+// the header in which a subject names the user it makes a request on behalf of
+pub const ON_BEHALF_OF_HEADER: &str = "x-tapis-user";
+
+// the subject of a verified token, and its claims other than the registered claims (e.g., `sub`,
+// `exp`), such as `tapis/tenant_id`; `source` is the address of the peer that sent the request,
+// and `on_behalf_of` the user named in the ON_BEHALF_OF_HEADER, which is not verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestClaims {
+    pub subject: String,
+    pub custom: Map<String, Value>,
+    pub source: Option<IpAddr>,
+    pub on_behalf_of: Option<String>,
+}
+
 pub async fn get_subject_of_request(
     req: HttpRequest,
     pub_key: &RS256PublicKey,
 ) -> Result<String, String> {
-    debug!("top of get_subject_of_request");
+    Ok(get_claims_of_request(req, pub_key).await?.subject)
+}
+
+pub async fn get_claims_of_request(
+    req: HttpRequest,
+    pub_key: &RS256PublicKey,
+) -> Result<RequestClaims, String> {
+    debug!("top of get_claims_of_request");
     let token = get_header_value(&req, "x-tapis-token");
     debug!("returned from get_header_value..");
     let token = match token {
@@ -33,7 +56,7 @@ pub async fn get_subject_of_request(
     };
     debug!("got token from header, {}", token);
     // validate token using public key; get claims
-    let claims = pub_key.verify_token::<NoCustomClaims>(token, None);
+    let claims = pub_key.verify_token::<Map<String, Value>>(token, None);
     let claims = match claims {
         Ok(claims) => claims,
         Err(error) => {
@@ -43,7 +66,12 @@ pub async fn get_subject_of_request(
         }
     };
     match claims.subject {
-        Some(sub) => Ok(sub),
+        Some(subject) => Ok(RequestClaims {
+            subject,
+            custom: claims.custom,
+            source: req.peer_addr().map(|a| a.ip()),
+            on_behalf_of: get_header_value(&req, ON_BEHALF_OF_HEADER).map(String::from),
+        }),
         None => {
             let msg = "token claims did not have a subject!".to_string();
             error!("{}", msg);
@@ -0,0 +1,223 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Template variables in ACLs
+// ==========================
+
+/// The variables that are always available to ACL templates.
+///
+/// `sub` is the subject of the request; Tapis subjects have the form `<username>@<tenant>`, from
+/// which `username` and `tenant` are taken unless the token has the claims configured for them.
+///
+pub const BUILTIN_VARIABLES: [&str; 3] = ["sub", "username", "tenant"];
+
+/// The values of the template variables for a request, and what is known about the request to
+/// check the conditions of ACLs: the address it came from and the local time it was made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimContext {
+    subject: String,
+    values: HashMap<String, String>,
+    source: Option<IpAddr>,
+    time: DateTime<FixedOffset>,
+}
+
+impl ClaimContext {
+    /// The variables that can be derived from the subject alone, e.g., when a request is checked on
+    /// behalf of another subject, whose token is not available.
+    pub fn for_subject(sub: &str) -> Self {
+        let mut values = HashMap::new();
+        values.insert(String::from("sub"), sub.to_string());
+        if let Some((username, tenant)) = sub.rsplit_once('@') {
+            values.insert(String::from("username"), username.to_string());
+            values.insert(String::from("tenant"), tenant.to_string());
+        }
+        ClaimContext {
+            subject: sub.to_string(),
+            values,
+            source: None,
+            time: Utc::now().into(),
+        }
+    }
+
+    /// The variables of a verified token; `claim_variables` maps variable names to the claims
+    /// they are taken from. Claims that are missing or are not strings or numbers are ignored.
+    pub fn from_claims(
+        sub: &str,
+        claims: &Map<String, Value>,
+        claim_variables: &HashMap<String, String>,
+    ) -> Self {
+        let mut context = Self::for_subject(sub);
+        for (variable, claim) in claim_variables {
+            let value = match claims.get(claim) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Number(n)) => n.to_string(),
+                _ => continue,
+            };
+            context.values.insert(variable.clone(), value);
+        }
+        context
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn get(&self, variable: &str) -> Option<&str> {
+        self.values.get(variable).map(|v| v.as_str())
+    }
+
+    // the same context for a request from an address
+    pub fn with_source(self, source: Option<IpAddr>) -> Self {
+        ClaimContext { source, ..self }
+    }
+
+    // the same context for a request made at a time, in the timezone time windows are evaluated in
+    pub fn at(self, time: DateTime<FixedOffset>) -> Self {
+        ClaimContext { time, ..self }
+    }
+
+    pub fn source(&self) -> Option<IpAddr> {
+        self.source
+    }
+
+    pub fn time(&self) -> &DateTime<FixedOffset> {
+        &self.time
+    }
+}
+
+// whether an ACL field contains template variables
+pub fn has_variables(field: &str) -> bool {
+    field.contains("${")
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// a piece of an ACL field: either text or a template variable
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+// splits a field into text and `${name}` variables
+fn parse(field: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = field;
+    while let Some(start) = rest.find("${") {
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable in {}", field))?;
+        let name = &after[..end];
+        if !is_variable_name(name) {
+            return Err(format!("invalid variable name ${{{}}}", name));
+        }
+        parts.push(Part::Variable(name));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+/// The names of the template variables in an ACL field, in order.
+pub fn template_variables(field: &str) -> Result<Vec<&str>, String> {
+    Ok(parse(field)?
+        .into_iter()
+        .filter_map(|p| match p {
+            Part::Variable(name) => Some(name),
+            Part::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Substitute the variables of an ACL field.
+///
+/// The text is transformed by `text` and the values of the variables by `value`, e.g., to escape
+/// them for a regex. Returns None if the field is not a valid template, or if a variable has no
+/// value or `value` rejects it.
+///
+pub fn substitute<T, V>(field: &str, context: &ClaimContext, text: T, value: V) -> Option<String>
+where
+    T: Fn(&str) -> String,
+    V: Fn(&str) -> Option<String>,
+{
+    let mut result = String::new();
+    for part in parse(field).ok()? {
+        match part {
+            Part::Text(t) => result.push_str(&text(t)),
+            Part::Variable(name) => result.push_str(&value(context.get(name)?)?),
+        }
+    }
+    Some(result)
+}
+
+// the field with every variable replaced by a plain value, so that the rest of the field can be
+// checked, e.g., compiled as a regex
+pub fn with_placeholders(field: &str) -> String {
+    match parse(field) {
+        Ok(parts) => parts
+            .into_iter()
+            .map(|p| match p {
+                Part::Text(t) => t,
+                Part::Variable(_) => "x",
+            })
+            .collect(),
+        Err(_) => field.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn variables_are_parsed_and_substituted() {
+        assert_eq!(
+            template_variables("/home/${sub}/${project_1}"),
+            Ok(vec!["sub", "project_1"])
+        );
+        assert!(template_variables("/home/${sub").is_err());
+        assert!(template_variables("/home/${1x}").is_err());
+        assert_eq!(template_variables("/home/$x{y}"), Ok(vec![]));
+
+        let mut claims = Map::new();
+        claims.insert(String::from("tapis/tenant_id"), Value::from("dev"));
+        claims.insert(String::from("group"), Value::from(7));
+        let variables = HashMap::from([
+            (String::from("tenant"), String::from("tapis/tenant_id")),
+            (String::from("group"), String::from("group")),
+            (String::from("missing"), String::from("nope")),
+        ]);
+        let context = ClaimContext::from_claims("jobs@admin", &claims, &variables);
+        assert_eq!(context.get("username"), Some("jobs"));
+        assert_eq!(context.get("tenant"), Some("dev"));
+        assert_eq!(context.get("group"), Some("7"));
+        assert_eq!(context.get("missing"), None);
+
+        let raw = |s: &str| s.to_string();
+        assert_eq!(
+            substitute("/t/${tenant}/${sub}", &context, raw, |v| Some(
+                regex::escape(v)
+            )),
+            Some(String::from("/t/dev/jobs@admin"))
+        );
+        assert_eq!(
+            substitute("/t/${missing}", &context, raw, |v| Some(v.to_string())),
+            None
+        );
+        assert_eq!(with_placeholders("/home/${sub}/.*"), "/home/x/.*");
+    }
+}
@@ -0,0 +1,431 @@
+use chrono::{DateTime, Datelike, FixedOffset, Offset, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::claims::ClaimContext;
+use crate::models::AclDecision;
+
+// Conditions of ACLs
+// ==================
+
+// the separator of the items of a condition stored in the db
+const ITEM_SEPARATOR: char = ',';
+
+// parses an IP network, e.g., `10.0.0.0/8`; a single address is a network of its own. The host bits
+// of a network are cleared, so `10.1.2.3/8` is `10.0.0.0/8`
+pub fn parse_cidr(cidr: &str) -> Result<IpNet, String> {
+    let cidr = cidr.trim();
+    match IpNet::from_str(cidr) {
+        Ok(net) => Ok(net.trunc()),
+        Err(_) => IpAddr::from_str(cidr)
+            .map(IpNet::from)
+            .map_err(|_| format!("{} is not an IP address or CIDR range", cidr)),
+    }
+}
+
+// parses a time of day, `HH:MM`, into minutes since midnight; `24:00` is the end of the day
+fn parse_minutes(time: &str) -> Result<u32, String> {
+    let invalid = || format!("{} is not a time of day (HH:MM)", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return Err(invalid());
+    }
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    match (hours, minutes) {
+        (24, 0) => Ok(24 * 60),
+        (0..=23, 0..=59) => Ok(hours * 60 + minutes),
+        _ => Err(invalid()),
+    }
+}
+
+/// A weekly period during which an ACL applies, e.g., `Mon-Fri 08:00-18:00`.
+///
+/// The days are a single day or a range of days, which can wrap around the end of the week (e.g.,
+/// `Sat-Sun` or `Fri-Mon`); without days, the window applies every day. A window whose end is not
+/// later than its start, e.g., `Fri 22:00-02:00`, ends on the day after it starts.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    days: Option<(Weekday, Weekday)>,
+    // minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    fn includes_day(&self, day: Weekday) -> bool {
+        match self.days {
+            None => true,
+            Some((first, last)) => {
+                let (first, last, day) = (
+                    first.num_days_from_monday(),
+                    last.num_days_from_monday(),
+                    day.num_days_from_monday(),
+                );
+                if first <= last {
+                    first <= day && day <= last
+                } else {
+                    day >= first || day <= last
+                }
+            }
+        }
+    }
+
+    /// Whether a local time falls within the window.
+    pub fn contains(&self, time: &DateTime<FixedOffset>) -> bool {
+        let minute = time.hour() * 60 + time.minute();
+        let day = time.weekday();
+        if self.start < self.end {
+            self.includes_day(day) && self.start <= minute && minute < self.end
+        } else {
+            (self.includes_day(day) && minute >= self.start)
+                || (self.includes_day(day.pred()) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(' ') {
+            Some((days, times)) => (Some(days.trim()), times),
+            None => (None, s),
+        };
+        let days = match days {
+            None => None,
+            Some(days) => {
+                let parse_day = |d: &str| {
+                    Weekday::from_str(d).map_err(|_| format!("{} is not a day of the week", d))
+                };
+                let (first, last) = days.split_once('-').unwrap_or((days, days));
+                Some((parse_day(first)?, parse_day(last)?))
+            }
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("{} is not a time window, e.g., Mon-Fri 08:00-18:00", s))?;
+        let (start, end) = (parse_minutes(start)?, parse_minutes(end)?);
+        if start == 24 * 60 {
+            return Err(format!("{} starts at the end of the day", s));
+        }
+        Ok(TimeWindow { days, start, end })
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.days {
+            Some((first, last)) if first == last => write!(f, "{} ", first)?,
+            Some((first, last)) => write!(f, "{}-{} ", first, last)?,
+            None => (),
+        }
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// The conditions under which an ACL applies, in addition to its subject, user, path and action.
+///
+/// An ACL with source ranges only applies to requests from an address within one of them, and an
+/// ACL with time windows only applies at a time within one of them, in the timezone of the policy.
+///
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AclConditions {
+    pub source_cidrs: Vec<IpNet>,
+    pub time_windows: Vec<TimeWindow>,
+}
+
+impl AclConditions {
+    /// Parse the conditions of an ACL, as stored in the db.
+    pub fn parse(source_cidrs: Option<&str>, time_windows: Option<&str>) -> Result<Self, String> {
+        Ok(AclConditions {
+            source_cidrs: split_items(source_cidrs)
+                .iter()
+                .map(|c| parse_cidr(c))
+                .collect::<Result<_, _>>()?,
+            time_windows: split_items(time_windows)
+                .iter()
+                .map(|w| TimeWindow::from_str(w))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.source_cidrs.is_empty() && self.time_windows.is_empty()
+    }
+
+    /// Whether a request from `source` satisfies the source ranges; None if the ACL has source
+    /// ranges but the source of the request is not known.
+    pub fn source_matches(&self, source: Option<IpAddr>) -> Option<bool> {
+        if self.source_cidrs.is_empty() {
+            return Some(true);
+        }
+        // IPv4 clients of a server listening on IPv6 have IPv4-mapped addresses
+        let source = source?.to_canonical();
+        Some(self.source_cidrs.iter().any(|net| net.contains(&source)))
+    }
+
+    /// Whether a request at a local time satisfies the time windows.
+    pub fn time_matches(&self, time: &DateTime<FixedOffset>) -> bool {
+        self.time_windows.is_empty() || self.time_windows.iter().any(|w| w.contains(time))
+    }
+}
+
+/// Whether the conditions of an ACL with a decision hold for a request.
+///
+/// A condition that cannot be checked fails closed: an Allow ACL does not apply, while a Deny ACL
+/// does. This is the case when the source of the request is not known, or when the conditions
+/// could not be parsed (`conditions` is None).
+///
+pub fn conditions_hold(
+    conditions: Option<&AclConditions>,
+    decision: AclDecision,
+    context: &ClaimContext,
+) -> bool {
+    let fail_closed = decision == AclDecision::Deny;
+    match conditions {
+        Some(c) => {
+            c.source_matches(context.source()).unwrap_or(fail_closed)
+                && c.time_matches(context.time())
+        }
+        None => fail_closed,
+    }
+}
+
+// joins the items of a condition for storage in the db, normalizing the items `parse` accepts;
+// None if there are no items
+pub fn join_items<T, P>(items: &[String], parse: P) -> Option<String>
+where
+    T: fmt::Display,
+    P: Fn(&str) -> Result<T, String>,
+{
+    if items.is_empty() {
+        return None;
+    }
+    let normalized: Vec<String> = items
+        .iter()
+        .map(|i| match parse(i) {
+            Ok(t) => t.to_string(),
+            Err(_) => i.trim().to_string(),
+        })
+        .collect();
+    Some(normalized.join(&ITEM_SEPARATOR.to_string()))
+}
+
+// the items of a condition stored in the db
+pub fn split_items(items: Option<&str>) -> Vec<String> {
+    items
+        .unwrap_or_default()
+        .split(ITEM_SEPARATOR)
+        .map(str::trim)
+        .filter(|i| !i.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The timezone in which the time windows of ACLs are evaluated: `UTC`, a fixed offset from UTC,
+/// e.g., `+02:00`, or a zone of the IANA time zone database, e.g., `Europe/Berlin`, whose offset
+/// follows daylight saving time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Timezone {
+    /// The local time of an instant in the timezone.
+    pub fn local(&self, time: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Self::Fixed(offset) => time.with_timezone(offset),
+            Self::Named(tz) => {
+                let local = time.with_timezone(tz);
+                local.with_timezone(&local.offset().fix())
+            }
+        }
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Fixed(FixedOffset::east(0))
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("UTC") || s == "Z" {
+            return Ok(Self::default());
+        }
+        let sign = match s.get(..1) {
+            Some("+") => 1,
+            Some("-") => -1,
+            _ => {
+                return Tz::from_str(s).map(Timezone::Named).map_err(|_| {
+                    format!(
+                        "{} is not UTC, an offset from UTC (+HH:MM) or an IANA time zone",
+                        s
+                    )
+                })
+            }
+        };
+        let invalid = || format!("{} is not a valid offset from UTC (+HH:MM)", s);
+        let minutes = parse_minutes(&s[1..]).map_err(|_| invalid())?;
+        FixedOffset::east_opt(sign * minutes as i32 * 60)
+            .map(Timezone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(offset) => write!(f, "{}", offset),
+            Self::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+impl Serialize for Timezone {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Timezone::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(time).unwrap()
+    }
+
+    #[test]
+    fn time_windows_are_parsed_and_matched() {
+        let window = TimeWindow::from_str("Mon-Fri 08:00-18:00").unwrap();
+        assert_eq!(window.to_string(), "Mon-Fri 08:00-18:00");
+        // 2022-03-21 is a Monday
+        assert!(window.contains(&at("2022-03-21T08:00:00Z")));
+        assert!(!window.contains(&at("2022-03-21T18:00:00Z")));
+        assert!(!window.contains(&at("2022-03-26T12:00:00Z")));
+        // the local time is used, not the time in UTC
+        assert!(!window.contains(&at("2022-03-21T07:30:00-02:00")));
+
+        // the window starting on Friday night ends on Saturday
+        let night = TimeWindow::from_str("fri 22:00-02:00").unwrap();
+        assert_eq!(night.to_string(), "Fri 22:00-02:00");
+        assert!(night.contains(&at("2022-03-25T23:00:00Z")));
+        assert!(night.contains(&at("2022-03-26T01:59:00Z")));
+        assert!(!night.contains(&at("2022-03-25T01:00:00Z")));
+
+        let weekend = TimeWindow::from_str("Sat-Sun 00:00-24:00").unwrap();
+        assert!(weekend.contains(&at("2022-03-27T23:59:00Z")));
+        assert!(!weekend.contains(&at("2022-03-28T00:00:00Z")));
+        assert!(TimeWindow::from_str("12:00-13:00")
+            .unwrap()
+            .contains(&at("2022-03-28T12:30:00Z")));
+
+        for invalid in [
+            "Mon",
+            "Mon 8:00-18:00",
+            "Someday 08:00-09:00",
+            "Mon 24:00-01:00",
+        ] {
+            assert!(TimeWindow::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn unknown_sources_fail_closed() {
+        let stored = join_items(
+            &[String::from("10.1.2.3/8"), String::from(" 2001:db8::1/32")],
+            parse_cidr,
+        );
+        assert_eq!(stored.as_deref(), Some("10.0.0.0/8,2001:db8::/32"));
+        let conditions = AclConditions::parse(stored.as_deref(), None).unwrap();
+        let context = |source: Option<&str>| {
+            ClaimContext::for_subject("jobs@admin")
+                .with_source(source.map(|s| s.parse().unwrap()))
+                .at(at("2022-03-21T08:00:00Z"))
+        };
+        for decision in [AclDecision::Allow, AclDecision::Deny] {
+            for (source, expected) in [
+                ("10.1.2.3", true),
+                ("::ffff:10.1.2.3", true),
+                ("2001:db8:1::1", true),
+                ("192.168.1.1", false),
+            ] {
+                let holds = conditions_hold(Some(&conditions), decision, &context(Some(source)));
+                assert_eq!(holds, expected, "{} {}", decision, source);
+            }
+        }
+        assert!(!conditions_hold(
+            Some(&conditions),
+            AclDecision::Allow,
+            &context(None)
+        ));
+        assert!(conditions_hold(
+            Some(&conditions),
+            AclDecision::Deny,
+            &context(None)
+        ));
+        // conditions that cannot be parsed fail closed as well
+        assert!(!conditions_hold(
+            None,
+            AclDecision::Allow,
+            &context(Some("10.1.2.3"))
+        ));
+        assert!(conditions_hold(
+            None,
+            AclDecision::Deny,
+            &context(Some("10.1.2.3"))
+        ));
+
+        assert_eq!(parse_cidr("10.1.2.3/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(parse_cidr("10.1.2.3").unwrap().to_string(), "10.1.2.3/32");
+        assert!(parse_cidr("campus").is_err());
+        assert_eq!(Timezone::from_str("-05:30").unwrap().to_string(), "-05:30");
+        assert!(Timezone::from_str("+25:00").is_err());
+        assert!(Timezone::from_str("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn named_timezones_follow_daylight_saving_time() {
+        let berlin = Timezone::from_str("Europe/Berlin").unwrap();
+        assert_eq!(berlin.to_string(), "Europe/Berlin");
+        let local = |utc: &str| berlin.local(&at(utc).with_timezone(&Utc));
+        // 09:00 local time is 08:00 UTC in winter, and 07:00 UTC in summer
+        let window = TimeWindow::from_str("Mon-Fri 09:00-17:00").unwrap();
+        assert_eq!(
+            local("2022-03-21T08:00:00Z").to_rfc3339(),
+            "2022-03-21T09:00:00+01:00"
+        );
+        assert!(window.contains(&local("2022-03-21T08:00:00Z")));
+        assert!(window.contains(&local("2022-06-20T07:00:00Z")));
+        assert!(!window.contains(&local("2022-06-20T15:30:00Z")));
+        // a fixed offset does not
+        let fixed = Timezone::from_str("+01:00").unwrap();
+        assert!(!window.contains(&fixed.local(&at("2022-06-20T07:00:00Z").with_timezone(&Utc))));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use config::Config;
@@ -6,7 +7,11 @@ use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 
+use crate::conditions::Timezone;
+use crate::engine::{EngineConfig, EnginePrecedence};
+use crate::models::{AclDecision, AuthzPolicy, PathDefaultDecision, PolicyMode};
 use crate::representations::TagentError;
+use crate::runas::RunAsSettings;
 
 // Tapis Tenants API response structs ---
 
@@ -72,7 +77,7 @@ fn insert_line_breaks_pub_key(pub_key: String) -> std::io::Result<String> {
             _ => {
                 let msg = "Unexpected error inserting newlines to public key";
                 error!("{}", msg);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, msg));
+                return Err(std::io::Error::other(msg));
             }
         };
         if t != "\n" {
@@ -126,6 +131,56 @@ pub struct TagentConfig {
     pub public_key: Option<String>,
     pub address: String,
     pub port: i16,
+    // Subjects that are always allowed to manage ACLs, regardless of the ACLs stored in
+    // the database. Additional administrators can be granted the `Admin` action through
+    // an ACL on the path `/`.
+    #[serde(default)]
+    pub admin_subjects: Vec<String>,
+    // The decision returned when no ACL matches a request; `default_decisions` overrides it
+    // for paths starting with a given prefix (the longest matching prefix wins).
+    pub default_decision: AclDecision,
+    #[serde(default)]
+    pub default_decisions: Vec<PathDefaultDecision>,
+    // How the decisions of multiple matching ACLs are combined.
+    pub policy_mode: PolicyMode,
+    // How often, in seconds, ACLs whose validity period has ended are deleted; 0 disables
+    // the sweeper. Expired ACLs are ignored when evaluating requests either way.
+    pub acl_sweep_interval: u64,
+    // A YAML or JSON file declaring ACLs. The ACLs managed by the file are reconciled with it at
+    // startup and whenever the server receives SIGHUP; ACLs created through the API are not affected.
+    pub policy_file: Option<PathBuf>,
+    // The template variables ACL paths and users can use, e.g., `${tenant}`, mapped to the JWT
+    // claims their values are taken from. `${sub}`, `${username}` and `${tenant}` are always
+    // available; `username` and `tenant` come from the subject unless mapped to a claim here.
+    #[serde(default)]
+    pub claim_variables: HashMap<String, String>,
+    // The timezone in which the time windows of ACLs are evaluated, as `UTC`, a fixed offset from
+    // UTC, e.g., `+02:00`, or an IANA time zone, e.g., `Europe/Berlin`, which follows daylight
+    // saving time.
+    #[serde(default)]
+    pub timezone: Timezone,
+    // How many of the most recent authorization checks are kept in memory, e.g., to simulate
+    // changes to ACLs against them; 0 disables the audit log.
+    pub audit_log_size: usize,
+    // How long, in seconds, the access granted by approving an access request lasts when neither
+    // the request nor its approval says when the access ends.
+    pub access_grant_duration: u64,
+    // The engines that decide authorization requests, in order, e.g., `[{type: acls}, {type:
+    // static, path: /etc/tagent/static.yaml}]`; by default only the ACLs in the database.
+    pub engines: Vec<EngineConfig>,
+    // How the decisions of several engines are combined.
+    #[serde(default)]
+    pub engine_precedence: EnginePrecedence,
+    // The JWT claim naming the user a token acts for, e.g., `tapis/username`; a service token
+    // whose claim names another user makes its own authorization checks as that user.
+    #[serde(default)]
+    pub delegation_claim: Option<String>,
+    // Run file operations as local users, e.g., `{users: {jdoe: {uid: 1001, gids: [1001]}},
+    // fallback_user: nobody}`: when the agent runs as root, uploads and the other file operations
+    // of a request are made as the local user it is made for, or as the fallback user. An agent
+    // running as root without this setting runs every file operation as `nobody`.
+    #[serde(default)]
+    pub run_as: Option<RunAsSettings>,
 }
 
 impl TagentConfig {
@@ -137,8 +192,35 @@ impl TagentConfig {
             public_key: None,
             address: String::from("127.0.0.1"),
             port: 8080,
+            admin_subjects: Vec::new(),
+            default_decision: AclDecision::Deny,
+            default_decisions: Vec::new(),
+            policy_mode: PolicyMode::DenyOverrides,
+            acl_sweep_interval: 300,
+            policy_file: None,
+            claim_variables: HashMap::from([
+                (String::from("tenant"), String::from("tapis/tenant_id")),
+                (String::from("username"), String::from("tapis/username")),
+            ]),
+            timezone: Timezone::default(),
+            audit_log_size: 1000,
+            access_grant_duration: 7 * 24 * 60 * 60,
+            engines: vec![EngineConfig::Acls],
+            engine_precedence: EnginePrecedence::default(),
+            delegation_claim: None,
+            run_as: None,
         })
     }
+
+    // Return the settings that determine how ACLs are evaluated.
+    pub fn authz_policy(&self) -> AuthzPolicy {
+        AuthzPolicy {
+            mode: self.policy_mode,
+            default_decision: self.default_decision,
+            path_default_decisions: self.default_decisions.clone(),
+            timezone: self.timezone,
+        }
+    }
 }
 
 impl From<config::ConfigError> for TagentError {
@@ -261,12 +343,52 @@ mod test {
         let temp = tempfile::TempDir::new()?;
         let filename = temp.path().join("foo.yaml");
         let mut file = std::fs::File::create(&filename)?;
-        let contents = "root_directory: foo\nport: 12";
+        let contents = "root_directory: foo\nport: 12\nadmin_subjects:\n  - tenants@admin";
         file.write_all(contents.as_bytes())?;
         let prefix = uuid::Uuid::new_v4().to_string();
         let config = TagentConfig::from_sources_with_names(filename.to_str().unwrap(), &prefix)?;
         assert_eq!(config.root_directory.to_str().unwrap(), "foo");
         assert_eq!(config.port, 12);
+        assert_eq!(config.admin_subjects, vec![String::from("tenants@admin")]);
+        Ok(())
+    }
+
+    #[test]
+    fn config_should_read_policy_settings() -> std::io::Result<()> {
+        let temp = tempfile::TempDir::new()?;
+        let filename = temp.path().join("foo.yaml");
+        let mut file = std::fs::File::create(&filename)?;
+        let contents = "policy_mode: FirstMatch\ndefault_decisions:\n  - prefix: /public\n    decision: Allow\nengines:\n  - type: acls\n  - type: static\n    path: /etc/tagent/static.yaml\nengine_precedence: FirstApplicable\ndelegation_claim: tapis/username";
+        file.write_all(contents.as_bytes())?;
+        let prefix = uuid::Uuid::new_v4().to_string();
+        std::env::set_var(format!("{}_DEFAULT_DECISION", &prefix), "Deny");
+        let config = TagentConfig::from_sources_with_names(filename.to_str().unwrap(), &prefix)?;
+        assert_eq!(
+            config.engines,
+            vec![
+                EngineConfig::Acls,
+                EngineConfig::Static {
+                    path: PathBuf::from("/etc/tagent/static.yaml")
+                }
+            ]
+        );
+        assert_eq!(config.engine_precedence, EnginePrecedence::FirstApplicable);
+        assert_eq!(config.delegation_claim.as_deref(), Some("tapis/username"));
+        let policy = config.authz_policy();
+        assert_eq!(policy.mode, PolicyMode::FirstMatch);
+        assert_eq!(
+            policy.default_decision_for_path("/public/data.txt"),
+            AclDecision::Allow
+        );
+        assert_eq!(
+            policy.default_decision_for_path("/public"),
+            AclDecision::Allow
+        );
+        assert_eq!(
+            policy.default_decision_for_path("/publicity"),
+            AclDecision::Deny
+        );
+        assert_eq!(policy.default_decision_for_path("/home"), AclDecision::Deny);
         Ok(())
     }
 
@@ -1,15 +1,27 @@
+use diesel::connection::TransactionManager;
 use diesel::prelude::*;
 // use diesel::{Connection};
-use crate::models::{AclAction, AclDecision, DbAcl};
+use crate::analysis::narrows;
+use crate::claims::{has_variables, ClaimContext};
+use crate::conditions::{conditions_hold, join_items, parse_cidr, TimeWindow};
+use crate::models::{
+    parse_timestamp, AccessRequestState, AclAction, AclDecision, AclFilter, AclOperation,
+    AclSortField, AclStatus, AuthzPolicy, DbAccessRequest, DbAcl, DbAclHistory, DbMembership,
+    DbPrincipal, NewAccessRequest, NewAccessRequestJson, NewAclHistory, NewMembership,
+    NewPrincipal, PatternType, PolicyMode, PrincipalKind, SortOrder,
+};
+use crate::patterns::Matcher;
+use crate::principals::Memberships;
 use chrono::prelude::{DateTime, Utc};
 use dotenv::dotenv;
 use log::{debug, error, info};
 use regex::Regex;
 use std::env;
+use std::str::FromStr;
 use std::time::SystemTime;
 
 use super::models::{NewAcl, NewAclJson};
-use super::schema::acls;
+use super::schema::{access_requests, acl_history, acls, memberships, principals};
 
 pub fn establish_connection() -> SqliteConnection {
     dotenv().ok();
@@ -28,44 +40,338 @@ fn iso8601(st: &SystemTime) -> String {
     // formats like "2001-07-08T00:34:60.026490+09:30"
 }
 
-pub fn save_acl(
-    conn: &mut SqliteConnection,
-    subject: &str,
-    action: &AclAction,
-    path: &str,
-    user: &str,
-    decision: &AclDecision,
-    create_by: &str,
-) -> Result<usize, diesel::result::Error> {
-    let now = SystemTime::now();
+// convert a user-supplied RFC 3339 timestamp to UTC, in the same format as `iso8601`; timestamps
+// that cannot be parsed are kept as they are
+fn normalize_timestamp(timestamp: &str) -> String {
+    match parse_timestamp(timestamp) {
+        Ok(t) => format!("{}", t.format("%+")),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+// like `Connection::transaction`, but the closure gets mutable access to the connection
+pub fn transaction_mut<T, F>(conn: &mut SqliteConnection, f: F) -> Result<T, diesel::result::Error>
+where
+    F: FnOnce(&mut SqliteConnection) -> Result<T, diesel::result::Error>,
+{
+    conn.transaction_manager().begin_transaction(conn)?;
+    match f(conn) {
+        Ok(value) => {
+            conn.transaction_manager().commit_transaction(conn)?;
+            Ok(value)
+        }
+        Err(e) => {
+            conn.transaction_manager().rollback_transaction(conn)?;
+            Err(e)
+        }
+    }
+}
 
-    let mut new_path = String::from("/");
+// runs `f` in a transaction that is always rolled back, e.g., to find out what changes would do
+// without making them
+pub fn rolled_back<T, F>(conn: &mut SqliteConnection, f: F) -> Result<T, diesel::result::Error>
+where
+    F: FnOnce(&mut SqliteConnection) -> Result<T, diesel::result::Error>,
+{
+    conn.transaction_manager().begin_transaction(conn)?;
+    let result = f(conn);
+    conn.transaction_manager().rollback_transaction(conn)?;
+    result
+}
+
+no_arg_sql_function!(
+    last_insert_rowid,
+    diesel::sql_types::Integer,
+    "the rowid of the last row inserted by the connection"
+);
 
-    // every path must start with a slash
-    if !(path.starts_with('/')) {
-        new_path.push_str(path);
+// the normal form of a request path: it starts with a slash, and repeated slashes, `.` segments
+// and a trailing slash are removed, so that `data/`, `//data` and `/./data` are all `/data`. Paths
+// with `..` segments are rejected rather than resolved
+pub fn normalize_path(path: &str) -> Result<String, String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return Err(String::from("must not contain `..` segments")),
+            s => segments.push(s),
+        }
+    }
+    Ok(format!("/{}", segments.join("/")))
+}
+
+// the path an ACL is stored with: literal paths and globs are normalized like request paths, while
+// a regex, whose dots and slashes are part of the pattern, only gets a leading slash. Literal paths
+// and globs with `..` segments, which `NewAclJson::validate` rejects, also only get a leading slash
+pub fn normalize_acl_path(path: &str, pattern_type: &PatternType) -> String {
+    match pattern_type {
+        PatternType::Literal | PatternType::Glob => {
+            if let Ok(normalized) = normalize_path(path) {
+                return normalized;
+            }
+        }
+        PatternType::Regex => (),
+    }
+    if path.starts_with('/') {
+        path.to_string()
     } else {
-        new_path = path.to_string();
+        format!("/{}", path)
     }
+}
+
+/// The actor recorded in the history of ACLs deleted by the sweeper once they expired.
+pub const ACL_SWEEPER: &str = "acl-sweeper";
+
+fn snapshot(acl: Option<&DbAcl>) -> Result<Option<String>, diesel::result::Error> {
+    acl.map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))
+}
+
+// records a change to an ACL in its history, as the ACL's next version
+fn record_acl_change(
+    conn: &mut SqliteConnection,
+    operation: AclOperation,
+    actor: &str,
+    before: Option<&DbAcl>,
+    after: Option<&DbAcl>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::acl_history::dsl;
+    let acl_id = match after.or(before) {
+        Some(acl) => acl.id,
+        None => return Ok(()),
+    };
+    let last_version: Option<i32> = dsl::acl_history
+        .filter(dsl::acl_id.eq(acl_id))
+        .select(diesel::dsl::max(dsl::version))
+        .first(conn)?;
+    let before_acl = snapshot(before)?;
+    let after_acl = snapshot(after)?;
+    let entry = NewAclHistory {
+        acl_id,
+        version: last_version.unwrap_or(0) + 1,
+        operation,
+        actor,
+        time: &iso8601(&SystemTime::now()),
+        before_acl: before_acl.as_deref(),
+        after_acl: after_acl.as_deref(),
+    };
+    diesel::insert_into(acl_history::table)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The outcome of changing or deleting an existing ACL.
+#[derive(Debug, PartialEq)]
+pub enum AclChange {
+    // the ACL after the change, or the ACL that was deleted
+    Done(Box<DbAcl>),
+    NotFound,
+    // the ACL was not at the expected revision, e.g., because it was changed concurrently; holds
+    // the current revision
+    Conflict(i32),
+}
+
+// retrieves an ACL that is about to be changed, checking it is at the expected revision, if any
+fn acl_to_change(
+    conn: &mut SqliteConnection,
+    acl_id: i32,
+    expected_revision: Option<i32>,
+) -> Result<Result<DbAcl, AclChange>, diesel::result::Error> {
+    Ok(match retrieve_acl_by_id(conn, acl_id).optional()? {
+        None => Err(AclChange::NotFound),
+        Some(acl) if expected_revision.is_some_and(|r| r != acl.revision) => {
+            Err(AclChange::Conflict(acl.revision))
+        }
+        Some(acl) => Ok(acl),
+    })
+}
+
+// saves a new ACL and returns its id
+pub fn save_acl(
+    conn: &mut SqliteConnection,
+    acl: &NewAclJson,
+    create_by: &str,
+) -> Result<i32, diesel::result::Error> {
+    save_acl_with_policy_key(conn, acl, create_by, None)
+}
 
+// saves a new ACL, managed by the policy file entry with key `policy_key` if any, and returns its id
+pub fn save_acl_with_policy_key(
+    conn: &mut SqliteConnection,
+    acl: &NewAclJson,
+    create_by: &str,
+    policy_key: Option<&str>,
+) -> Result<i32, diesel::result::Error> {
+    let now = SystemTime::now();
+    let new_path = normalize_acl_path(&acl.path, &acl.pattern_type);
+    let valid_from = acl.valid_from.as_deref().map(normalize_timestamp);
+    let valid_until = acl.valid_until.as_deref().map(normalize_timestamp);
+    let (source_cidrs, time_windows) = stored_conditions(acl);
     let new_acl = NewAcl {
-        subject,
-        action: &action.to_string(),
+        subject: &acl.subject,
+        action: acl.action,
         path: &new_path,
-        user,
-        decision: &decision.to_string(),
+        user: &acl.user,
+        decision: acl.decision,
         create_by,
         create_time: &iso8601(&now),
+        priority: acl.priority,
+        pattern_type: acl.pattern_type,
+        valid_from: valid_from.as_deref(),
+        valid_until: valid_until.as_deref(),
+        enabled: acl.enabled,
+        policy_key,
+        source_cidrs: source_cidrs.as_deref(),
+        time_windows: time_windows.as_deref(),
     };
-    diesel::insert_into(acls::table)
-        .values(&new_acl)
-        .execute(conn)
+    transaction_mut(conn, |conn| {
+        diesel::insert_into(acls::table)
+            .values(&new_acl)
+            .execute(conn)?;
+        let acl_id = diesel::select(last_insert_rowid).get_result::<i32>(conn)?;
+        let acl = retrieve_acl_by_id(conn, acl_id)?;
+        record_acl_change(conn, AclOperation::Create, create_by, None, Some(&acl))?;
+        Ok(acl_id)
+    })
+}
+
+// the conditions of a new ACL as they are stored in the db
+pub(crate) fn stored_conditions(acl: &NewAclJson) -> (Option<String>, Option<String>) {
+    (
+        join_items(&acl.source_cidrs, parse_cidr),
+        join_items(&acl.time_windows, TimeWindow::from_str),
+    )
+}
+
+// whether a DB ACL has the same fields as a new ACL, once the path, timestamps and conditions of the
+// new ACL are normalized as they would be when saved
+pub fn is_same_acl(db_acl: &DbAcl, acl: &NewAclJson) -> bool {
+    db_acl.subject == acl.subject
+        && db_acl.action == acl.action
+        && db_acl.path == normalize_acl_path(&acl.path, &acl.pattern_type)
+        && db_acl.pattern_type == acl.pattern_type
+        && db_acl.user == acl.user
+        && db_acl.decision == acl.decision
+        && db_acl.priority == acl.priority
+        && db_acl.valid_from == acl.valid_from.as_deref().map(normalize_timestamp)
+        && db_acl.valid_until == acl.valid_until.as_deref().map(normalize_timestamp)
+        && db_acl.enabled == acl.enabled
+        && (db_acl.source_cidrs.clone(), db_acl.time_windows.clone()) == stored_conditions(acl)
 }
 
 pub fn retrieve_all_acls(conn: &mut SqliteConnection) -> Result<Vec<DbAcl>, diesel::result::Error> {
     acls::dsl::acls.load::<DbAcl>(conn)
 }
 
+// escapes the wildcards of a LIKE pattern, using `\` as the escape character
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// the query for the ACLs matching a filter, unsorted; the status of ACLs is taken at `now`, which
+// is formatted like the stored validity bounds so that they compare as strings
+fn filtered_acls<'a>(
+    filter: &'a AclFilter,
+    now: &'a str,
+) -> acls::BoxedQuery<'a, diesel::sqlite::Sqlite> {
+    use crate::schema::acls::dsl;
+
+    let mut query = dsl::acls.into_boxed();
+    if let Some(s) = &filter.subject {
+        query = query.filter(dsl::subject.eq(s));
+    }
+    if let Some(u) = &filter.user {
+        query = query.filter(dsl::user.eq(u));
+    }
+    if let Some(a) = filter.action {
+        query = query.filter(dsl::action.eq(a));
+    }
+    if let Some(d) = filter.decision {
+        query = query.filter(dsl::decision.eq(d));
+    }
+    if let Some(p) = &filter.path {
+        let pattern = format!("%{}%", escape_like(p));
+        query = query.filter(dsl::path.like(pattern).escape('\\'));
+    }
+    if let Some(c) = &filter.create_by {
+        query = query.filter(dsl::create_by.eq(c));
+    }
+    if let Some(subs) = &filter.visible_to {
+        let creator = subs.first().cloned().unwrap_or_default();
+        query = query.filter(dsl::create_by.eq(creator).or(dsl::subject.eq_any(subs)));
+    }
+    // mirrors `AclValidity::status_at`
+    let unexpired = dsl::valid_until.is_null().or(dsl::valid_until.gt(now));
+    query = match filter.status {
+        None => query,
+        Some(AclStatus::Expired) => query.filter(dsl::valid_until.le(now)),
+        Some(AclStatus::Disabled) => query.filter(unexpired).filter(dsl::enabled.eq(false)),
+        Some(AclStatus::Pending) => query
+            .filter(unexpired)
+            .filter(dsl::enabled.eq(true))
+            .filter(dsl::valid_from.gt(now)),
+        Some(AclStatus::Active) => query
+            .filter(unexpired)
+            .filter(dsl::enabled.eq(true))
+            .filter(dsl::valid_from.is_null().or(dsl::valid_from.le(now))),
+    };
+    query
+}
+
+// returns the ACLs matching a filter, sorted as requested; ties are sorted by id
+pub fn retrieve_acls_filtered(
+    conn: &mut SqliteConnection,
+    filter: &AclFilter,
+) -> Result<Vec<DbAcl>, diesel::result::Error> {
+    retrieve_acls_page(conn, filter, 0, None).map(|(acls, _)| acls)
+}
+
+// returns a page of the ACLs matching a filter, sorted as requested, i.e., at most `limit` ACLs
+// after skipping `offset` of them, along with the total number of matching ACLs
+pub fn retrieve_acls_page(
+    conn: &mut SqliteConnection,
+    filter: &AclFilter,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<(Vec<DbAcl>, i64), diesel::result::Error> {
+    use crate::schema::acls::dsl;
+
+    let now = iso8601(&SystemTime::now());
+    let total = filtered_acls(filter, &now)
+        .count()
+        .get_result::<i64>(conn)?;
+    let mut query = filtered_acls(filter, &now);
+    query = match (filter.sort, filter.order) {
+        (AclSortField::Id, SortOrder::Asc) => query.order(dsl::id.asc()),
+        (AclSortField::Id, SortOrder::Desc) => query.order(dsl::id.desc()),
+        (AclSortField::Subject, SortOrder::Asc) => query.order(dsl::subject.asc()),
+        (AclSortField::Subject, SortOrder::Desc) => query.order(dsl::subject.desc()),
+        (AclSortField::User, SortOrder::Asc) => query.order(dsl::user.asc()),
+        (AclSortField::User, SortOrder::Desc) => query.order(dsl::user.desc()),
+        (AclSortField::Action, SortOrder::Asc) => query.order(dsl::action.asc()),
+        (AclSortField::Action, SortOrder::Desc) => query.order(dsl::action.desc()),
+        (AclSortField::Path, SortOrder::Asc) => query.order(dsl::path.asc()),
+        (AclSortField::Path, SortOrder::Desc) => query.order(dsl::path.desc()),
+        (AclSortField::Priority, SortOrder::Asc) => query.order(dsl::priority.asc()),
+        (AclSortField::Priority, SortOrder::Desc) => query.order(dsl::priority.desc()),
+        (AclSortField::CreateTime, SortOrder::Asc) => query.order(dsl::create_time.asc()),
+        (AclSortField::CreateTime, SortOrder::Desc) => query.order(dsl::create_time.desc()),
+    };
+    // SQLite needs a limit for an offset
+    let limit = limit.map_or(i64::MAX, |l| l.min(i64::MAX as usize) as i64);
+    let offset = offset.min(i64::MAX as usize) as i64;
+    let acls = query
+        .then_order_by(dsl::id.asc())
+        .limit(limit)
+        .offset(offset)
+        .load::<DbAcl>(conn)?;
+    Ok((acls, total))
+}
+
 pub fn retrieve_acls_for_subject(
     conn: &mut SqliteConnection,
     sub: &str,
@@ -74,6 +380,17 @@ pub fn retrieve_acls_for_subject(
     acls::dsl::acls.filter(subject.eq(sub)).load::<DbAcl>(conn)
 }
 
+// returns the ACLs of any of a set of subjects, e.g., a subject and its groups and roles
+pub fn retrieve_acls_for_subjects(
+    conn: &mut SqliteConnection,
+    subs: &[String],
+) -> Result<Vec<DbAcl>, diesel::result::Error> {
+    use crate::schema::acls::subject;
+    acls::dsl::acls
+        .filter(subject.eq_any(subs))
+        .load::<DbAcl>(conn)
+}
+
 pub fn retrieve_acls_for_subject_user(
     conn: &mut SqliteConnection,
     sub: &str,
@@ -87,6 +404,45 @@ pub fn retrieve_acls_for_subject_user(
         .load::<DbAcl>(conn)
 }
 
+// returns the ACLs managed by the policy file
+pub fn retrieve_policy_acls(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<DbAcl>, diesel::result::Error> {
+    use crate::schema::acls::policy_key;
+    acls::dsl::acls
+        .filter(policy_key.is_not_null())
+        .load::<DbAcl>(conn)
+}
+
+// deletes ACLs, recording each deletion in the history of the ACL; returns the number of ACLs deleted
+fn delete_acls(
+    conn: &mut SqliteConnection,
+    acls: &[DbAcl],
+    actor: &str,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::acls::id;
+    transaction_mut(conn, |conn| {
+        let mut deleted = 0;
+        for acl in acls {
+            deleted += diesel::delete(acls::table.filter(id.eq(acl.id))).execute(conn)?;
+            record_acl_change(conn, AclOperation::Delete, actor, Some(acl), None)?;
+        }
+        Ok(deleted)
+    })
+}
+
+// deletes every ACL that is not managed by the policy file; returns the number of ACLs deleted
+pub fn delete_api_acls(
+    conn: &mut SqliteConnection,
+    actor: &str,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::acls::policy_key;
+    let api_acls = acls::dsl::acls
+        .filter(policy_key.is_null())
+        .load::<DbAcl>(conn)?;
+    delete_acls(conn, &api_acls, actor)
+}
+
 pub fn retrieve_acl_by_id(
     conn: &mut SqliteConnection,
     id: i32,
@@ -94,40 +450,472 @@ pub fn retrieve_acl_by_id(
     acls::dsl::acls.find(id).first(conn)
 }
 
+// deletes an ACL, provided it is at the expected revision if one is given
 pub fn delete_acl_from_db_by_id(
     conn: &mut SqliteConnection,
     acl_id: i32,
-) -> Result<usize, diesel::result::Error> {
-    use crate::schema::acls::id;
-    diesel::delete(acls::table.filter(id.eq(&acl_id))).execute(conn)
+    actor: &str,
+    expected_revision: Option<i32>,
+) -> Result<AclChange, diesel::result::Error> {
+    transaction_mut(conn, |conn| {
+        let acl = match acl_to_change(conn, acl_id, expected_revision)? {
+            Ok(acl) => acl,
+            Err(change) => return Ok(change),
+        };
+        delete_acls(conn, std::slice::from_ref(&acl), actor)?;
+        Ok(AclChange::Done(Box::new(acl)))
+    })
 }
 
+// updates the fields of an ACL that can be changed, provided it is at the expected revision if one
+// is given; the creator and creation time are kept
 pub fn update_acl_in_db_by_id(
     conn: &mut SqliteConnection,
     acl_id: i32,
     new_acl: &NewAclJson,
-    new_subject: &str,
+    actor: &str,
+    expected_revision: Option<i32>,
+) -> Result<AclChange, diesel::result::Error> {
+    transaction_mut(conn, |conn| {
+        let before = match acl_to_change(conn, acl_id, expected_revision)? {
+            Ok(acl) => acl,
+            Err(change) => return Ok(change),
+        };
+        set_acl_fields(conn, acl_id, new_acl)?;
+        let after = retrieve_acl_by_id(conn, acl_id)?;
+        record_acl_change(
+            conn,
+            AclOperation::Update,
+            actor,
+            Some(&before),
+            Some(&after),
+        )?;
+        Ok(AclChange::Done(Box::new(after)))
+    })
+}
+
+fn set_acl_fields(
+    conn: &mut SqliteConnection,
+    acl_id: i32,
+    new_acl: &NewAclJson,
 ) -> Result<usize, diesel::result::Error> {
     use crate::schema::acls::action;
-    use crate::schema::acls::create_by;
     use crate::schema::acls::decision;
+    use crate::schema::acls::enabled;
     use crate::schema::acls::id;
     use crate::schema::acls::path;
+    use crate::schema::acls::pattern_type;
+    use crate::schema::acls::priority;
+    use crate::schema::acls::revision;
+    use crate::schema::acls::source_cidrs;
     use crate::schema::acls::subject;
+    use crate::schema::acls::time_windows;
     use crate::schema::acls::user;
+    use crate::schema::acls::valid_from;
+    use crate::schema::acls::valid_until;
 
+    let (new_source_cidrs, new_time_windows) = stored_conditions(new_acl);
     diesel::update(acls::table.filter(id.eq(&acl_id)))
         .set((
-            action.eq(new_acl.action.to_string()),
+            action.eq(new_acl.action),
             subject.eq(new_acl.subject.clone()),
-            path.eq(new_acl.path.clone()),
+            path.eq(normalize_acl_path(&new_acl.path, &new_acl.pattern_type)),
             user.eq(new_acl.user.clone()),
-            decision.eq(new_acl.decision.to_string()),
-            create_by.eq(new_subject),
+            decision.eq(new_acl.decision),
+            priority.eq(new_acl.priority),
+            pattern_type.eq(new_acl.pattern_type),
+            valid_from.eq(new_acl.valid_from.as_deref().map(normalize_timestamp)),
+            valid_until.eq(new_acl.valid_until.as_deref().map(normalize_timestamp)),
+            enabled.eq(new_acl.enabled),
+            source_cidrs.eq(new_source_cidrs),
+            time_windows.eq(new_time_windows),
+            revision.eq(revision + 1),
         ))
         .execute(conn)
 }
 
+// returns the history of an ACL, oldest version first
+pub fn retrieve_acl_history(
+    conn: &mut SqliteConnection,
+    acl_id: i32,
+) -> Result<Vec<DbAclHistory>, diesel::result::Error> {
+    use crate::schema::acl_history::dsl;
+    dsl::acl_history
+        .filter(dsl::acl_id.eq(acl_id))
+        .order(dsl::version.asc())
+        .load::<DbAclHistory>(conn)
+}
+
+// returns the changes to all ACLs, most recent first, optionally only those made by an actor
+pub fn retrieve_all_acl_history(
+    conn: &mut SqliteConnection,
+    actor: Option<&str>,
+) -> Result<Vec<DbAclHistory>, diesel::result::Error> {
+    use crate::schema::acl_history::dsl;
+    let mut query = dsl::acl_history.into_boxed();
+    if let Some(a) = actor {
+        query = query.filter(dsl::actor.eq(a));
+    }
+    query.order(dsl::id.desc()).load::<DbAclHistory>(conn)
+}
+
+pub fn retrieve_acl_version(
+    conn: &mut SqliteConnection,
+    acl_id: i32,
+    version: i32,
+) -> Result<DbAclHistory, diesel::result::Error> {
+    use crate::schema::acl_history::dsl;
+    dsl::acl_history
+        .filter(dsl::acl_id.eq(acl_id))
+        .filter(dsl::version.eq(version))
+        .first(conn)
+}
+
+/// Revert an ACL to the state recorded by one of its versions, recreating the ACL with the same id
+/// if it was deleted.
+///
+/// Returns the restored ACL, or None if the version deleted the ACL so that there is no state to
+/// restore. A version that does not exist is a `NotFound` error.
+///
+pub fn restore_acl_version(
+    conn: &mut SqliteConnection,
+    acl_id: i32,
+    version: i32,
+    actor: &str,
+) -> Result<Option<DbAcl>, diesel::result::Error> {
+    use crate::schema::acls::dsl;
+    transaction_mut(conn, |conn| {
+        let target = match retrieve_acl_version(conn, acl_id, version)?.after() {
+            Some(acl) => acl,
+            None => return Ok(None),
+        };
+        let before = retrieve_acl_by_id(conn, acl_id).optional()?;
+        // every stored field of the version is restored, whether or not the ACL still exists
+        if before.is_some() {
+            diesel::update(acls::table.filter(dsl::id.eq(acl_id)))
+                .set((
+                    dsl::subject.eq(&target.subject),
+                    dsl::action.eq(target.action),
+                    dsl::path.eq(&target.path),
+                    dsl::user.eq(&target.user),
+                    dsl::create_by.eq(&target.create_by),
+                    dsl::create_time.eq(&target.create_time),
+                    dsl::decision.eq(target.decision),
+                    dsl::priority.eq(target.priority),
+                    dsl::pattern_type.eq(&target.pattern_type),
+                    dsl::valid_from.eq(&target.valid_from),
+                    dsl::valid_until.eq(&target.valid_until),
+                    dsl::enabled.eq(target.enabled),
+                    dsl::policy_key.eq(&target.policy_key),
+                    dsl::revision.eq(dsl::revision + 1),
+                    dsl::source_cidrs.eq(&target.source_cidrs),
+                    dsl::time_windows.eq(&target.time_windows),
+                ))
+                .execute(conn)?;
+        } else {
+            diesel::insert_into(acls::table)
+                .values((
+                    dsl::id.eq(acl_id),
+                    dsl::subject.eq(&target.subject),
+                    dsl::action.eq(target.action),
+                    dsl::path.eq(&target.path),
+                    dsl::user.eq(&target.user),
+                    dsl::create_by.eq(&target.create_by),
+                    dsl::create_time.eq(&target.create_time),
+                    dsl::decision.eq(target.decision),
+                    dsl::priority.eq(target.priority),
+                    dsl::pattern_type.eq(&target.pattern_type),
+                    dsl::valid_from.eq(&target.valid_from),
+                    dsl::valid_until.eq(&target.valid_until),
+                    dsl::enabled.eq(target.enabled),
+                    dsl::policy_key.eq(&target.policy_key),
+                    dsl::revision.eq(target.revision + 1),
+                    dsl::source_cidrs.eq(&target.source_cidrs),
+                    dsl::time_windows.eq(&target.time_windows),
+                ))
+                .execute(conn)?;
+        }
+        let after = retrieve_acl_by_id(conn, acl_id)?;
+        record_acl_change(
+            conn,
+            AclOperation::Restore,
+            actor,
+            before.as_ref(),
+            Some(&after),
+        )?;
+        Ok(Some(after))
+    })
+}
+
+// deletes the ACLs whose validity period ended at or before `now`; returns the number of ACLs deleted
+pub fn delete_expired_acls(
+    conn: &mut SqliteConnection,
+    now: &DateTime<Utc>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::acls::valid_until;
+    let expired: Vec<DbAcl> = acls::dsl::acls
+        .filter(valid_until.is_not_null())
+        .load::<DbAcl>(conn)?
+        .into_iter()
+        .filter(|acl| acl.validity().is_expired_at(now))
+        .collect();
+    delete_acls(conn, &expired, ACL_SWEEPER)
+}
+
+// groups and roles ---
+
+// saves a new group or role and returns its id
+pub fn save_principal(
+    conn: &mut SqliteConnection,
+    kind: PrincipalKind,
+    name: &str,
+    create_by: &str,
+) -> Result<i32, diesel::result::Error> {
+    let now = SystemTime::now();
+    let new_principal = NewPrincipal {
+        kind: &kind.to_string(),
+        name,
+        create_by,
+        create_time: &iso8601(&now),
+    };
+    conn.transaction(|| {
+        diesel::insert_into(principals::table)
+            .values(&new_principal)
+            .execute(conn)?;
+        diesel::select(last_insert_rowid).get_result::<i32>(conn)
+    })
+}
+
+pub fn retrieve_principals(
+    conn: &mut SqliteConnection,
+    kind: PrincipalKind,
+) -> Result<Vec<DbPrincipal>, diesel::result::Error> {
+    use crate::schema::principals::kind as kind_col;
+    principals::dsl::principals
+        .filter(kind_col.eq(kind.to_string()))
+        .load::<DbPrincipal>(conn)
+}
+
+pub fn retrieve_principal(
+    conn: &mut SqliteConnection,
+    kind: PrincipalKind,
+    name: &str,
+) -> Result<DbPrincipal, diesel::result::Error> {
+    use crate::schema::principals::kind as kind_col;
+    use crate::schema::principals::name as name_col;
+    principals::dsl::principals
+        .filter(kind_col.eq(kind.to_string()))
+        .filter(name_col.eq(name))
+        .first(conn)
+}
+
+// deletes a group or role together with its memberships, both its members and the groups and
+// roles it is a member of, and the ACLs for it, so that a group or role created later with the
+// same name does not inherit them. Returns the number of ACLs deleted, or None if the principal
+// does not exist.
+pub fn delete_principal(
+    conn: &mut SqliteConnection,
+    kind: PrincipalKind,
+    name: &str,
+    actor: &str,
+) -> Result<Option<usize>, diesel::result::Error> {
+    use crate::schema::acls::subject;
+    use crate::schema::memberships::member;
+    use crate::schema::memberships::principal;
+    use crate::schema::principals::kind as kind_col;
+    use crate::schema::principals::name as name_col;
+    let p = kind.principal(name);
+    transaction_mut(conn, |conn| {
+        let deleted = diesel::delete(
+            principals::table
+                .filter(kind_col.eq(kind.to_string()))
+                .filter(name_col.eq(name)),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Ok(None);
+        }
+        diesel::delete(memberships::table.filter(principal.eq(&p).or(member.eq(&p))))
+            .execute(conn)?;
+        let principal_acls = acls::dsl::acls.filter(subject.eq(&p)).load::<DbAcl>(conn)?;
+        delete_acls(conn, &principal_acls, actor).map(Some)
+    })
+}
+
+pub fn retrieve_all_memberships(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<DbMembership>, diesel::result::Error> {
+    memberships::dsl::memberships.load::<DbMembership>(conn)
+}
+
+// returns the direct members of a group or role, e.g., `group:lab`
+pub fn retrieve_members(
+    conn: &mut SqliteConnection,
+    principal: &str,
+) -> Result<Vec<DbMembership>, diesel::result::Error> {
+    use crate::schema::memberships::principal as principal_col;
+    memberships::dsl::memberships
+        .filter(principal_col.eq(principal))
+        .load::<DbMembership>(conn)
+}
+
+// adds a member to a group or role and returns the id of the membership, or None if the membership
+// would make the group or role a member of itself
+pub fn save_membership(
+    conn: &mut SqliteConnection,
+    principal: &str,
+    member: &str,
+    create_by: &str,
+) -> Result<Option<i32>, diesel::result::Error> {
+    let now = SystemTime::now();
+    let new_membership = NewMembership {
+        principal,
+        member,
+        create_by,
+        create_time: &iso8601(&now),
+    };
+    transaction_mut(conn, |conn| {
+        let memberships = Memberships::new(retrieve_all_memberships(conn)?);
+        if memberships.would_create_cycle(principal, member) {
+            return Ok(None);
+        }
+        diesel::insert_into(memberships::table)
+            .values(&new_membership)
+            .execute(conn)?;
+        diesel::select(last_insert_rowid)
+            .get_result::<i32>(conn)
+            .map(Some)
+    })
+}
+
+pub fn delete_membership(
+    conn: &mut SqliteConnection,
+    principal: &str,
+    member: &str,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::memberships::member as member_col;
+    use crate::schema::memberships::principal as principal_col;
+    diesel::delete(
+        memberships::table
+            .filter(principal_col.eq(principal))
+            .filter(member_col.eq(member)),
+    )
+    .execute(conn)
+}
+
+// returns the subject followed by every group and role it is a member of, including nested memberships
+pub fn retrieve_principals_for_subject(
+    conn: &mut SqliteConnection,
+    sub: &str,
+) -> Result<Vec<String>, diesel::result::Error> {
+    Ok(Memberships::new(retrieve_all_memberships(conn)?).principals_for(sub))
+}
+
+// access requests ---
+
+// saves a request of `subject` for access, pending review, and returns its id
+pub fn save_access_request(
+    conn: &mut SqliteConnection,
+    request: &NewAccessRequestJson,
+    subject: &str,
+) -> Result<i32, diesel::result::Error> {
+    let now = SystemTime::now();
+    let path = normalize_acl_path(&request.path, &request.pattern_type);
+    let valid_until = request.valid_until.as_deref().map(normalize_timestamp);
+    let new_request = NewAccessRequest {
+        subject,
+        action: request.action,
+        path: &path,
+        pattern_type: request.pattern_type,
+        user: &request.user,
+        justification: request.justification.trim(),
+        valid_until: valid_until.as_deref(),
+        state: AccessRequestState::Pending,
+        create_time: &iso8601(&now),
+    };
+    transaction_mut(conn, |conn| {
+        diesel::insert_into(access_requests::table)
+            .values(&new_request)
+            .execute(conn)?;
+        diesel::select(last_insert_rowid).get_result::<i32>(conn)
+    })
+}
+
+pub fn retrieve_access_request(
+    conn: &mut SqliteConnection,
+    id: i32,
+) -> Result<DbAccessRequest, diesel::result::Error> {
+    access_requests::dsl::access_requests.find(id).first(conn)
+}
+
+// returns the access requests in `state` and made by `subject`, if given, oldest first
+pub fn retrieve_access_requests(
+    conn: &mut SqliteConnection,
+    state: Option<AccessRequestState>,
+    subject: Option<&str>,
+) -> Result<Vec<DbAccessRequest>, diesel::result::Error> {
+    use crate::schema::access_requests::dsl;
+    let mut query = dsl::access_requests.into_boxed();
+    if let Some(s) = state {
+        query = query.filter(dsl::state.eq(s));
+    }
+    if let Some(s) = subject {
+        query = query.filter(dsl::subject.eq(s));
+    }
+    query.order(dsl::id.asc()).load::<DbAccessRequest>(conn)
+}
+
+/// The outcome of deciding an access request.
+#[derive(Debug, PartialEq)]
+pub enum AccessReview {
+    // the request after the decision
+    Done(Box<DbAccessRequest>),
+    NotFound,
+    // the request was already decided; holds its state
+    AlreadyDecided(AccessRequestState),
+}
+
+// decides a pending access request: the request is approved by saving `grant` as a new ACL created
+// by the reviewer, or rejected if there is no grant. The ACL and the decision are saved together.
+pub fn review_access_request(
+    conn: &mut SqliteConnection,
+    id: i32,
+    reviewer: &str,
+    comment: Option<&str>,
+    grant: Option<&NewAclJson>,
+) -> Result<AccessReview, diesel::result::Error> {
+    use crate::schema::access_requests::dsl;
+    let now = iso8601(&SystemTime::now());
+    transaction_mut(conn, |conn| {
+        let request = match retrieve_access_request(conn, id).optional()? {
+            None => return Ok(AccessReview::NotFound),
+            Some(r) => r,
+        };
+        if request.state != AccessRequestState::Pending {
+            return Ok(AccessReview::AlreadyDecided(request.state));
+        }
+        let (state, acl_id) = match grant {
+            Some(acl) => (
+                AccessRequestState::Approved,
+                Some(save_acl(conn, acl, reviewer)?),
+            ),
+            None => (AccessRequestState::Rejected, None),
+        };
+        diesel::update(dsl::access_requests.find(id))
+            .set((
+                dsl::state.eq(state),
+                dsl::reviewed_by.eq(reviewer),
+                dsl::review_time.eq(&now),
+                dsl::review_comment.eq(comment),
+                dsl::acl_id.eq(acl_id),
+            ))
+            .execute(conn)?;
+        Ok(AccessReview::Done(Box::new(retrieve_access_request(
+            conn, id,
+        )?)))
+    })
+}
+
 // checks whether a field with a wildcard character matches another field value
 pub fn check_acl_field_with_wildcard_for_match(acl_field: &str, field: &str) -> bool {
     let re = Regex::new(acl_field);
@@ -142,16 +930,29 @@ pub fn check_acl_field_with_wildcard_for_match(acl_field: &str, field: &str) ->
     re.is_match(field)
 }
 
-// checks if a DB ACL matches a set of criteria
-pub fn check_acl_for_match(sub: &str, usr: &str, pth: &str, act: &str, acl: &DbAcl) -> bool {
+// checks if a DB ACL matches a set of criteria; template variables in the ACL are substituted from
+// the context of the request
+pub fn check_acl_for_match(
+    sub: &str,
+    context: &ClaimContext,
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+    acl: &DbAcl,
+) -> bool {
     debug!("top of check_acl_for_match for acl: {}", acl.id);
     // subject must be an exact match
     if sub != acl.subject {
         debug!("subject didn't match; returning false");
         return false;
     };
-    // user field allowed to have wild cards
-    if usr != acl.user {
+    // user field allowed to have wild cards and template variables
+    if has_variables(&acl.user) {
+        if !Matcher::for_user_in(&acl.user, context).is_match(usr) {
+            debug!("acl user template didn't match; returning false");
+            return false;
+        }
+    } else if usr != acl.user {
         debug!("user isn't exact match");
         // if it wasn't an exact match and the acl doesn't contain wildcards, it's not a match
         if !acl.user.contains('*') {
@@ -165,104 +966,1051 @@ pub fn check_acl_for_match(sub: &str, usr: &str, pth: &str, act: &str, acl: &DbA
             return false;
         };
     };
-    // path field allowed to have wild cards
-    if pth != acl.path {
-        debug!("patch isn't exact match");
-        // if it wasn't an exact match and the acl doesn't contain wildcards, it's not a match
-        if !acl.path.contains('*') {
-            debug!("acl path was not a regex; returning false");
-            return false;
-        };
-        debug!("acl path WAS a regex; checking for wildcard match");
-        // special check for acl with wildcard
-        if !(check_acl_field_with_wildcard_for_match(&acl.path, pth)) {
-            debug!("acl path regex didn't match; returning false");
-            return false;
-        };
+    // path field is matched according to the pattern type of the acl
+    if !Matcher::for_db_path_in(&acl.pattern_type, &acl.path, context).is_match(pth) {
+        debug!(
+            "acl path ({} {}) didn't match; returning false",
+            acl.pattern_type, acl.path
+        );
+        return false;
     };
-
-    if acl.action != act {
-        // actions have a hierarchy, with "higher" values implying lower values
-        // Read < Execute < Write
-        // whether the ACL matches depends on the decision associated with the ACL.
-        // in case Deny, lower ACL values match because a Deny of a lower action implies deny for higher actions.
-        // in case Allow, higher ACL values match because an Allow of a higher action implies allow for lower actions
-        if acl.decision == "Allow" {
-            if acl.is_leq_action(act) {
-                return false;
-            }
-        }
-        // acl decision is "Deny" so it is only a match if the acl action is greater than
-        else {
-            debug!(
-                "checking Deny ACL action ({}) against action ({})",
-                acl.action, act
-            );
-            if !(acl.is_leq_action(act)) {
-                debug!("Deny ACL action was not less than action.. returning false");
-                return false;
-            }
-        }
+    if !acl.matches_action(act) {
+        debug!("acl action didn't match; returning false");
+        return false;
+    };
+    if !conditions_hold(acl.conditions().as_ref(), acl.decision, context) {
+        debug!("acl conditions didn't hold; returning false");
+        return false;
     };
     debug!("db_acl with id {} matched request", acl.id);
     true
 }
 
+// returns the ACL whose decision determines the outcome of a request according to the policy mode,
+// among the ACLs that matched the request; ties are broken by priority, then by id. Returns None if
+// no ACL matched the request
+pub fn deciding_acl<'a, I>(mode: &PolicyMode, matched: I) -> Option<&'a DbAcl>
+where
+    I: IntoIterator<Item = &'a DbAcl>,
+{
+    let mut first: Option<&DbAcl> = None;
+    let mut first_allow: Option<&DbAcl> = None;
+    let mut first_deny: Option<&DbAcl> = None;
+    for acl in matched {
+        let first_with_decision = match acl.decision {
+            AclDecision::Allow => &mut first_allow,
+            AclDecision::Deny => &mut first_deny,
+        };
+        for f in [&mut first, first_with_decision] {
+            if f.is_none_or(|f| (acl.priority, acl.id) < (f.priority, f.id)) {
+                *f = Some(acl);
+            }
+        }
+    }
+    match mode {
+        // a matching ACL with a Deny decision always wins
+        PolicyMode::DenyOverrides => first_deny.or(first_allow),
+        PolicyMode::AllowOverrides => first_allow.or(first_deny),
+        PolicyMode::FirstMatch => first,
+    }
+}
+
+// combines the decisions of the ACLs that matched a request according to the policy mode; returns
+// the decision that determined the outcome, or None if no ACL matched the request
+pub fn combine_decisions<'a, I>(mode: &PolicyMode, matched: I) -> Option<AclDecision>
+where
+    I: IntoIterator<Item = &'a DbAcl>,
+{
+    deciding_acl(mode, matched).map(|acl| {
+        debug!("deciding ACL was {}", acl.id);
+        acl.decision
+    })
+}
+
+// evaluates a set of ACLs against a request according to the policy mode; `subs` are the subject of
+// the request and the groups and roles it belongs to. Returns the decision that determined the
+// outcome, or None if no ACL matched the request
+pub fn evaluate_acls(
+    mode: &PolicyMode,
+    acls: &[DbAcl],
+    subs: &[String],
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+) -> Option<AclDecision> {
+    let now = Utc::now();
+    let context = ClaimContext::for_subject(subs.first().map_or("", |s| s.as_str()));
+    combine_decisions(
+        mode,
+        acls.iter().filter(|acl| {
+            // ACLs that are disabled or outside their validity period are ignored
+            acl.status_at(&now) == AclStatus::Active
+                && subs
+                    .iter()
+                    .any(|sub| check_acl_for_match(sub, &context, usr, pth, act, acl))
+        }),
+    )
+}
+
+// evaluates the ACLs of a subject, and of the groups and roles it belongs to, against a request;
+// returns None if no ACL matched
+fn evaluate_acls_db(
+    conn: &mut SqliteConnection,
+    mode: &PolicyMode,
+    sub: &str,
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+) -> Result<Option<AclDecision>, diesel::result::Error> {
+    let subs = retrieve_principals_for_subject(conn, sub)?;
+    let acls = retrieve_acls_for_subjects(conn, &subs)?;
+    Ok(evaluate_acls(mode, &acls, &subs, usr, pth, act))
+}
+
 pub fn is_authz_db(
     conn: &mut SqliteConnection,
+    policy: &AuthzPolicy,
     sub: &str,
     usr: &str,
     pth: &str,
     act: &AclAction,
 ) -> bool {
-    use crate::schema::acls::decision;
-    use crate::schema::acls::subject;
-
-    // first check for a matching ACL with a Deny decision
-    let deny_str = AclDecision::Deny.to_string();
-    let deny_acls = acls::dsl::acls
-        .filter(subject.eq(&sub))
-        .filter(decision.eq(&deny_str))
-        .load::<DbAcl>(conn);
-    let deny_acls = match deny_acls {
+    let decision = match evaluate_acls_db(conn, &policy.mode, sub, usr, pth, act) {
         Ok(d) => d,
         Err(e) => {
             let msg = format!(
-                "got error retrieving Deny ACLS from db; Returning false! details: {}",
+                "got error retrieving ACLS from db; Returning false! details: {}",
                 e
             );
             error!("{}", msg);
             return false;
         }
     };
-    for acl in deny_acls {
-        if check_acl_for_match(sub, usr, pth, &act.to_string(), &acl) {
-            return false;
+    let decision = match decision {
+        Some(d) => d,
+        None => {
+            // if no ACL matched then the default decision for the path applies
+            let d = policy.default_decision_for_path(pth);
+            debug!("no ACL matched; returning default decision ({})", d);
+            d
         }
+    };
+    decision == AclDecision::Allow
+}
+
+// checks whether an ACL is visible to a (non-admin) subject; i.e., the subject created the ACL or the
+// ACL applies to the subject. `subs` are the subject and the groups and roles it belongs to.
+pub fn is_acl_visible_to_subject(acl: &DbAcl, subs: &[String]) -> bool {
+    subs.first().is_some_and(|sub| &acl.create_by == sub) || subs.contains(&acl.subject)
+}
+
+// an ACL as it would be stored, with normalized fields, before it is saved
+pub fn unsaved_acl(acl: &NewAclJson, create_by: &str) -> DbAcl {
+    let (source_cidrs, time_windows) = stored_conditions(acl);
+    DbAcl {
+        id: 0,
+        subject: acl.subject.clone(),
+        action: acl.action,
+        path: normalize_acl_path(&acl.path, &acl.pattern_type),
+        user: acl.user.clone(),
+        create_by: create_by.to_string(),
+        create_time: iso8601(&SystemTime::now()),
+        decision: acl.decision,
+        priority: acl.priority,
+        pattern_type: acl.pattern_type,
+        valid_from: acl.valid_from.as_deref().map(normalize_timestamp),
+        valid_until: acl.valid_until.as_deref().map(normalize_timestamp),
+        enabled: acl.enabled,
+        policy_key: None,
+        revision: 1,
+        source_cidrs,
+        time_windows,
     }
-    // check for any matching ACL with an Allow decision
-    let allow_str = AclDecision::Allow.to_string();
-    let allow_acls = acls::dsl::acls
-        .filter(subject.eq(&sub))
-        .filter(decision.eq(&allow_str))
-        .load::<DbAcl>(conn);
-    let allow_acls = match allow_acls {
-        Ok(d) => d,
-        Err(e) => {
-            let msg = format!(
-                "got error retrieving Allow ACLS from db; Returning false! details: {}",
-                e
+}
+
+// checks whether a (non-admin) subject may replace the ACL `current` with `new`; a missing `current`
+// is a new ACL, and a missing `new` a deletion. Subjects manage the ACLs they created or that apply
+// to them, and can only narrow what they grant: they may revoke and narrow Allow ACLs, and create,
+// change and delete Deny ACLs for themselves.
+pub fn may_change_acl(
+    sub: &str,
+    current: Option<&DbAcl>,
+    new: Option<&DbAcl>,
+) -> Result<(), String> {
+    if let Some(current) = current {
+        if current.create_by != sub && current.subject != sub {
+            return Err(format!(
+                "ACL {} was not created by subject {} and does not apply to it",
+                current.id, sub
+            ));
+        }
+        let own_deny = current.decision == AclDecision::Deny
+            && current.create_by == sub
+            && current.subject == sub;
+        if current.decision == AclDecision::Deny && !own_deny {
+            return Err(format!(
+                "removing Deny ACL {} would widen what subject {} is granted",
+                current.id, current.subject
+            ));
+        }
+    }
+    match new {
+        None => Ok(()),
+        Some(new) if new.decision == AclDecision::Deny => {
+            if new.subject == sub {
+                Ok(())
+            } else {
+                Err(format!(
+                    "subject {} can only create Deny ACLs for itself, not for subject {}",
+                    sub, new.subject
+                ))
+            }
+        }
+        Some(new) => match current {
+            Some(current) if narrows(current, new) => Ok(()),
+            Some(current) => Err(format!(
+                "the changes to Allow ACL {} would widen what subject {} is granted",
+                current.id, current.subject
+            )),
+            None => Err(format!(
+                "subject {} cannot grant access with Allow ACLs",
+                sub
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::conditions::Timezone;
+    use crate::models::{PathDefaultDecision, PatternType};
+    use diesel::connection::SimpleConnection;
+
+    embed_migrations!();
+
+    // an in-memory database with all migrations applied
+    pub(crate) fn test_connection() -> SqliteConnection {
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        embedded_migrations::run(&conn).unwrap();
+        conn
+    }
+
+    pub(crate) fn new_acl(
+        subject: &str,
+        action: AclAction,
+        path: &str,
+        decision: AclDecision,
+        priority: i32,
+    ) -> NewAclJson {
+        NewAclJson {
+            subject: subject.to_string(),
+            action,
+            decision,
+            path: path.to_string(),
+            user: String::from("self"),
+            priority,
+            pattern_type: PatternType::Regex,
+            valid_from: None,
+            valid_until: None,
+            enabled: true,
+            source_cidrs: Vec::new(),
+            time_windows: Vec::new(),
+        }
+    }
+
+    // a subject without group or role memberships
+    fn subs(sub: &str) -> Vec<String> {
+        vec![sub.to_string()]
+    }
+
+    pub(crate) fn test_policy(mode: PolicyMode) -> AuthzPolicy {
+        AuthzPolicy {
+            mode,
+            default_decision: AclDecision::Deny,
+            path_default_decisions: Vec::new(),
+            timezone: Timezone::default(),
+        }
+    }
+
+    #[test]
+    fn acls_are_visible_to_their_subject_and_creator() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        save_acl(
+            &mut conn,
+            &new_acl("jobs@admin", AclAction::Read, "/tmp", AclDecision::Allow, 0),
+            "tenants@admin",
+        )?;
+        let acl = &retrieve_all_acls(&mut conn)?[0];
+        assert!(is_acl_visible_to_subject(acl, &subs("jobs@admin")));
+        assert!(is_acl_visible_to_subject(acl, &subs("tenants@admin")));
+        assert!(!is_acl_visible_to_subject(acl, &subs("files@admin")));
+        Ok(())
+    }
+
+    #[test]
+    fn subjects_can_only_narrow_their_acls() {
+        let stored = |acl: NewAclJson, create_by: &str| DbAcl {
+            id: 7,
+            ..unsaved_acl(&acl, create_by)
+        };
+        let allow = new_acl(
+            "jobs@admin",
+            AclAction::Write,
+            "/data/.*",
+            AclDecision::Allow,
+            0,
+        );
+        let granted = stored(allow.clone(), "tenants@admin");
+        let narrower = stored(
+            NewAclJson {
+                action: AclAction::Read,
+                path: String::from("/data/a"),
+                valid_until: Some(String::from("2999-01-01T00:00:00+00:00")),
+                ..allow.clone()
+            },
+            "tenants@admin",
+        );
+        // Allow ACLs can be revoked and narrowed by their subject and creator only
+        assert_eq!(may_change_acl("jobs@admin", Some(&granted), None), Ok(()));
+        assert_eq!(
+            may_change_acl("tenants@admin", Some(&granted), Some(&narrower)),
+            Ok(())
+        );
+        assert!(may_change_acl("files@admin", Some(&granted), None).is_err());
+        // but not widened, given precedence, moved to another subject, or created
+        for wider in [
+            NewAclJson {
+                action: AclAction::Delete,
+                ..allow.clone()
+            },
+            NewAclJson {
+                path: String::from("/.*"),
+                ..allow.clone()
+            },
+            NewAclJson {
+                priority: -1,
+                ..allow.clone()
+            },
+            NewAclJson {
+                subject: String::from("files@admin"),
+                ..allow.clone()
+            },
+        ] {
+            let wider = stored(wider, "tenants@admin");
+            assert!(may_change_acl("jobs@admin", Some(&granted), Some(&wider)).is_err());
+        }
+        assert!(may_change_acl("jobs@admin", Some(&narrower), Some(&granted)).is_err());
+        assert!(may_change_acl("jobs@admin", None, Some(&granted)).is_err());
+
+        // Deny ACLs can be created, changed and deleted by subjects for themselves only
+        let deny = new_acl(
+            "jobs@admin",
+            AclAction::Read,
+            "/data/.*",
+            AclDecision::Deny,
+            0,
+        );
+        let own = stored(deny.clone(), "jobs@admin");
+        assert_eq!(may_change_acl("jobs@admin", None, Some(&own)), Ok(()));
+        assert_eq!(may_change_acl("jobs@admin", Some(&own), None), Ok(()));
+        assert_eq!(
+            may_change_acl("jobs@admin", Some(&granted), Some(&own)),
+            Ok(())
+        );
+        assert!(may_change_acl("files@admin", None, Some(&own)).is_err());
+        assert!(may_change_acl("jobs@admin", Some(&own), Some(&granted)).is_err());
+        let imposed = stored(deny, "tenants@admin");
+        assert!(may_change_acl("jobs@admin", Some(&imposed), None).is_err());
+        assert!(may_change_acl("tenants@admin", Some(&imposed), None).is_err());
+    }
+
+    #[test]
+    fn acls_are_filtered_and_sorted() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let read = save_acl(
+            &mut conn,
+            &new_acl(
+                "jobs@admin",
+                AclAction::Read,
+                "/data/a_b",
+                AclDecision::Allow,
+                5,
+            ),
+            "tenants@admin",
+        )?;
+        let write = save_acl(
+            &mut conn,
+            &new_acl(
+                "jobs@admin",
+                AclAction::Write,
+                "/data/axb",
+                AclDecision::Deny,
+                1,
+            ),
+            "jobs@admin",
+        )?;
+        let other = save_acl(
+            &mut conn,
+            &new_acl(
+                "files@admin",
+                AclAction::Read,
+                "/tmp",
+                AclDecision::Allow,
+                3,
+            ),
+            "tenants@admin",
+        )?;
+        let ids = |acls: Vec<DbAcl>| acls.iter().map(|a| a.id).collect::<Vec<i32>>();
+
+        let all = retrieve_acls_filtered(&mut conn, &AclFilter::default())?;
+        assert_eq!(ids(all), vec![read, write, other]);
+        let filter = AclFilter {
+            subject: Some(String::from("jobs@admin")),
+            ..AclFilter::default()
+        };
+        assert_eq!(
+            ids(retrieve_acls_filtered(&mut conn, &filter)?),
+            vec![read, write]
+        );
+        let filter = AclFilter {
+            action: Some(AclAction::Read),
+            decision: Some(AclDecision::Allow),
+            create_by: Some(String::from("tenants@admin")),
+            ..AclFilter::default()
+        };
+        assert_eq!(
+            ids(retrieve_acls_filtered(&mut conn, &filter)?),
+            vec![read, other]
+        );
+        // LIKE wildcards in the path substring match themselves
+        let filter = AclFilter {
+            path: Some(String::from("a_b")),
+            ..AclFilter::default()
+        };
+        assert_eq!(ids(retrieve_acls_filtered(&mut conn, &filter)?), vec![read]);
+        let filter = AclFilter {
+            sort: AclSortField::Priority,
+            order: SortOrder::Desc,
+            ..AclFilter::default()
+        };
+        assert_eq!(
+            ids(retrieve_acls_filtered(&mut conn, &filter)?),
+            vec![read, other, write]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn acls_are_paged_in_the_database() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let acl = |sub: &str| new_acl(sub, AclAction::Read, "/data", AclDecision::Allow, 0);
+        let active = save_acl(&mut conn, &acl("jobs@admin"), "tenants@admin")?;
+        let expired = NewAclJson {
+            valid_until: Some(String::from("2000-01-01T02:00:00+02:00")),
+            ..acl("jobs@admin")
+        };
+        let expired = save_acl(&mut conn, &expired, "tenants@admin")?;
+        let pending = NewAclJson {
+            valid_from: Some(String::from("2999-01-01T00:00:00-05:00")),
+            ..acl("role:reader")
+        };
+        let pending = save_acl(&mut conn, &pending, "tenants@admin")?;
+        let disabled = NewAclJson {
+            enabled: false,
+            ..acl("files@admin")
+        };
+        let disabled = save_acl(&mut conn, &disabled, "jobs@admin")?;
+        let other = save_acl(&mut conn, &acl("files@admin"), "tenants@admin")?;
+        let page = |conn: &mut SqliteConnection, filter: &AclFilter, offset, limit| {
+            retrieve_acls_page(conn, filter, offset, limit)
+                .map(|(acls, total)| (acls.iter().map(|a| a.id).collect::<Vec<i32>>(), total))
+        };
+
+        let all = AclFilter::default();
+        assert_eq!(
+            page(&mut conn, &all, 1, Some(2))?,
+            (vec![expired, pending], 5)
+        );
+        assert_eq!(page(&mut conn, &all, 4, None)?, (vec![other], 5));
+        assert_eq!(page(&mut conn, &all, 9, Some(2))?, (vec![], 5));
+        for (status, id) in [
+            (AclStatus::Active, vec![active, other]),
+            (AclStatus::Expired, vec![expired]),
+            (AclStatus::Pending, vec![pending]),
+            (AclStatus::Disabled, vec![disabled]),
+        ] {
+            let filter = AclFilter {
+                status: Some(status),
+                ..AclFilter::default()
+            };
+            let total = id.len() as i64;
+            assert_eq!(page(&mut conn, &filter, 0, None)?, (id, total));
+        }
+        // a subject sees the ACLs it created or that apply to it or its roles
+        let visible = AclFilter {
+            visible_to: Some(vec![
+                String::from("jobs@admin"),
+                String::from("role:reader"),
+            ]),
+            ..AclFilter::default()
+        };
+        assert_eq!(
+            page(&mut conn, &visible, 1, Some(10))?,
+            (vec![expired, pending, disabled], 4)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn acl_changes_are_recorded_and_can_be_restored() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let acl = new_acl("jobs@admin", AclAction::Read, "/tmp", AclDecision::Allow, 0);
+        let id = save_acl(&mut conn, &acl, "tenants@admin")?;
+        let updated = NewAclJson {
+            action: AclAction::Write,
+            ..acl
+        };
+        update_acl_in_db_by_id(&mut conn, id, &updated, "files@admin", None)?;
+        // the creator is kept when an ACL is updated
+        assert_eq!(
+            retrieve_acl_by_id(&mut conn, id)?.create_by,
+            "tenants@admin"
+        );
+        delete_acl_from_db_by_id(&mut conn, id, "files@admin", None)?;
+
+        let history = retrieve_acl_history(&mut conn, id)?;
+        let ops: Vec<(i32, AclOperation, &str)> = history
+            .iter()
+            .map(|h| (h.version, h.operation, h.actor.as_str()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (1, AclOperation::Create, "tenants@admin"),
+                (2, AclOperation::Update, "files@admin"),
+                (3, AclOperation::Delete, "files@admin"),
+            ]
+        );
+        assert_eq!(history[1].before().unwrap().action, AclAction::Read);
+        assert_eq!(history[1].after().unwrap().action, AclAction::Write);
+        assert!(history[2].after().is_none());
+        assert_eq!(
+            retrieve_all_acl_history(&mut conn, Some("files@admin"))?
+                .iter()
+                .map(|h| h.version)
+                .collect::<Vec<i32>>(),
+            vec![3, 2]
+        );
+
+        // restoring a deleted ACL recreates it with the same id
+        let restored = restore_acl_version(&mut conn, id, 2, "tenants@admin")?.unwrap();
+        // the restore is a change, so the revision is incremented
+        let version_2 = history[1].after().unwrap();
+        assert_eq!(
+            restored,
+            DbAcl {
+                revision: version_2.revision + 1,
+                ..version_2
+            }
+        );
+        restore_acl_version(&mut conn, id, 1, "tenants@admin")?;
+        assert_eq!(retrieve_acl_by_id(&mut conn, id)?.action, AclAction::Read);
+        let history = retrieve_acl_history(&mut conn, id)?;
+        assert_eq!(history.len(), 5);
+        assert_eq!(history[4].operation, AclOperation::Restore);
+        // a deletion has no state to restore, and unknown versions are not found
+        assert_eq!(
+            restore_acl_version(&mut conn, id, 3, "tenants@admin")?,
+            None
+        );
+        assert_eq!(
+            restore_acl_version(&mut conn, id, 9, "tenants@admin"),
+            Err(diesel::result::Error::NotFound)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn restoring_an_existing_acl_restores_every_field() -> Result<(), diesel::result::Error> {
+        use crate::schema::acls::dsl;
+        let mut conn = test_connection();
+        let acl = new_acl("jobs@admin", AclAction::Read, "/tmp", AclDecision::Allow, 0);
+        let id = save_acl_with_policy_key(&mut conn, &acl, "tenants@admin", Some("lab"))?;
+        let updated = NewAclJson {
+            action: AclAction::Write,
+            ..acl
+        };
+        update_acl_in_db_by_id(&mut conn, id, &updated, "files@admin", None)?;
+        // the ACL is no longer managed by the policy file, e.g., after an upgrade
+        diesel::update(acls::table.filter(dsl::id.eq(id)))
+            .set((
+                dsl::policy_key.eq(None::<String>),
+                dsl::create_by.eq("files@admin"),
+            ))
+            .execute(&conn)?;
+
+        let restored = restore_acl_version(&mut conn, id, 1, "tenants@admin")?.unwrap();
+        let version_1 = retrieve_acl_history(&mut conn, id)?[0].after().unwrap();
+        assert_eq!(restored.policy_key, Some(String::from("lab")));
+        assert_eq!(
+            restored,
+            DbAcl {
+                revision: 3,
+                ..version_1
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn changes_require_the_expected_revision() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let acl = new_acl("jobs@admin", AclAction::Read, "/tmp", AclDecision::Allow, 0);
+        let id = save_acl(&mut conn, &acl, "tenants@admin")?;
+        let current = retrieve_acl_by_id(&mut conn, id)?;
+        assert_eq!(current.revision, 1);
+
+        let patch = serde_json::json!({"action": "Write", "valid_until": null, "priority": 3});
+        let patched = NewAclJson::from_db_acl(&current)
+            .merge_patch(&patch)
+            .unwrap();
+        assert_eq!(
+            patched,
+            NewAclJson {
+                action: AclAction::Write,
+                priority: 3,
+                ..acl.clone()
+            }
+        );
+        let updated =
+            match update_acl_in_db_by_id(&mut conn, id, &patched, "tenants@admin", Some(1))? {
+                AclChange::Done(updated) => *updated,
+                other => panic!("unexpected outcome {:?}", other),
+            };
+        assert_eq!(updated.revision, 2);
+        assert_eq!(updated.action, AclAction::Write);
+
+        // a change based on a stale revision is rejected and leaves the ACL unchanged
+        assert_eq!(
+            update_acl_in_db_by_id(&mut conn, id, &acl, "files@admin", Some(1))?,
+            AclChange::Conflict(2)
+        );
+        assert_eq!(
+            delete_acl_from_db_by_id(&mut conn, id, "files@admin", Some(1))?,
+            AclChange::Conflict(2)
+        );
+        assert_eq!(retrieve_acl_by_id(&mut conn, id)?, updated);
+        assert_eq!(
+            update_acl_in_db_by_id(&mut conn, id + 1, &acl, "files@admin", None)?,
+            AclChange::NotFound
+        );
+        assert_eq!(
+            delete_acl_from_db_by_id(&mut conn, id, "files@admin", Some(2))?,
+            AclChange::Done(Box::new(updated))
+        );
+        assert_eq!(
+            delete_acl_from_db_by_id(&mut conn, id, "files@admin", None)?,
+            AclChange::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_acls_are_rejected_by_field_and_by_the_db() {
+        let acl = NewAclJson {
+            subject: String::from(" "),
+            valid_from: Some(String::from("2022-04-01T00:00:00Z")),
+            valid_until: Some(String::from("2022-03-01T00:00:00Z")),
+            source_cidrs: vec![String::from("10.0.0.0/8"), String::from("campus")],
+            time_windows: vec![String::from("Mon-Fri 8:00-18:00")],
+            ..new_acl("s", AclAction::Read, "data/*(", AclDecision::Allow, 0)
+        };
+        let fields: Vec<String> = acl.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "subject",
+                "path",
+                "source_cidrs",
+                "time_windows",
+                "valid_until"
+            ]
+        );
+        assert!(
+            new_acl("s", AclAction::Read, "data/.*", AclDecision::Allow, 0)
+                .validate()
+                .is_empty()
+        );
+
+        // values that bypass validation are rejected by the CHECK constraints
+        let conn = test_connection();
+        for (column, value) in [
+            ("action", "Fly"),
+            ("decision", "allow"),
+            ("pattern_type", "fuzzy"),
+            ("path", "data"),
+            ("subject", ""),
+        ] {
+            let sql = format!(
+                "INSERT INTO acls (subject, action, path, user, create_by, create_time) \
+                 VALUES ('s', 'Read', '/p', 'self', 'c', 't'); \
+                 UPDATE acls SET {} = '{}'",
+                column, value
             );
-            error!("{}", msg);
-            return false;
+            assert!(conn.batch_execute(&sql).is_err(), "{}", column);
         }
-    };
-    for acl in allow_acls {
-        if check_acl_for_match(sub, usr, pth, &act.to_string(), &acl) {
-            return true;
+    }
+
+    #[test]
+    fn paths_are_normalized() -> Result<(), diesel::result::Error> {
+        for (path, normal) in [
+            ("data", "/data"),
+            ("/data/", "/data"),
+            ("//data//a.txt", "/data/a.txt"),
+            ("/./data/./a.txt", "/data/a.txt"),
+            ("/", "/"),
+            ("", "/"),
+        ] {
+            assert_eq!(normalize_path(path).as_deref(), Ok(normal), "{}", path);
         }
+        assert!(normalize_path("/data/../etc/passwd").is_err());
+
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let sub = "jobs@admin";
+        let acl = |path: &str, pattern_type: PatternType| NewAclJson {
+            pattern_type,
+            ..new_acl(sub, AclAction::Read, path, AclDecision::Allow, 0)
+        };
+        for (path, pattern_type) in [
+            ("/data/", PatternType::Literal),
+            ("shared//*.txt", PatternType::Glob),
+            ("/a/./b", PatternType::Regex),
+        ] {
+            save_acl(&mut conn, &acl(path, pattern_type), "tenants@admin")?;
+        }
+        let stored: Vec<String> = retrieve_all_acls(&mut conn)?
+            .into_iter()
+            .map(|a| a.path)
+            .collect();
+        assert_eq!(stored, vec!["/data", "/shared/*.txt", "/a/./b"]);
+        assert!(is_authz_db(
+            &mut conn,
+            &policy,
+            sub,
+            "self",
+            "/data",
+            &AclAction::Read
+        ));
+        // in a regex, a dot is any character
+        assert!(is_authz_db(
+            &mut conn,
+            &policy,
+            sub,
+            "self",
+            "/a/x/b",
+            &AclAction::Read
+        ));
+        // `..` is rejected in literal paths and globs, not in regexes
+        let fields =
+            |a: NewAclJson| -> Vec<String> { a.validate().into_iter().map(|e| e.field).collect() };
+        assert_eq!(
+            fields(acl("/data/../etc", PatternType::Literal)),
+            vec!["path"]
+        );
+        assert_eq!(fields(acl("/data/../**", PatternType::Glob)), vec!["path"]);
+        assert!(fields(acl("/data/..", PatternType::Regex)).is_empty());
+
+        // ACLs saved before paths were normalized still match in the index
+        conn.batch_execute(
+            "INSERT INTO acls (subject, action, path, user, create_by, create_time, pattern_type) \
+             VALUES ('jobs@admin', 'Read', '/legacy/', 'self', 'c', 't', 'literal')",
+        )
+        .unwrap();
+        let index = crate::index::AclIndex::new(
+            retrieve_all_acls(&mut conn)?,
+            retrieve_all_memberships(&mut conn)?,
+        );
+        assert!(index.is_authz(&policy, sub, "self", "/legacy", &AclAction::Read));
+        assert!(is_authz_db(
+            &mut conn,
+            &policy,
+            sub,
+            "self",
+            "/legacy",
+            &AclAction::Read
+        ));
+
+        // pattern types the ACLs cannot have are rejected when loading, not matched as literals
+        conn.batch_execute(
+            "PRAGMA ignore_check_constraints = ON; \
+             UPDATE acls SET pattern_type = 'fuzzy' WHERE path = '/legacy/'; \
+             PRAGMA ignore_check_constraints = OFF",
+        )
+        .unwrap();
+        assert!(matches!(
+            retrieve_all_acls(&mut conn),
+            Err(diesel::result::Error::DeserializationError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn higher_actions_imply_lower_actions() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let sub = "jobs@admin";
+        let acls = [
+            (AclAction::Write, "/upload", AclDecision::Allow),
+            (AclAction::Delete, "/scratch", AclDecision::Allow),
+            (AclAction::Read, "/scratch/keep", AclDecision::Deny),
+            (AclAction::List, "/listing", AclDecision::Allow),
+        ];
+        for (act, pth, dec) in acls {
+            save_acl(&mut conn, &new_acl(sub, act, pth, dec, 0), "tenants@admin")?;
+        }
+        // actions are read back from their names
+        let stored: Vec<AclAction> = retrieve_all_acls(&mut conn)?
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        assert_eq!(stored, acls.map(|(act, _, _)| act));
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let allowed = |conn: &mut SqliteConnection, path: &str| -> Vec<AclAction> {
+            AclAction::ALL
+                .into_iter()
+                .filter(|act| is_authz_db(conn, &policy, sub, "self", path, act))
+                .collect()
+        };
+        use AclAction::*;
+        // may upload but not delete
+        assert_eq!(
+            allowed(&mut conn, "/upload"),
+            vec![List, Read, Execute, Write]
+        );
+        assert_eq!(
+            allowed(&mut conn, "/scratch"),
+            vec![List, Read, Execute, Write, Delete]
+        );
+        assert_eq!(allowed(&mut conn, "/scratch/keep"), Vec::<AclAction>::new());
+        // may list but not download
+        assert_eq!(allowed(&mut conn, "/listing"), vec![List]);
+        Ok(())
+    }
+
+    #[test]
+    fn policy_mode_determines_conflicting_decisions() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let sub = "jobs@admin";
+        // the Allow ACL has the lower priority value, so it is checked first in FirstMatch mode
+        save_acl(
+            &mut conn,
+            &new_acl(sub, AclAction::Read, "/data/.*", AclDecision::Allow, 1),
+            "tenants@admin",
+        )?;
+        save_acl(
+            &mut conn,
+            &new_acl(sub, AclAction::Read, "/data/secret.*", AclDecision::Deny, 2),
+            "tenants@admin",
+        )?;
+        let check = |conn: &mut SqliteConnection, mode: PolicyMode, path: &str| {
+            is_authz_db(
+                conn,
+                &test_policy(mode),
+                sub,
+                "self",
+                path,
+                &AclAction::Read,
+            )
+        };
+        assert!(!check(
+            &mut conn,
+            PolicyMode::DenyOverrides,
+            "/data/secret.txt"
+        ));
+        assert!(check(&mut conn, PolicyMode::DenyOverrides, "/data/a.txt"));
+        assert!(check(
+            &mut conn,
+            PolicyMode::AllowOverrides,
+            "/data/secret.txt"
+        ));
+        assert!(check(&mut conn, PolicyMode::FirstMatch, "/data/secret.txt"));
+        assert!(!check(&mut conn, PolicyMode::FirstMatch, "/other.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn group_and_role_acls_apply_to_members() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let admin = "tenants@admin";
+        save_principal(&mut conn, PrincipalKind::Group, "lab", admin)?;
+        save_principal(&mut conn, PrincipalKind::Role, "reader", admin)?;
+        assert!(save_membership(&mut conn, "group:lab", "jobs@admin", admin)?.is_some());
+        assert!(save_membership(&mut conn, "role:reader", "group:lab", admin)?.is_some());
+        // the role cannot become a member of a group it contains
+        assert!(save_membership(&mut conn, "group:lab", "role:reader", admin)?.is_none());
+        save_acl(
+            &mut conn,
+            &new_acl(
+                "role:reader",
+                AclAction::Read,
+                "/data/.*",
+                AclDecision::Allow,
+                0,
+            ),
+            admin,
+        )?;
+        save_acl(
+            &mut conn,
+            &new_acl(
+                "group:lab",
+                AclAction::Read,
+                "/data/secret.*",
+                AclDecision::Deny,
+                0,
+            ),
+            admin,
+        )?;
+        let check = |conn: &mut SqliteConnection, sub: &str, pth: &str| {
+            is_authz_db(conn, &policy, sub, "self", pth, &AclAction::Read)
+        };
+        assert!(check(&mut conn, "jobs@admin", "/data/a.txt"));
+        assert!(!check(&mut conn, "jobs@admin", "/data/secret.txt"));
+        assert!(!check(&mut conn, "files@admin", "/data/a.txt"));
+        let principals = retrieve_principals_for_subject(&mut conn, "jobs@admin")?;
+        assert!(is_acl_visible_to_subject(
+            &retrieve_all_acls(&mut conn)?[0],
+            &principals
+        ));
+        // deleting the group removes its memberships, so its members lose the role, and its ACLs
+        let lab_acl = retrieve_all_acls(&mut conn)?
+            .into_iter()
+            .find(|a| a.subject == "group:lab")
+            .unwrap();
+        assert_eq!(
+            delete_principal(&mut conn, PrincipalKind::Group, "lab", admin)?,
+            Some(1)
+        );
+        assert!(!check(&mut conn, "jobs@admin", "/data/a.txt"));
+        assert!(retrieve_all_memberships(&mut conn)?.is_empty());
+        assert!(retrieve_all_acls(&mut conn)?
+            .iter()
+            .all(|a| a.subject != "group:lab"));
+        let history = retrieve_acl_history(&mut conn, lab_acl.id)?;
+        assert_eq!(
+            history.last().map(|h| h.operation),
+            Some(AclOperation::Delete)
+        );
+        assert_eq!(
+            delete_principal(&mut conn, PrincipalKind::Group, "lab", admin)?,
+            None
+        );
+        // a group created later with the same name starts without the old grants
+        save_principal(&mut conn, PrincipalKind::Group, "lab", admin)?;
+        save_membership(&mut conn, "group:lab", "jobs@admin", admin)?;
+        save_membership(&mut conn, "role:reader", "group:lab", admin)?;
+        assert!(check(&mut conn, "jobs@admin", "/data/secret.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn default_decision_applies_when_no_acl_matches() {
+        let mut conn = test_connection();
+        let mut policy = test_policy(PolicyMode::DenyOverrides);
+        policy.path_default_decisions.push(PathDefaultDecision {
+            prefix: String::from("/public/"),
+            decision: AclDecision::Allow,
+        });
+        let sub = "jobs@admin";
+        assert!(is_authz_db(
+            &mut conn,
+            &policy,
+            sub,
+            "self",
+            "/public/a.txt",
+            &AclAction::Read
+        ));
+        assert!(!is_authz_db(
+            &mut conn,
+            &policy,
+            sub,
+            "self",
+            "/private/a.txt",
+            &AclAction::Read
+        ));
+    }
+
+    #[test]
+    fn access_requests_are_decided_once() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let now = Utc::now();
+        let request = NewAccessRequestJson {
+            action: AclAction::Read,
+            path: String::from("data/report.csv"),
+            pattern_type: PatternType::Literal,
+            user: String::from("self"),
+            justification: String::from("quarterly report"),
+            valid_until: None,
+        };
+        let approved = save_access_request(&mut conn, &request, "jobs@admin")?;
+        let rejected = save_access_request(&mut conn, &request, "apps@admin")?;
+        let pending = retrieve_access_requests(&mut conn, Some(AccessRequestState::Pending), None)?;
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].path, "/data/report.csv");
+
+        let until = (now + chrono::Duration::hours(1)).to_rfc3339();
+        let grant = retrieve_access_request(&mut conn, approved)?
+            .to_request_json()
+            .acl_for("jobs@admin", &now, Some(until));
+        let review = review_access_request(
+            &mut conn,
+            approved,
+            "tenants@admin",
+            Some("ok"),
+            Some(&grant),
+        )?;
+        let decided = match review {
+            AccessReview::Done(r) => r,
+            other => panic!("request was not approved: {:?}", other),
+        };
+        assert_eq!(decided.state, AccessRequestState::Approved);
+        assert_eq!(decided.reviewed_by.as_deref(), Some("tenants@admin"));
+        let acl = retrieve_acl_by_id(&mut conn, decided.acl_id.unwrap())?;
+        assert_eq!(
+            (acl.subject.as_str(), acl.decision, acl.create_by.as_str()),
+            ("jobs@admin", AclDecision::Allow, "tenants@admin")
+        );
+        assert!(acl.valid_until.is_some());
+        assert!(is_authz_db(
+            &mut conn,
+            &test_policy(PolicyMode::DenyOverrides),
+            "jobs@admin",
+            "self",
+            "/data/report.csv",
+            &AclAction::Read
+        ));
+
+        // a decided request cannot be decided again
+        assert_eq!(
+            review_access_request(&mut conn, approved, "tenants@admin", None, None)?,
+            AccessReview::AlreadyDecided(AccessRequestState::Approved)
+        );
+        review_access_request(&mut conn, rejected, "tenants@admin", None, None)?;
+        let request = retrieve_access_request(&mut conn, rejected)?;
+        assert_eq!(
+            (request.state, request.acl_id),
+            (AccessRequestState::Rejected, None)
+        );
+        assert_eq!(
+            review_access_request(&mut conn, 99, "tenants@admin", None, None)?,
+            AccessReview::NotFound
+        );
+        assert_eq!(retrieve_all_acls(&mut conn)?.len(), 1);
+        assert_eq!(
+            retrieve_access_requests(&mut conn, None, Some("apps@admin"))?.len(),
+            1
+        );
+        Ok(())
     }
-    debug!("no ACL matched; returning default decision (false)");
-    // if no ACL matched then the action is not authorized by default
-    false
 }
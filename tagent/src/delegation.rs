@@ -0,0 +1,217 @@
+use serde_json::Value;
+
+use crate::auth::{RequestClaims, ON_BEHALF_OF_HEADER};
+use crate::claims::ClaimContext;
+use crate::engine::PolicyEngine;
+use crate::models::{AclAction, AclDecision};
+
+// Acting on behalf of users
+// =========================
+
+// the user named by the delegation claim of a token, without its tenant; None if the claim is
+// missing or names the subject of the token itself
+fn delegated_user(
+    claims: &RequestClaims,
+    context: &ClaimContext,
+    delegation_claim: &str,
+) -> Option<String> {
+    let user = match claims.custom.get(delegation_claim) {
+        Some(Value::String(s)) => s.split('@').next().unwrap_or(s),
+        _ => return None,
+    };
+    if user.is_empty() || Some(user) == context.get("username") {
+        return None;
+    }
+    Some(user.to_string())
+}
+
+/// The user the caller makes a request on behalf of, for a path.
+///
+/// A caller names the user in the `x-tapis-user` header, which it may only do when an ACL allows
+/// it the Impersonate action for that user and the path; as with the Admin action, the default
+/// decision never applies. Without the header, a token whose `delegation_claim` names another
+/// user, e.g., a service token acting for a user, acts for that user. Otherwise the caller acts
+/// for itself, i.e., `self`.
+///
+/// `context` is the context of the caller's own request, and `engine` decides whether the caller
+/// may impersonate the user.
+///
+pub fn effective_user(
+    engine: &dyn PolicyEngine,
+    claims: &RequestClaims,
+    context: &ClaimContext,
+    delegation_claim: Option<&str>,
+    pth: &str,
+) -> Result<String, String> {
+    if let Some(user) = &claims.on_behalf_of {
+        let decision = engine.authorize(user, &AclAction::Impersonate, pth, context);
+        if decision != Some(AclDecision::Allow) {
+            return Err(format!(
+                "subject {} is not authorized to act on behalf of user {} for {} (header {})",
+                claims.subject, user, pth, ON_BEHALF_OF_HEADER
+            ));
+        }
+        return Ok(user.clone());
+    }
+    Ok(delegation_claim
+        .and_then(|claim| delegated_user(claims, context, claim))
+        .unwrap_or_else(|| String::from("self")))
+}
+
+/// The user to evaluate a check the caller makes of itself for, given the user the check names.
+///
+/// The check may name `self`, which stands for the effective user of the caller, or the effective
+/// user itself; a caller acting for itself may also name its own username. Naming any other user
+/// is an attempt to pass as that user and is rejected.
+///
+pub fn bind_user(
+    engine: &dyn PolicyEngine,
+    claims: &RequestClaims,
+    context: &ClaimContext,
+    delegation_claim: Option<&str>,
+    usr: &str,
+    pth: &str,
+) -> Result<String, String> {
+    let effective = effective_user(engine, claims, context, delegation_claim, pth)?;
+    if usr == "self" || usr == effective {
+        return Ok(effective);
+    }
+    if effective == "self" && Some(usr) == context.get("username") {
+        return Ok(usr.to_string());
+    }
+    Err(format!(
+        "subject {} acts on behalf of {}, not of user {}",
+        claims.subject, effective, usr
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Map};
+
+    use std::sync::{Arc, RwLock};
+
+    use super::*;
+    use crate::db::save_acl;
+    use crate::db::test::{new_acl, test_connection, test_policy};
+    use crate::db::{retrieve_all_acls, retrieve_all_memberships};
+    use crate::engine::AclEngine;
+    use crate::index::AclIndex;
+    use crate::models::PolicyMode;
+
+    fn claims(sub: &str, custom: Map<String, Value>, on_behalf_of: Option<&str>) -> RequestClaims {
+        RequestClaims {
+            subject: sub.to_string(),
+            custom,
+            source: None,
+            on_behalf_of: on_behalf_of.map(String::from),
+        }
+    }
+
+    fn engine(index: AclIndex) -> AclEngine {
+        AclEngine::new(Arc::new(RwLock::new(index)), PolicyMode::DenyOverrides)
+    }
+
+    fn bind(
+        engine: &AclEngine,
+        claims: &RequestClaims,
+        delegation_claim: Option<&str>,
+        usr: &str,
+        pth: &str,
+    ) -> Result<String, String> {
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let context = policy.context_for(&claims.subject);
+        bind_user(engine, claims, &context, delegation_claim, usr, pth)
+    }
+
+    #[test]
+    fn users_cannot_be_spoofed() {
+        let acls = engine(AclIndex::empty());
+        let user = claims("jdoe@tacc", Map::new(), None);
+        assert_eq!(
+            bind(&acls, &user, None, "self", "/data"),
+            Ok(String::from("self"))
+        );
+        assert_eq!(
+            bind(&acls, &user, None, "jdoe", "/data"),
+            Ok(String::from("jdoe"))
+        );
+        assert!(bind(&acls, &user, None, "root", "/data").is_err());
+        // a header is not enough to act for another user
+        let spoofed = claims("jdoe@tacc", Map::new(), Some("root"));
+        assert!(bind(&acls, &spoofed, None, "self", "/data").is_err());
+        assert!(bind(&acls, &spoofed, None, "root", "/data").is_err());
+    }
+
+    #[test]
+    fn impersonation_requires_an_acl() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let mut acl = new_acl(
+            "jobs@admin",
+            AclAction::Impersonate,
+            "/data/.*",
+            AclDecision::Allow,
+            0,
+        );
+        acl.user = String::from("jdoe");
+        save_acl(&mut conn, &acl, "tenants@admin")?;
+        // Impersonate neither implies nor is implied by the actions on files
+        save_acl(
+            &mut conn,
+            &new_acl("apps@admin", AclAction::Admin, "/.*", AclDecision::Allow, 0),
+            "tenants@admin",
+        )?;
+        let acls = engine(AclIndex::new(
+            retrieve_all_acls(&mut conn)?,
+            retrieve_all_memberships(&mut conn)?,
+        ));
+        let service = claims("jobs@admin", Map::new(), Some("jdoe"));
+        assert_eq!(
+            bind(&acls, &service, None, "self", "/data/a"),
+            Ok(String::from("jdoe"))
+        );
+        assert_eq!(
+            bind(&acls, &service, None, "jdoe", "/data/a"),
+            Ok(String::from("jdoe"))
+        );
+        // not for another user in the check, nor outside the ACL's paths or users
+        assert!(bind(&acls, &service, None, "root", "/data/a").is_err());
+        assert!(bind(&acls, &service, None, "self", "/home/a").is_err());
+        let other = claims("jobs@admin", Map::new(), Some("root"));
+        assert!(bind(&acls, &other, None, "self", "/data/a").is_err());
+        let admin = claims("apps@admin", Map::new(), Some("jdoe"));
+        assert!(bind(&acls, &admin, None, "self", "/data/a").is_err());
+        let context = test_policy(PolicyMode::DenyOverrides).context_for("jobs@admin");
+        assert_eq!(
+            acls.authorize("jdoe", &AclAction::List, "/data/a", &context),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn delegated_tokens_act_for_their_user() {
+        let acls = engine(AclIndex::empty());
+        let mut custom = Map::new();
+        custom.insert(String::from("tapis/username"), json!("jdoe@tacc"));
+        let service = claims("jobs@admin", custom.clone(), None);
+        let claim = Some("tapis/username");
+        assert_eq!(
+            bind(&acls, &service, claim, "self", "/data"),
+            Ok(String::from("jdoe"))
+        );
+        assert!(bind(&acls, &service, claim, "root", "/data").is_err());
+        // the claim is ignored unless configured
+        assert_eq!(
+            bind(&acls, &service, None, "self", "/data"),
+            Ok(String::from("self"))
+        );
+        // a token naming its own subject acts for itself
+        custom.insert(String::from("tapis/username"), json!("jobs"));
+        let own = claims("jobs@admin", custom, None);
+        assert_eq!(
+            bind(&acls, &own, claim, "self", "/data"),
+            Ok(String::from("self"))
+        );
+    }
+}
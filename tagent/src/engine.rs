@@ -0,0 +1,701 @@
+use chrono::Utc;
+use config::Config;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::claims::ClaimContext;
+use crate::db::{normalize_acl_path, stored_conditions};
+use crate::explain::{explain_authz, AuthzExplanation, EngineTrace};
+use crate::index::{read_index, AclIndex, ACL_ADMIN_PATH};
+use crate::models::{AclAction, AclDecision, AuthzPolicy, DbAcl, DbMembership, PolicyMode};
+use crate::policy::{PolicyAcl, PolicyFile};
+use crate::posix::{PosixEngine, PosixSettings};
+use crate::representations::TagentError;
+
+// Policy engines
+// ==============
+
+/// A source of authorization decisions.
+///
+/// `context` is the context of a request, including its subject, so that the subject an engine
+/// decides for is always the one its variables and conditions are taken from. An engine returns None when it has no
+/// decision for a request, e.g., because none of its rules matched, so that the decision can be
+/// left to another engine or to the default decision of the policy.
+///
+pub trait PolicyEngine: Send + Sync {
+    /// A short description of the engine, for logs.
+    fn name(&self) -> String;
+
+    fn authorize(
+        &self,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision>;
+
+    /// The decision of the engine for a request if the ACLs stored in the database were `acls`,
+    /// e.g., to simulate changes to them; engines that do not decide with the stored ACLs decide
+    /// as they always do.
+    fn authorize_with(
+        &self,
+        _acls: &AclIndex,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        self.authorize(user, action, path, context)
+    }
+
+    /// The subjects the engine has rules for, sorted, e.g., to find the subjects with access to a
+    /// path; engines that cannot list them, such as the POSIX engine, have none.
+    fn subjects(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The trace of how the engine's ACLs evaluate a request, for engines deciding with ACLs; the
+    /// default decision of `policy` applies when no ACL matches.
+    fn explain(
+        &self,
+        _policy: &AuthzPolicy,
+        _user: &str,
+        _action: &AclAction,
+        _path: &str,
+        _context: &ClaimContext,
+    ) -> Option<AuthzExplanation> {
+        None
+    }
+
+    /// The decision of the engine for a request, or of each engine it combines, in order, with
+    /// the trace of their ACLs.
+    fn trace(
+        &self,
+        policy: &AuthzPolicy,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Vec<EngineTrace> {
+        vec![EngineTrace {
+            engine: self.name(),
+            decision: self.authorize(user, action, path, context),
+            acls: self.explain(policy, user, action, path, context),
+        }]
+    }
+}
+
+/// Whether a subject may manage ACLs: subjects listed in the configuration are always
+/// administrators, and additional subjects can be granted the Admin action on `ACL_ADMIN_PATH`.
+/// The default decision never applies here; the engine must explicitly allow the Admin action.
+pub fn is_acl_admin(
+    engine: &dyn PolicyEngine,
+    policy: &AuthzPolicy,
+    admin_subjects: &[String],
+    sub: &str,
+) -> bool {
+    if admin_subjects.iter().any(|s| s == sub) {
+        debug!("subject {} is a configured admin", sub);
+        return true;
+    }
+    let context = policy.context_for(sub);
+    let decision = engine.authorize("self", &AclAction::Admin, ACL_ADMIN_PATH, &context);
+    decision == Some(AclDecision::Allow)
+}
+
+/// The subjects an engine allows at least one action on a path, with the actions allowed, sorted
+/// by subject. Only the subjects the engine lists are considered, and the default decision never
+/// applies; the engine must explicitly allow an action.
+pub fn subjects_with_access(
+    engine: &dyn PolicyEngine,
+    policy: &AuthzPolicy,
+    usr: &str,
+    pth: &str,
+) -> Vec<(String, Vec<AclAction>)> {
+    engine
+        .subjects()
+        .into_iter()
+        .map(|sub| {
+            let context = policy.context_for(&sub);
+            let actions: Vec<AclAction> = AclAction::ALL
+                .into_iter()
+                .filter(|act| engine.authorize(usr, act, pth, &context) == Some(AclDecision::Allow))
+                .collect();
+            (sub, actions)
+        })
+        .filter(|(_, actions)| !actions.is_empty())
+        .collect()
+}
+
+/// The engine deciding requests with the ACLs stored in the database, through the index that is
+/// refreshed whenever they change.
+pub struct AclEngine {
+    index: Arc<RwLock<AclIndex>>,
+    mode: PolicyMode,
+}
+
+impl AclEngine {
+    pub fn new(index: Arc<RwLock<AclIndex>>, mode: PolicyMode) -> Self {
+        AclEngine { index, mode }
+    }
+}
+
+impl PolicyEngine for AclEngine {
+    fn name(&self) -> String {
+        String::from("acls")
+    }
+
+    fn authorize(
+        &self,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        read_index(&self.index).evaluate_in(&self.mode, context, user, path, action)
+    }
+
+    fn authorize_with(
+        &self,
+        acls: &AclIndex,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        acls.evaluate_in(&self.mode, context, user, path, action)
+    }
+
+    fn subjects(&self) -> Vec<String> {
+        read_index(&self.index).known_subjects()
+    }
+
+    fn explain(
+        &self,
+        policy: &AuthzPolicy,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AuthzExplanation> {
+        let index = read_index(&self.index);
+        Some(explain_authz(&index, policy, context, user, path, action))
+    }
+}
+
+/// The creator recorded for the ACLs of a static policy file.
+pub const STATIC_FILE_CREATOR: &str = "static-file";
+
+/// The contents of a static policy file: ACLs in the format of policy files, and the members of
+/// the groups and roles they refer to, e.g., in YAML:
+///
+/// ```yaml
+/// acls:
+///   - key: lab-read-data
+///     subject: group:lab
+///     action: Read
+///     path: /data/**
+///     pattern_type: glob
+///     user: self
+///     decision: Allow
+/// members:
+///   group:lab: [jobs@admin]
+/// ```
+///
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct StaticPolicy {
+    #[serde(flatten)]
+    pub file: PolicyFile,
+    #[serde(default)]
+    pub members: HashMap<String, Vec<String>>,
+}
+
+impl StaticPolicy {
+    /// Read a static policy file; the format (YAML or JSON) is determined by the file extension.
+    pub fn load(path: &Path) -> Result<Self, TagentError> {
+        let policy = Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize::<StaticPolicy>()?;
+        policy.file.check()?;
+        Ok(policy)
+    }
+
+    // the ACLs and memberships of the file, as if they were stored in the database
+    fn index(&self) -> AclIndex {
+        let now = format!("{}", Utc::now().format("%+"));
+        let acls = self
+            .file
+            .acls
+            .iter()
+            .zip(1..)
+            .map(|(entry, id)| static_acl(id, entry, &now))
+            .collect();
+        let memberships = self
+            .members
+            .iter()
+            .flat_map(|(principal, members)| members.iter().map(move |m| (principal, m)))
+            .zip(1..)
+            .map(|((principal, member), id)| DbMembership {
+                id,
+                principal: principal.clone(),
+                member: member.clone(),
+                create_by: STATIC_FILE_CREATOR.to_string(),
+                create_time: now.clone(),
+            })
+            .collect();
+        AclIndex::new(acls, memberships)
+    }
+}
+
+// an ACL of a static policy file; ids are positions in the file, and the key is the policy key
+fn static_acl(id: i32, entry: &PolicyAcl, create_time: &str) -> DbAcl {
+    let acl = &entry.acl;
+    let (source_cidrs, time_windows) = stored_conditions(acl);
+    DbAcl {
+        id,
+        subject: acl.subject.clone(),
+        action: acl.action,
+        path: normalize_acl_path(&acl.path, &acl.pattern_type),
+        user: acl.user.clone(),
+        create_by: STATIC_FILE_CREATOR.to_string(),
+        create_time: create_time.to_string(),
+        decision: acl.decision,
+        priority: acl.priority,
+        pattern_type: acl.pattern_type,
+        valid_from: acl.valid_from.clone(),
+        valid_until: acl.valid_until.clone(),
+        enabled: acl.enabled,
+        policy_key: Some(entry.key.clone()),
+        revision: 1,
+        source_cidrs,
+        time_windows,
+    }
+}
+
+/// The engine deciding requests with ACLs read from a static policy file at startup; they are
+/// evaluated like the ACLs in the database, but cannot be changed through the API.
+pub struct StaticEngine {
+    path: PathBuf,
+    index: AclIndex,
+    mode: PolicyMode,
+}
+
+impl StaticEngine {
+    pub fn load(path: &Path, mode: PolicyMode) -> Result<Self, TagentError> {
+        let policy = StaticPolicy::load(path).map_err(|e| {
+            TagentError::from(format!(
+                "Could not load static policy file {:?}; details: {}",
+                path, e
+            ))
+        })?;
+        Ok(Self::new(path, &policy, mode))
+    }
+
+    pub fn new(path: &Path, policy: &StaticPolicy, mode: PolicyMode) -> Self {
+        StaticEngine {
+            path: path.to_path_buf(),
+            index: policy.index(),
+            mode,
+        }
+    }
+}
+
+impl PolicyEngine for StaticEngine {
+    fn name(&self) -> String {
+        format!("static file {:?}", self.path)
+    }
+
+    fn authorize(
+        &self,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        self.index
+            .evaluate_in(&self.mode, context, user, path, action)
+    }
+
+    fn subjects(&self) -> Vec<String> {
+        self.index.known_subjects()
+    }
+
+    fn explain(
+        &self,
+        policy: &AuthzPolicy,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AuthzExplanation> {
+        Some(explain_authz(
+            &self.index,
+            policy,
+            context,
+            user,
+            path,
+            action,
+        ))
+    }
+}
+
+/// How the decisions of the engines of a `CompositeEngine` are combined; engines without a
+/// decision are ignored.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum EnginePrecedence {
+    // any engine denying the request denies it
+    #[default]
+    DenyOverrides,
+    // any engine allowing the request allows it
+    AllowOverrides,
+    // the first engine, in order, with a decision decides
+    FirstApplicable,
+}
+
+/// An engine combining the decisions of other engines.
+pub struct CompositeEngine {
+    engines: Vec<Box<dyn PolicyEngine>>,
+    precedence: EnginePrecedence,
+}
+
+impl CompositeEngine {
+    pub fn new(engines: Vec<Box<dyn PolicyEngine>>, precedence: EnginePrecedence) -> Self {
+        CompositeEngine {
+            engines,
+            precedence,
+        }
+    }
+
+    // combines the decisions of the engines, as made by `decide`
+    fn combine<F>(&self, decide: F) -> Option<AclDecision>
+    where
+        F: Fn(&dyn PolicyEngine) -> Option<AclDecision>,
+    {
+        let mut decisions = self.engines.iter().filter_map(|e| {
+            let d = decide(e.as_ref());
+            debug!("engine {} decided {:?}", e.name(), d);
+            d
+        });
+        let overriding = match self.precedence {
+            EnginePrecedence::FirstApplicable => return decisions.next(),
+            EnginePrecedence::DenyOverrides => AclDecision::Deny,
+            EnginePrecedence::AllowOverrides => AclDecision::Allow,
+        };
+        // every engine is asked, so that each logs its decision
+        let decisions: Vec<AclDecision> = decisions.collect();
+        if decisions.contains(&overriding) {
+            Some(overriding)
+        } else {
+            decisions.first().copied()
+        }
+    }
+}
+
+impl PolicyEngine for CompositeEngine {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.engines.iter().map(|e| e.name()).collect();
+        format!("{:?} of [{}]", self.precedence, names.join(", "))
+    }
+
+    fn authorize(
+        &self,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        self.combine(|e| e.authorize(user, action, path, context))
+    }
+
+    fn authorize_with(
+        &self,
+        acls: &AclIndex,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        self.combine(|e| e.authorize_with(acls, user, action, path, context))
+    }
+
+    fn subjects(&self) -> Vec<String> {
+        let mut subjects: Vec<String> = self.engines.iter().flat_map(|e| e.subjects()).collect();
+        subjects.sort_unstable();
+        subjects.dedup();
+        subjects
+    }
+
+    fn trace(
+        &self,
+        policy: &AuthzPolicy,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Vec<EngineTrace> {
+        self.engines
+            .iter()
+            .flat_map(|e| e.trace(policy, user, action, path, context))
+            .collect()
+    }
+}
+
+/// An engine that can be enabled in the configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EngineConfig {
+    // the ACLs stored in the database
+    Acls,
+    // the ACLs of a static policy file
+    Static { path: PathBuf },
+    // the POSIX permissions of the files under the root directory
+    Posix(PosixSettings),
+}
+
+/// Build the engine that decides authorization requests from the engines enabled in the
+/// configuration, in order; several engines are combined according to `precedence`.
+pub fn build_engine(
+    configs: &[EngineConfig],
+    precedence: EnginePrecedence,
+    mode: PolicyMode,
+    acl_index: &Arc<RwLock<AclIndex>>,
+    root_dir: &Path,
+) -> Result<Box<dyn PolicyEngine>, TagentError> {
+    let mut engines: Vec<Box<dyn PolicyEngine>> = Vec::new();
+    for config in configs {
+        engines.push(match config {
+            EngineConfig::Acls => Box::new(AclEngine::new(acl_index.clone(), mode)),
+            EngineConfig::Static { path } => Box::new(StaticEngine::load(path, mode)?),
+            EngineConfig::Posix(settings) => Box::new(PosixEngine::new(root_dir, settings.clone())),
+        });
+    }
+    match engines.len() {
+        0 => Err(TagentError::from(
+            "At least one policy engine must be enabled",
+        )),
+        1 => Ok(engines.remove(0)),
+        _ => Ok(Box::new(CompositeEngine::new(engines, precedence))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::db::save_acl;
+    use crate::db::test::{new_acl, test_connection, test_policy};
+    use crate::explain::explain_decision;
+    use crate::index::refresh_index;
+
+    // an engine with the same decision for every request
+    struct Fixed(Option<AclDecision>);
+
+    impl PolicyEngine for Fixed {
+        fn name(&self) -> String {
+            format!("fixed {:?}", self.0)
+        }
+
+        fn authorize(
+            &self,
+            _user: &str,
+            _action: &AclAction,
+            _path: &str,
+            _context: &ClaimContext,
+        ) -> Option<AclDecision> {
+            self.0
+        }
+    }
+
+    fn composite(
+        precedence: EnginePrecedence,
+        decisions: &[Option<AclDecision>],
+    ) -> CompositeEngine {
+        CompositeEngine::new(
+            decisions
+                .iter()
+                .map(|d| Box::new(Fixed(*d)) as Box<dyn PolicyEngine>)
+                .collect(),
+            precedence,
+        )
+    }
+
+    #[test]
+    fn composite_engines_combine_decisions_by_precedence() {
+        let context = ClaimContext::for_subject("jobs@admin");
+        let decide =
+            |engine: CompositeEngine| engine.authorize("self", &AclAction::Read, "/a", &context);
+        let (allow, deny) = (Some(AclDecision::Allow), Some(AclDecision::Deny));
+        let decisions = [None, allow, deny];
+        assert_eq!(
+            decide(composite(EnginePrecedence::DenyOverrides, &decisions)),
+            deny
+        );
+        assert_eq!(
+            decide(composite(EnginePrecedence::AllowOverrides, &decisions)),
+            allow
+        );
+        assert_eq!(
+            decide(composite(EnginePrecedence::FirstApplicable, &decisions)),
+            allow
+        );
+        assert_eq!(
+            decide(composite(EnginePrecedence::DenyOverrides, &[None, None])),
+            None
+        );
+    }
+
+    #[test]
+    fn configured_admin_subjects_are_admins() {
+        let engine = Fixed(None);
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let admins = vec![String::from("tenants@admin")];
+        assert!(is_acl_admin(&engine, &policy, &admins, "tenants@admin"));
+        assert!(!is_acl_admin(&engine, &policy, &admins, "jobs@admin"));
+    }
+
+    #[test]
+    fn admin_action_acl_grants_admin() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let mut policy = test_policy(PolicyMode::DenyOverrides);
+        let index = Arc::new(RwLock::new(AclIndex::empty()));
+        let engine = AclEngine::new(index.clone(), policy.mode);
+        let sub = "jobs@admin";
+        save_acl(
+            &mut conn,
+            &new_acl(sub, AclAction::Write, "/.*", AclDecision::Allow, 0),
+            "tenants@admin",
+        )?;
+        refresh_index(&mut conn, &index)?;
+        // a broad Write ACL does not make the subject an admin
+        assert!(!is_acl_admin(&engine, &policy, &[], sub));
+        // and neither does an Allow default decision
+        policy.default_decision = AclDecision::Allow;
+        assert!(!is_acl_admin(&engine, &policy, &[], sub));
+        save_acl(
+            &mut conn,
+            &new_acl(sub, AclAction::Admin, ACL_ADMIN_PATH, AclDecision::Allow, 0),
+            "tenants@admin",
+        )?;
+        refresh_index(&mut conn, &index)?;
+        assert!(is_acl_admin(&engine, &policy, &[], sub));
+        // any decision other than Allow does not either
+        assert!(!is_acl_admin(
+            &Fixed(Some(AclDecision::Deny)),
+            &policy,
+            &[],
+            sub
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn posix_engine_does_not_decide_admin_of_non_local_subjects() -> Result<(), TagentError> {
+        let dir = tempfile::TempDir::new()?;
+        let mut conn = test_connection();
+        let sub = "jobs@admin";
+        save_acl(
+            &mut conn,
+            &new_acl(sub, AclAction::Admin, ACL_ADMIN_PATH, AclDecision::Allow, 0),
+            "tenants@admin",
+        )
+        .map_err(|e| e.to_string())?;
+        let index = Arc::new(RwLock::new(AclIndex::empty()));
+        refresh_index(&mut conn, &index).map_err(|e| e.to_string())?;
+        // jobs@admin has no local account, so the POSIX engine denies its file operations
+        let posix = PosixSettings {
+            root_directory: None,
+            users: HashMap::new(),
+            user_variable: String::from("username"),
+            grant: false,
+        };
+        let configs = [EngineConfig::Acls, EngineConfig::Posix(posix)];
+        let engine = build_engine(
+            &configs,
+            EnginePrecedence::DenyOverrides,
+            PolicyMode::DenyOverrides,
+            &index,
+            dir.path(),
+        )?;
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let context = ClaimContext::for_subject(sub);
+        assert_eq!(
+            engine.authorize("self", &AclAction::Read, "/a.txt", &context),
+            Some(AclDecision::Deny)
+        );
+        assert!(is_acl_admin(engine.as_ref(), &policy, &[], sub));
+        Ok(())
+    }
+
+    #[test]
+    fn static_and_db_acls_are_combined() -> Result<(), TagentError> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("static.yaml");
+        std::fs::File::create(&path)?.write_all(
+            b"acls:\n  - key: lab\n    subject: group:lab\n    action: Write\n    path: /data/**\n    pattern_type: glob\n    user: self\n    decision: Deny\nmembers:\n  group:lab: [jobs@admin]\n",
+        )?;
+        let mut conn = test_connection();
+        save_acl(
+            &mut conn,
+            &new_acl("jobs@admin", AclAction::Write, "/.*", AclDecision::Allow, 0),
+            "tenants@admin",
+        )
+        .map_err(|e| e.to_string())?;
+        let index = Arc::new(RwLock::new(AclIndex::empty()));
+        refresh_index(&mut conn, &index).map_err(|e| e.to_string())?;
+        let engine_name = format!("static file {:?}", path);
+        let configs = [EngineConfig::Acls, EngineConfig::Static { path }];
+        let engine = build_engine(
+            &configs,
+            EnginePrecedence::DenyOverrides,
+            PolicyMode::DenyOverrides,
+            &index,
+            dir.path(),
+        )?;
+
+        let context = ClaimContext::for_subject("jobs@admin");
+        let decide = |pth: &str| engine.authorize("self", &AclAction::Write, pth, &context);
+        assert_eq!(decide("/data/a.txt"), Some(AclDecision::Deny));
+        assert_eq!(decide("/tmp/a.txt"), Some(AclDecision::Allow));
+
+        // explanations trace the ACLs of every engine, and name the engine that decided
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let explain = |pth: &str| {
+            explain_decision(
+                engine.as_ref(),
+                &policy,
+                "self",
+                pth,
+                &AclAction::Write,
+                &context,
+            )
+        };
+        let e = explain("/data/a.txt");
+        let names: Vec<&str> = e.engines.iter().map(|t| t.engine.as_str()).collect();
+        assert_eq!(names, vec!["acls", engine_name.as_str()]);
+        assert_eq!(e.decided_by, Some(engine_name.clone()));
+        assert_eq!(e.decision, AclDecision::Deny);
+        let traced: Vec<Option<i32>> = e
+            .engines
+            .iter()
+            .map(|t| t.acls.as_ref().and_then(|a| a.decided_by))
+            .collect();
+        assert_eq!(traced, vec![Some(1), Some(1)]);
+        let e = explain("/tmp/a.txt");
+        assert_eq!(e.decided_by, Some(String::from("acls")));
+        assert_eq!(e.engines[1].decision, None);
+        assert!(build_engine(
+            &[],
+            EnginePrecedence::DenyOverrides,
+            PolicyMode::DenyOverrides,
+            &index,
+            dir.path()
+        )
+        .is_err());
+        Ok(())
+    }
+}
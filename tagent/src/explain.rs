@@ -0,0 +1,312 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::claims::ClaimContext;
+use crate::conditions::{conditions_hold, split_items};
+use crate::db::deciding_acl;
+use crate::engine::PolicyEngine;
+use crate::index::AclIndex;
+use crate::models::{
+    AclAction, AclDecision, AclStatus, AuthzPolicy, DbAcl, PatternType, PolicyMode,
+};
+use crate::patterns::Matcher;
+
+// Explaining authorization decisions
+// ==================================
+
+/// How a candidate ACL was evaluated against a request.
+///
+/// The candidates of a request are the ACLs of the requesting subject and of the groups and
+/// roles it belongs to; an ACL matches the request when it is active, every field matched and
+/// its conditions hold. `source_matched` is None when the ACL has source ranges but the source of
+/// the request is not known, in which case the conditions hold only for a Deny ACL.
+///
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AclTrace {
+    pub acl_id: i32,
+    pub subject: String,
+    pub user: String,
+    pub path: String,
+    pub pattern_type: PatternType,
+    pub action: AclAction,
+    pub decision: AclDecision,
+    pub priority: i32,
+    pub status: AclStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source_cidrs: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<String>,
+    pub subject_matched: bool,
+    pub user_matched: bool,
+    pub path_matched: bool,
+    pub action_matched: bool,
+    pub source_matched: Option<bool>,
+    pub time_matched: bool,
+    pub conditions_matched: bool,
+    pub matched: bool,
+}
+
+/// The evaluation trace of an authorization request.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AuthzExplanation {
+    pub mode: PolicyMode,
+    // the source address and local time of the request the conditions of ACLs were checked against
+    pub source: Option<String>,
+    pub time: String,
+    // the subject followed by the groups and roles whose ACLs were candidates
+    pub principals: Vec<String>,
+    pub candidates: Vec<AclTrace>,
+    // the ACL that determined the decision, if any ACL matched
+    pub decided_by: Option<i32>,
+    // whether no ACL matched, so that the default decision for the path applied
+    pub default_applied: bool,
+    pub decision: AclDecision,
+}
+
+impl AuthzExplanation {
+    pub fn is_allowed(&self) -> bool {
+        self.decision == AclDecision::Allow
+    }
+}
+
+fn trace_acl(
+    acl: &DbAcl,
+    principals: &[String],
+    context: &ClaimContext,
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+) -> AclTrace {
+    let status = acl.status_at(&Utc::now());
+    let subject_matched = principals.contains(&acl.subject);
+    let user_matched = Matcher::for_user_in(&acl.user, context).is_match(usr);
+    let path_matched = Matcher::for_db_path_in(&acl.pattern_type, &acl.path, context).is_match(pth);
+    let action_matched = acl.matches_action(act);
+    let conditions = acl.conditions();
+    let source_matched = match &conditions {
+        Some(c) => c.source_matches(context.source()),
+        None => None,
+    };
+    let time_matched = conditions
+        .as_ref()
+        .is_some_and(|c| c.time_matches(context.time()));
+    let conditions_matched = conditions_hold(conditions.as_ref(), acl.decision, context);
+    AclTrace {
+        acl_id: acl.id,
+        subject: acl.subject.clone(),
+        user: acl.user.clone(),
+        path: acl.path.clone(),
+        pattern_type: acl.pattern_type,
+        action: acl.action,
+        decision: acl.decision,
+        priority: acl.priority,
+        status,
+        source_cidrs: split_items(acl.source_cidrs.as_deref()),
+        time_windows: split_items(acl.time_windows.as_deref()),
+        subject_matched,
+        user_matched,
+        path_matched,
+        action_matched,
+        source_matched,
+        time_matched,
+        conditions_matched,
+        matched: status == AclStatus::Active
+            && subject_matched
+            && user_matched
+            && path_matched
+            && action_matched
+            && conditions_matched,
+    }
+}
+
+/// Evaluate a request against the index, recording how every candidate ACL was evaluated.
+///
+/// Reaches the same decision as `AclIndex::is_authz_in`, but checks every candidate ACL rather
+/// than only those found by the path lookup, so it is meant for troubleshooting rather than for
+/// every request.
+///
+pub fn explain_authz(
+    index: &AclIndex,
+    policy: &AuthzPolicy,
+    context: &ClaimContext,
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+) -> AuthzExplanation {
+    let principals = index.principals_for(context.subject());
+    let acls: Vec<&DbAcl> = principals
+        .iter()
+        .flat_map(|p| index.acls_for_subject(p))
+        .collect();
+    let candidates: Vec<AclTrace> = acls
+        .iter()
+        .map(|acl| trace_acl(acl, &principals, context, usr, pth, act))
+        .collect();
+    let matched = acls
+        .iter()
+        .zip(&candidates)
+        .filter(|(_, t)| t.matched)
+        .map(|(acl, _)| *acl);
+    let decided_by = deciding_acl(&policy.mode, matched);
+    AuthzExplanation {
+        mode: policy.mode,
+        source: context.source().map(|s| s.to_string()),
+        time: context.time().to_rfc3339(),
+        principals,
+        candidates,
+        decided_by: decided_by.map(|acl| acl.id),
+        default_applied: decided_by.is_none(),
+        decision: match decided_by {
+            Some(acl) => acl.decision,
+            None => policy.default_decision_for_path(pth),
+        },
+    }
+}
+
+/// The decision of a single policy engine for a request.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct EngineTrace {
+    pub engine: String,
+    // None when the engine has no decision for the request
+    pub decision: Option<AclDecision>,
+    // how the ACLs of the engine were evaluated, for engines deciding with ACLs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acls: Option<AuthzExplanation>,
+}
+
+/// The explanation of an authorization decision: the decision of every policy engine, in order,
+/// the engine whose decision was taken, and whether the default decision applied because no
+/// engine had a decision.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DecisionExplanation {
+    pub engines: Vec<EngineTrace>,
+    pub decided_by: Option<String>,
+    pub default_applied: bool,
+    pub decision: AclDecision,
+}
+
+/// Explain how the policy engines decide a request; see `explain_authz` for how the ACLs of
+/// engines deciding with ACLs are traced. When several engines agree on the decision, the first
+/// of them is reported as the deciding engine.
+pub fn explain_decision(
+    engine: &dyn PolicyEngine,
+    policy: &AuthzPolicy,
+    usr: &str,
+    pth: &str,
+    act: &AclAction,
+    context: &ClaimContext,
+) -> DecisionExplanation {
+    let engines = engine.trace(policy, usr, act, pth, context);
+    let decision = engine.authorize(usr, act, pth, context);
+    let decided_by = engines
+        .iter()
+        .find(|t| decision.is_some() && t.decision == decision)
+        .map(|t| t.engine.clone());
+    DecisionExplanation {
+        engines,
+        decided_by,
+        default_applied: decision.is_none(),
+        decision: decision.unwrap_or_else(|| policy.default_decision_for_path(pth)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::retrieve_all_acls;
+    use crate::db::test::{new_acl, test_connection, test_policy};
+    use crate::db::{retrieve_all_memberships, save_acl, save_membership};
+
+    #[test]
+    fn explanations_agree_with_the_index() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let allow = new_acl(
+            "group:lab",
+            AclAction::Write,
+            "/data/.*",
+            AclDecision::Allow,
+            0,
+        );
+        let deny = new_acl(
+            "jobs@admin",
+            AclAction::Read,
+            "/data/secret.*",
+            AclDecision::Deny,
+            0,
+        );
+        let other = new_acl(
+            "files@admin",
+            AclAction::Read,
+            "/data/.*",
+            AclDecision::Allow,
+            0,
+        );
+        let allow_id = save_acl(&mut conn, &allow, "tenants@admin")?;
+        let deny_id = save_acl(&mut conn, &deny, "tenants@admin")?;
+        save_acl(&mut conn, &other, "tenants@admin")?;
+        save_membership(&mut conn, "group:lab", "jobs@admin", "tenants@admin")?;
+        let index = AclIndex::new(
+            retrieve_all_acls(&mut conn)?,
+            retrieve_all_memberships(&mut conn)?,
+        );
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let context = ClaimContext::for_subject("jobs@admin");
+
+        for (pth, act) in [
+            ("/data/a.txt", AclAction::Read),
+            ("/data/secret.txt", AclAction::Read),
+            ("/other", AclAction::Read),
+            ("/data/a.txt", AclAction::Admin),
+        ] {
+            let e = explain_authz(&index, &policy, &context, "self", pth, &act);
+            assert_eq!(
+                e.is_allowed(),
+                index.is_authz(&policy, "jobs@admin", "self", pth, &act),
+                "{} {}",
+                act,
+                pth
+            );
+            // ACLs of other subjects are never candidates
+            assert_eq!(e.candidates.len(), 2);
+        }
+
+        let e = explain_authz(
+            &index,
+            &policy,
+            &context,
+            "self",
+            "/data/secret.txt",
+            &AclAction::Read,
+        );
+        assert_eq!(e.principals, vec!["jobs@admin", "group:lab"]);
+        assert_eq!(e.decided_by, Some(deny_id));
+        assert!(!e.default_applied);
+        assert!(e.candidates.iter().all(|t| t.matched));
+
+        let e = explain_authz(
+            &index,
+            &policy,
+            &context,
+            "self",
+            "/data/a.txt",
+            &AclAction::Read,
+        );
+        assert_eq!(e.decided_by, Some(allow_id));
+        let deny_trace = e.candidates.iter().find(|t| t.acl_id == deny_id).unwrap();
+        assert!(deny_trace.subject_matched && deny_trace.action_matched);
+        assert!(!deny_trace.path_matched && !deny_trace.matched);
+
+        let e = explain_authz(
+            &index,
+            &policy,
+            &context,
+            "self",
+            "/other",
+            &AclAction::Read,
+        );
+        assert_eq!(e.decided_by, None);
+        assert!(e.default_applied);
+        assert_eq!(e.decision, policy.default_decision);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use config::{Config, FileFormat};
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use yaml_rust::{Yaml, YamlEmitter};
+
+use crate::db::{delete_api_acls, is_same_acl, retrieve_all_acls, save_acl};
+use crate::models::{DbAcl, FieldError, NewAclJson};
+use std::collections::HashMap;
+
+// Exporting and importing ACLs
+// ============================
+
+/// The version of the format of exported ACL documents; importing a document with a different
+/// version is rejected.
+pub const ACL_DOCUMENT_VERSION: u32 = 1;
+
+/// A document containing ACLs, used to move ACLs between servers.
+///
+/// ACLs managed by the policy file are not exported, since every server reconciles them with
+/// its own policy file.
+///
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AclDocument {
+    pub version: u32,
+    // the version of tagent that exported the document
+    #[serde(default)]
+    pub tagent_version: String,
+    #[serde(default)]
+    pub acls: Vec<NewAclJson>,
+}
+
+/// The formats an `AclDocument` can be written in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl DocumentFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    // the format of a request body with the given content type; JSON unless the type mentions yaml
+    pub fn from_content_type(content_type: &str) -> Self {
+        if content_type.contains("yaml") {
+            Self::Yaml
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// How an import combines the imported ACLs with the existing ones.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    // add the imported ACLs, skipping those identical to an existing ACL
+    #[default]
+    Merge,
+    // delete every ACL not managed by the policy file, then add the imported ACLs
+    Replace,
+}
+
+/// The outcome of an import.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ImportSummary {
+    pub created: usize,
+    // imported ACLs that were identical to an existing ACL (merge mode only)
+    pub skipped: usize,
+    pub deleted: usize,
+}
+
+impl AclDocument {
+    /// Build a document from the ACLs in the database.
+    pub fn from_db_acls(acls: &[DbAcl], tagent_version: &str) -> Self {
+        let acls = acls
+            .iter()
+            .filter(|a| a.policy_key.is_none())
+            .map(NewAclJson::from_db_acl)
+            .collect();
+        AclDocument {
+            version: ACL_DOCUMENT_VERSION,
+            tagent_version: tagent_version.to_string(),
+            acls,
+        }
+    }
+
+    /// Parse a document; actions and decisions are checked while parsing.
+    pub fn parse(body: &str, format: DocumentFormat) -> Result<Self, String> {
+        match format {
+            DocumentFormat::Json => serde_json::from_str(body).map_err(|e| e.to_string()),
+            DocumentFormat::Yaml => Config::builder()
+                .add_source(config::File::from_str(body, FileFormat::Yaml))
+                .build()
+                .and_then(|c| c.try_deserialize())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Write the document in a format.
+    pub fn write(&self, format: DocumentFormat) -> Result<String, String> {
+        let value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        match format {
+            DocumentFormat::Json => serde_json::to_string_pretty(&value).map_err(|e| e.to_string()),
+            DocumentFormat::Yaml => {
+                let mut out = String::new();
+                YamlEmitter::new(&mut out)
+                    .dump(&json_to_yaml(&value))
+                    .map_err(|e| format!("{:?}", e))?;
+                out.push('\n');
+                Ok(out)
+            }
+        }
+    }
+
+    /// Check every ACL in the document; returns all of the problems found, with the fields of
+    /// each ACL prefixed with the position of the ACL in the document, e.g., `acls[2].path`.
+    /// Template variables must be built in or be mapped to a claim by `claim_variables`.
+    pub fn check(&self, claim_variables: &HashMap<String, String>) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.version != ACL_DOCUMENT_VERSION {
+            errors.push(FieldError::new(
+                "version",
+                format!("must be {}", ACL_DOCUMENT_VERSION),
+            ));
+        }
+        for (i, acl) in self.acls.iter().enumerate() {
+            let parent = format!("acls[{}]", i);
+            errors.extend(
+                acl.validate()
+                    .into_iter()
+                    .chain(acl.validate_variables(claim_variables))
+                    .map(|e| e.nested(&parent)),
+            );
+        }
+        errors
+    }
+}
+
+fn json_to_yaml(value: &serde_json::Value) -> Yaml {
+    use serde_json::Value;
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Yaml::Integer(i),
+            None => Yaml::Real(n.to_string()),
+        },
+        Value::String(s) => Yaml::String(s.clone()),
+        Value::Array(a) => Yaml::Array(a.iter().map(json_to_yaml).collect()),
+        Value::Object(o) => Yaml::Hash(
+            o.iter()
+                .map(|(k, v)| (Yaml::String(k.clone()), json_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Apply a checked document to the database.
+///
+/// Meant to run inside a transaction (e.g., with `index::change_acls`), so that either all of
+/// the ACLs are imported or none are.
+///
+pub fn import_acls(
+    conn: &mut SqliteConnection,
+    document: &AclDocument,
+    mode: ImportMode,
+    create_by: &str,
+) -> Result<ImportSummary, diesel::result::Error> {
+    let deleted = match mode {
+        ImportMode::Replace => delete_api_acls(conn, create_by)?,
+        ImportMode::Merge => 0,
+    };
+    let existing = retrieve_all_acls(conn)?;
+    let mut summary = ImportSummary {
+        created: 0,
+        skipped: 0,
+        deleted,
+    };
+    for acl in &document.acls {
+        if mode == ImportMode::Merge && existing.iter().any(|e| is_same_acl(e, acl)) {
+            summary.skipped += 1;
+            continue;
+        }
+        save_acl(conn, acl, create_by)?;
+        summary.created += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test::{new_acl, test_connection};
+    use crate::models::{AclAction, AclDecision};
+
+    #[test]
+    fn documents_round_trip_through_json_and_yaml() -> Result<(), String> {
+        let mut conn = test_connection();
+        let mut acl = new_acl(
+            "jobs@admin",
+            AclAction::Read,
+            "/data/.*",
+            AclDecision::Allow,
+            3,
+        );
+        acl.valid_until = Some(String::from("2999-01-01T00:00:00Z"));
+        save_acl(&mut conn, &acl, "tenants@admin").map_err(|e| e.to_string())?;
+        let acls = retrieve_all_acls(&mut conn).map_err(|e| e.to_string())?;
+        let document = AclDocument::from_db_acls(&acls, "0.1.0");
+        for format in [DocumentFormat::Json, DocumentFormat::Yaml] {
+            let written = document.write(format)?;
+            assert_eq!(
+                AclDocument::parse(&written, format)?,
+                document,
+                "{}",
+                written
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn all_problems_are_reported() -> Result<(), String> {
+        let document = AclDocument {
+            version: ACL_DOCUMENT_VERSION,
+            tagent_version: String::new(),
+            acls: vec![
+                new_acl(
+                    "jobs@admin",
+                    AclAction::Read,
+                    "/data/*(",
+                    AclDecision::Allow,
+                    0,
+                ),
+                new_acl("jobs@admin", AclAction::Read, "/ok", AclDecision::Allow, 0),
+                NewAclJson {
+                    valid_from: Some(String::from("tomorrow")),
+                    ..new_acl("jobs@admin", AclAction::Read, "/ok", AclDecision::Allow, 0)
+                },
+            ],
+        };
+        let errors = document.check(&HashMap::new());
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["acls[0].path", "acls[2].valid_from"]);
+        // unknown actions are rejected when parsing
+        let body = r#"{"version": 1, "acls": [{"subject": "s", "action": "Fly", "path": "/", "user": "self", "decision": "Allow"}]}"#;
+        assert!(AclDocument::parse(body, DocumentFormat::Json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_skips_existing_acls_and_replace_deletes_them() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let a = new_acl("jobs@admin", AclAction::Read, "/a", AclDecision::Allow, 0);
+        let b = new_acl("jobs@admin", AclAction::Read, "/b", AclDecision::Allow, 0);
+        save_acl(&mut conn, &a, "tenants@admin")?;
+        let document = AclDocument {
+            version: ACL_DOCUMENT_VERSION,
+            tagent_version: String::new(),
+            acls: vec![a.clone(), b],
+        };
+        let summary = import_acls(&mut conn, &document, ImportMode::Merge, "tenants@admin")?;
+        assert_eq!(
+            (summary.created, summary.skipped, summary.deleted),
+            (1, 1, 0)
+        );
+        let summary = import_acls(&mut conn, &document, ImportMode::Replace, "tenants@admin")?;
+        assert_eq!(
+            (summary.created, summary.skipped, summary.deleted),
+            (2, 0, 2)
+        );
+        assert_eq!(retrieve_all_acls(&mut conn)?.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,823 @@
+use chrono::Utc;
+use diesel::SqliteConnection;
+use log::{debug, error};
+use regex::RegexSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+
+use crate::claims::{has_variables, ClaimContext};
+use crate::conditions::{conditions_hold, AclConditions};
+use crate::db::{
+    combine_decisions, delete_expired_acls, normalize_acl_path, retrieve_all_acls,
+    retrieve_all_memberships, transaction_mut,
+};
+use crate::models::{
+    parse_principal, AclAction, AclDecision, AclStatus, AclValidity, AuthzCheckJson, AuthzPolicy,
+    DbAcl, DbMembership, PolicyMode,
+};
+use crate::patterns::{Matcher, RegexCache};
+use crate::principals::Memberships;
+
+// In-memory ACL index
+// ===================
+
+// the path checked against ACLs with the Admin action to determine whether a subject may manage ACLs
+pub const ACL_ADMIN_PATH: &str = "/";
+
+/// An ACL with its path and user compiled for matching, and its validity period and conditions
+/// parsed.
+///
+/// The path and user of an ACL with template variables depend on the request, so they are only
+/// prepared for matching once the request is known.
+///
+#[derive(Debug)]
+pub struct CompiledAcl {
+    pub acl: DbAcl,
+    user: Matcher,
+    validity: AclValidity,
+    // None if the conditions cannot be parsed, in which case they fail closed
+    conditions: Option<AclConditions>,
+    templated: bool,
+}
+
+impl CompiledAcl {
+    fn user_matches(&self, usr: &str, context: &ClaimContext, cache: &RegexCache) -> bool {
+        if self.templated {
+            Matcher::for_user_in_cached(&self.acl.user, context, cache).is_match(usr)
+        } else {
+            self.user.is_match(usr)
+        }
+    }
+}
+
+/// The ACLs of a single subject.
+///
+/// ACLs are looked up by path first: ACLs whose path is exactly the requested path are found
+/// with a hash lookup, and ACLs with a path pattern are found with a single pass of a
+/// `RegexSet` containing all of the subject's path patterns.
+///
+#[derive(Debug)]
+struct SubjectAcls {
+    acls: Vec<CompiledAcl>,
+    // maps a path to the positions in `acls` of the ACLs with exactly that path
+    literal_paths: HashMap<String, Vec<usize>>,
+    path_patterns: RegexSet,
+    // the position in `acls` of the ACL for each pattern in `path_patterns`
+    pattern_acls: Vec<usize>,
+    // the positions in `acls` of the ACLs with template variables, which are matched one by one
+    templated: Vec<usize>,
+    // the regexes of the ACLs with template variables, once substituted
+    regexes: RegexCache,
+}
+
+impl SubjectAcls {
+    fn new(acls: Vec<DbAcl>) -> Self {
+        let mut literal_paths: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut patterns = Vec::new();
+        let mut pattern_acls = Vec::new();
+        let mut templated = Vec::new();
+        let mut compiled = Vec::new();
+        for (i, acl) in acls.into_iter().enumerate() {
+            let is_templated = has_variables(&acl.path) || has_variables(&acl.user);
+            if is_templated {
+                templated.push(i);
+            } else {
+                // ACLs saved before paths were normalized may not be
+                let path = normalize_acl_path(&acl.path, &acl.pattern_type);
+                // a path always matches itself, whatever the pattern type
+                literal_paths.entry(path).or_default().push(i);
+                if let Some(re) = Matcher::for_db_path(&acl.pattern_type, &acl.path).regex() {
+                    patterns.push(re.as_str().to_string());
+                    pattern_acls.push(i);
+                }
+            }
+            compiled.push(CompiledAcl {
+                user: Matcher::for_user(&acl.user),
+                validity: acl.validity(),
+                conditions: acl.conditions(),
+                templated: is_templated,
+                acl,
+            });
+        }
+        // every pattern compiled individually, so the set compiles as well
+        let path_patterns = RegexSet::new(&patterns).unwrap_or_else(|e| {
+            error!("could not build RegexSet for ACL paths; details: {}", e);
+            RegexSet::empty()
+        });
+        SubjectAcls {
+            acls: compiled,
+            literal_paths,
+            path_patterns,
+            pattern_acls,
+            templated,
+            regexes: RegexCache::default(),
+        }
+    }
+
+    // returns the ACLs whose path matches a path, in the order they were added; template variables
+    // are substituted from the context of the request
+    fn for_path(&self, pth: &str, context: &ClaimContext) -> Vec<&CompiledAcl> {
+        let mut positions: Vec<usize> = self.literal_paths.get(pth).cloned().unwrap_or_default();
+        for m in self.path_patterns.matches(pth).iter() {
+            positions.push(self.pattern_acls[m]);
+        }
+        for &i in &self.templated {
+            let acl = &self.acls[i].acl;
+            if Matcher::for_db_path_in_cached(&acl.pattern_type, &acl.path, context, &self.regexes)
+                .is_match(pth)
+            {
+                positions.push(i);
+            }
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        positions.into_iter().map(|i| &self.acls[i]).collect()
+    }
+}
+
+/// An in-memory index of all ACLs, keyed by subject, and of the group and role memberships used
+/// to find the ACLs that apply to a subject.
+#[derive(Debug)]
+pub struct AclIndex {
+    subjects: HashMap<String, SubjectAcls>,
+    memberships: Memberships,
+}
+
+impl AclIndex {
+    pub fn new(acls: Vec<DbAcl>, memberships: Vec<DbMembership>) -> Self {
+        let mut by_subject: HashMap<String, Vec<DbAcl>> = HashMap::new();
+        for acl in acls {
+            by_subject.entry(acl.subject.clone()).or_default().push(acl);
+        }
+        AclIndex {
+            subjects: by_subject
+                .into_iter()
+                .map(|(sub, acls)| (sub, SubjectAcls::new(acls)))
+                .collect(),
+            memberships: Memberships::new(memberships),
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(Vec::new(), Vec::new())
+    }
+
+    // returns the subject followed by every group and role it is a member of
+    pub fn principals_for(&self, sub: &str) -> Vec<String> {
+        self.memberships.principals_for(sub)
+    }
+
+    // returns all ACLs of a subject
+    pub fn acls_for_subject(&self, sub: &str) -> Vec<&DbAcl> {
+        match self.subjects.get(sub) {
+            Some(s) => s.acls.iter().map(|c| &c.acl).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // returns the subjects with at least one ACL
+    pub fn subjects(&self) -> HashSet<&str> {
+        self.subjects.keys().map(|s| s.as_str()).collect()
+    }
+
+    // returns the ACLs of a subject, and of the groups and roles it belongs to, that match a request
+    pub fn matching_acls(&self, sub: &str, usr: &str, pth: &str, act: &AclAction) -> Vec<&DbAcl> {
+        self.matching_acls_in(&ClaimContext::for_subject(sub), usr, pth, act)
+    }
+
+    // like `matching_acls`, for the subject of a context whose variables are substituted into ACLs
+    pub fn matching_acls_in(
+        &self,
+        context: &ClaimContext,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> Vec<&DbAcl> {
+        let principals = self.principals_for(context.subject());
+        self.matching_acls_for_principals(&principals, context, usr, pth, act)
+    }
+
+    // returns the ACLs of a subject that match a request, given the principals returned by
+    // `principals_for` for the subject
+    fn matching_acls_for_principals(
+        &self,
+        principals: &[String],
+        context: &ClaimContext,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> Vec<&DbAcl> {
+        let now = Utc::now();
+        principals
+            .iter()
+            .filter_map(|p| self.subjects.get(p))
+            .flat_map(|s| {
+                s.for_path(pth, context)
+                    .into_iter()
+                    .filter(|c| c.user_matches(usr, context, &s.regexes))
+            })
+            .filter(|c| c.validity.status_at(&now) == AclStatus::Active)
+            .filter(|c| c.acl.matches_action(act))
+            .filter(|c| conditions_hold(c.conditions.as_ref(), c.acl.decision, context))
+            .map(|c| &c.acl)
+            .collect()
+    }
+
+    // evaluates the ACLs that apply to a subject against a request; returns None if no ACL matched
+    pub fn evaluate(
+        &self,
+        mode: &PolicyMode,
+        sub: &str,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> Option<AclDecision> {
+        self.evaluate_in(mode, &ClaimContext::for_subject(sub), usr, pth, act)
+    }
+
+    pub fn evaluate_in(
+        &self,
+        mode: &PolicyMode,
+        context: &ClaimContext,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> Option<AclDecision> {
+        combine_decisions(mode, self.matching_acls_in(context, usr, pth, act))
+    }
+
+    // the index-backed equivalent of `db::is_authz_db`
+    pub fn is_authz(
+        &self,
+        policy: &AuthzPolicy,
+        sub: &str,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> bool {
+        self.is_authz_in(policy, &policy.context_for(sub), usr, pth, act)
+    }
+
+    // like `is_authz`, for the subject of a context whose variables are substituted into ACLs
+    pub fn is_authz_in(
+        &self,
+        policy: &AuthzPolicy,
+        context: &ClaimContext,
+        usr: &str,
+        pth: &str,
+        act: &AclAction,
+    ) -> bool {
+        // if no ACL matched then the default decision for the path applies
+        policy.is_allowed(self.evaluate_in(&policy.mode, context, usr, pth, act), pth)
+    }
+
+    // the index-backed equivalent of `is_authz` for many requests at once; the groups and roles of
+    // each subject are only expanded once. Checks of the subject of `caller` use its context;
+    // other checks use the context of their subject. Returns the result of each check, in order
+    pub fn is_authz_batch(
+        &self,
+        policy: &AuthzPolicy,
+        checks: &[AuthzCheckJson],
+        caller: &ClaimContext,
+    ) -> Vec<bool> {
+        let mut subjects: HashMap<&str, (Vec<String>, ClaimContext)> = HashMap::new();
+        checks
+            .iter()
+            .map(|c| {
+                let (principals, context) = subjects.entry(&c.subject).or_insert_with(|| {
+                    let context = if c.subject == caller.subject() {
+                        caller.clone()
+                    } else {
+                        policy.context_for(&c.subject)
+                    };
+                    (self.principals_for(&c.subject), context)
+                });
+                let matched = self
+                    .matching_acls_for_principals(principals, context, &c.user, &c.path, &c.action);
+                policy.is_allowed(combine_decisions(&policy.mode, matched), &c.path)
+            })
+            .collect()
+    }
+
+    // returns the actions a subject is allowed on a path, from lowest to highest
+    pub fn effective_actions(
+        &self,
+        policy: &AuthzPolicy,
+        sub: &str,
+        usr: &str,
+        pth: &str,
+    ) -> Vec<AclAction> {
+        self.effective_actions_in(policy, &policy.context_for(sub), usr, pth)
+    }
+
+    pub fn effective_actions_in(
+        &self,
+        policy: &AuthzPolicy,
+        context: &ClaimContext,
+        usr: &str,
+        pth: &str,
+    ) -> Vec<AclAction> {
+        AclAction::ALL
+            .into_iter()
+            .filter(|act| self.is_authz_in(policy, context, usr, pth, act))
+            .collect()
+    }
+
+    // returns the subjects with an ACL of their own or a membership in a group or role, sorted;
+    // groups and roles are not subjects
+    pub fn known_subjects(&self) -> Vec<String> {
+        let mut subs: Vec<String> = self
+            .subjects
+            .keys()
+            .map(|s| s.as_str())
+            .chain(self.memberships.members())
+            .filter(|s| parse_principal(s).is_none())
+            .map(String::from)
+            .collect();
+        subs.sort_unstable();
+        subs.dedup();
+        subs
+    }
+}
+
+// acquire the index for reading; the index is replaced as a whole, so it is safe to keep using
+// it even if a writer panicked
+pub fn read_index(index: &RwLock<AclIndex>) -> RwLockReadGuard<'_, AclIndex> {
+    index.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Change ACLs, groups or roles and refresh the index.
+///
+/// `change` runs in a database transaction, and the index is rebuilt from the ACLs and memberships
+/// read in the same transaction, so the index always reflects a committed state of the database. The write
+/// lock is held for the whole operation so that concurrent changes are applied to the index in
+/// the same order they are committed.
+///
+pub fn change_acls<T, F>(
+    conn: &mut SqliteConnection,
+    index: &RwLock<AclIndex>,
+    change: F,
+) -> Result<T, diesel::result::Error>
+where
+    F: FnOnce(&mut SqliteConnection) -> Result<T, diesel::result::Error>,
+{
+    let mut guard = index.write().unwrap_or_else(PoisonError::into_inner);
+    let (result, new_index) = transaction_mut(conn, |conn| {
+        let result = change(conn)?;
+        let acls = retrieve_all_acls(conn)?;
+        let memberships = retrieve_all_memberships(conn)?;
+        Ok((result, AclIndex::new(acls, memberships)))
+    })?;
+    *guard = new_index;
+    debug!("ACL index refreshed");
+    Ok(result)
+}
+
+// rebuild the index from the database
+pub fn refresh_index(
+    conn: &mut SqliteConnection,
+    index: &RwLock<AclIndex>,
+) -> Result<(), diesel::result::Error> {
+    change_acls(conn, index, |_| Ok(()))
+}
+
+// delete the ACLs whose validity period has ended and refresh the index; returns the number of
+// ACLs deleted
+pub fn sweep_expired_acls(
+    conn: &mut SqliteConnection,
+    index: &RwLock<AclIndex>,
+) -> Result<usize, diesel::result::Error> {
+    let now = Utc::now();
+    change_acls(conn, index, |conn| delete_expired_acls(conn, &now))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::test::{new_acl, test_connection, test_policy};
+    use crate::db::{is_authz_db, save_acl, save_membership};
+    use crate::engine::{subjects_with_access, AclEngine};
+    use crate::models::{NewAclJson, PatternType};
+
+    fn index_for(conn: &mut SqliteConnection) -> AclIndex {
+        AclIndex::new(
+            retrieve_all_acls(conn).unwrap(),
+            retrieve_all_memberships(conn).unwrap(),
+        )
+    }
+
+    #[test]
+    fn index_agrees_with_db() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let sub = "jobs@admin";
+        let acls = [
+            (sub, AclAction::Write, "/data/.*", AclDecision::Allow),
+            (sub, AclAction::Read, "/data/secret.*", AclDecision::Deny),
+            (sub, AclAction::Execute, "/bin/run", AclDecision::Allow),
+            (sub, AclAction::Read, "/bad/*(", AclDecision::Allow),
+            ("group:lab", AclAction::Write, "/bin/.*", AclDecision::Deny),
+            ("group:lab", AclAction::Read, "/other", AclDecision::Allow),
+        ];
+        for (s, act, pth, dec) in acls {
+            save_acl(&mut conn, &new_acl(s, act, pth, dec, 0), "tenants@admin")?;
+        }
+        save_membership(&mut conn, "group:lab", sub, "tenants@admin")?;
+        let index = index_for(&mut conn);
+        let paths = [
+            "/data/a.txt",
+            "/data/secret.txt",
+            "/bin/run",
+            "/bin/run2",
+            "/bad/*(",
+            "/other",
+        ];
+        for mode in [
+            PolicyMode::DenyOverrides,
+            PolicyMode::AllowOverrides,
+            PolicyMode::FirstMatch,
+        ] {
+            let policy = test_policy(mode);
+            for pth in paths {
+                for act in AclAction::ALL {
+                    assert_eq!(
+                        index.is_authz(&policy, sub, "self", pth, &act),
+                        is_authz_db(&mut conn, &policy, sub, "self", pth, &act),
+                        "{} {} {}",
+                        mode,
+                        pth,
+                        act
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn templated_acls_match_the_subject_of_the_request() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let home = NewAclJson {
+            pattern_type: PatternType::Glob,
+            ..new_acl(
+                "group:lab",
+                AclAction::Write,
+                "/home/${sub}/**",
+                AclDecision::Allow,
+                0,
+            )
+        };
+        let shared = NewAclJson {
+            user: String::from("${username}"),
+            ..new_acl(
+                "group:lab",
+                AclAction::Read,
+                "/shared/${tenant}/.*",
+                AclDecision::Allow,
+                0,
+            )
+        };
+        save_acl(&mut conn, &home, "tenants@admin")?;
+        save_acl(&mut conn, &shared, "tenants@admin")?;
+        save_membership(&mut conn, "group:lab", "jobs@admin", "tenants@admin")?;
+        save_membership(&mut conn, "group:lab", "apps@admin", "tenants@admin")?;
+        let index = index_for(&mut conn);
+        let checks = [
+            (
+                "jobs@admin",
+                "self",
+                "/home/jobs@admin/a.txt",
+                AclAction::Write,
+                true,
+            ),
+            (
+                "apps@admin",
+                "self",
+                "/home/apps@admin/a.txt",
+                AclAction::Write,
+                true,
+            ),
+            (
+                "jobs@admin",
+                "self",
+                "/home/apps@admin/a.txt",
+                AclAction::Write,
+                false,
+            ),
+            // the value of a variable is not a pattern
+            (
+                "a.*@admin",
+                "self",
+                "/home/apps@admin/a.txt",
+                AclAction::Write,
+                false,
+            ),
+            (
+                "jobs@admin",
+                "jobs",
+                "/shared/admin/x",
+                AclAction::Read,
+                true,
+            ),
+            (
+                "jobs@admin",
+                "apps",
+                "/shared/admin/x",
+                AclAction::Read,
+                false,
+            ),
+            (
+                "jobs@admin",
+                "jobs",
+                "/shared/dev/x",
+                AclAction::Read,
+                false,
+            ),
+        ];
+        for (sub, usr, pth, act, expected) in checks {
+            assert_eq!(
+                index.is_authz(&policy, sub, usr, pth, &act),
+                expected,
+                "{} {}",
+                sub,
+                pth
+            );
+            assert_eq!(
+                is_authz_db(&mut conn, &policy, sub, usr, pth, &act),
+                expected,
+                "{} {}",
+                sub,
+                pth
+            );
+        }
+        // the claims of the token can override the values derived from the subject
+        let mut claims = serde_json::Map::new();
+        claims.insert(
+            String::from("tapis/tenant_id"),
+            serde_json::Value::from("dev"),
+        );
+        let variables = HashMap::from([(String::from("tenant"), String::from("tapis/tenant_id"))]);
+        let context = ClaimContext::from_claims("jobs@admin", &claims, &variables);
+        assert!(index.is_authz_in(&policy, &context, "jobs", "/shared/dev/x", &AclAction::Read));
+        assert!(!index.is_authz_in(
+            &policy,
+            &context,
+            "jobs",
+            "/shared/admin/x",
+            &AclAction::Read
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn conditions_restrict_when_acls_apply() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let sub = "jobs@admin";
+        let campus = NewAclJson {
+            source_cidrs: vec![String::from("10.1.0.0/16"), String::from("2001:db8::/32")],
+            ..new_acl(sub, AclAction::Write, "/data/.*", AclDecision::Allow, 0)
+        };
+        let maintenance = NewAclJson {
+            time_windows: vec![String::from("sat 22:00-02:00")],
+            ..new_acl(sub, AclAction::Write, "/data/.*", AclDecision::Deny, 0)
+        };
+        save_acl(&mut conn, &campus, "tenants@admin")?;
+        save_acl(&mut conn, &maintenance, "tenants@admin")?;
+        let index = index_for(&mut conn);
+        assert_eq!(
+            index.acls_for_subject(sub)[1].time_windows.as_deref(),
+            Some("Sat 22:00-02:00")
+        );
+        let context = |source: Option<&str>, time: &str| {
+            ClaimContext::for_subject(sub)
+                .with_source(source.map(|s| s.parse().unwrap()))
+                .at(chrono::DateTime::parse_from_rfc3339(time).unwrap())
+        };
+        // 2022-03-26 is a Saturday
+        let checks = [
+            (Some("10.1.2.3"), "2022-03-26T12:00:00Z", true),
+            (Some("2001:db8::7"), "2022-03-26T12:00:00Z", true),
+            (Some("10.2.2.3"), "2022-03-26T12:00:00Z", false),
+            (None, "2022-03-26T12:00:00Z", false),
+            (Some("10.1.2.3"), "2022-03-26T23:00:00Z", false),
+            (Some("10.1.2.3"), "2022-03-27T01:00:00Z", false),
+            // the same time in a timezone where it is still Saturday afternoon
+            (Some("10.1.2.3"), "2022-03-26T16:00:00-07:00", true),
+        ];
+        for (source, time, expected) in checks {
+            let context = context(source, time);
+            assert_eq!(
+                index.is_authz_in(&policy, &context, "self", "/data/a", &AclAction::Write),
+                expected,
+                "{:?} {}",
+                source,
+                time
+            );
+        }
+        // the conditions of other ACLs are unaffected
+        assert!(index.is_authz_in(
+            &policy,
+            &context(Some("10.1.2.3"), "2022-03-26T23:00:00Z"),
+            "self",
+            "/data/a",
+            &AclAction::Read
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn only_active_acls_apply_and_expired_acls_are_swept() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let index = RwLock::new(AclIndex::empty());
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let sub = "jobs@admin";
+        let past = String::from("2000-01-01T00:00:00Z");
+        let future = String::from("2999-01-01T00:00:00+02:00");
+        let acls = [
+            ("/active", Some(past.clone()), Some(future.clone()), true),
+            ("/expired", None, Some(past.clone()), true),
+            ("/pending", Some(future.clone()), None, true),
+            ("/disabled", None, None, false),
+        ];
+        change_acls(&mut conn, &index, |conn| {
+            for (pth, valid_from, valid_until, enabled) in acls.clone() {
+                let acl = NewAclJson {
+                    valid_from,
+                    valid_until,
+                    enabled,
+                    ..new_acl(sub, AclAction::Read, pth, AclDecision::Allow, 0)
+                };
+                save_acl(conn, &acl, "tenants@admin")?;
+            }
+            Ok(())
+        })?;
+        for (pth, _, _, _) in &acls {
+            let expected = *pth == "/active";
+            assert_eq!(
+                read_index(&index).is_authz(&policy, sub, "self", pth, &AclAction::Read),
+                expected,
+                "{}",
+                pth
+            );
+            assert_eq!(
+                is_authz_db(&mut conn, &policy, sub, "self", pth, &AclAction::Read),
+                expected,
+                "{}",
+                pth
+            );
+        }
+        assert_eq!(sweep_expired_acls(&mut conn, &index)?, 1);
+        let paths: Vec<String> = retrieve_all_acls(&mut conn)?
+            .into_iter()
+            .map(|a| a.path)
+            .collect();
+        assert_eq!(paths, vec!["/active", "/pending", "/disabled"]);
+        assert_eq!(read_index(&index).acls_for_subject(sub).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn effective_actions_and_subjects_with_access() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let acls = [
+            (
+                "jobs@admin",
+                AclAction::Write,
+                "/data/.*",
+                AclDecision::Allow,
+            ),
+            (
+                "jobs@admin",
+                AclAction::Execute,
+                "/data/x",
+                AclDecision::Deny,
+            ),
+            ("group:lab", AclAction::Read, "/data/.*", AclDecision::Allow),
+            ("group:lab", AclAction::Admin, "/other", AclDecision::Allow),
+            ("files@admin", AclAction::Read, "/data/x", AclDecision::Deny),
+        ];
+        for (s, act, pth, dec) in acls {
+            save_acl(&mut conn, &new_acl(s, act, pth, dec, 0), "tenants@admin")?;
+        }
+        save_membership(&mut conn, "group:lab", "apps@admin", "tenants@admin")?;
+        save_membership(&mut conn, "group:lab", "files@admin", "tenants@admin")?;
+        let index = index_for(&mut conn);
+        let mut policy = test_policy(PolicyMode::DenyOverrides);
+
+        assert_eq!(
+            index.effective_actions(&policy, "jobs@admin", "self", "/data/a"),
+            vec![
+                AclAction::List,
+                AclAction::Read,
+                AclAction::Execute,
+                AclAction::Write
+            ]
+        );
+        // a Deny of Execute also denies the higher actions
+        assert_eq!(
+            index.effective_actions(&policy, "jobs@admin", "self", "/data/x"),
+            vec![AclAction::List, AclAction::Read]
+        );
+        assert!(index
+            .effective_actions(&policy, "jobs@admin", "self", "/nowhere")
+            .is_empty());
+
+        let engine = AclEngine::new(Arc::new(RwLock::new(index)), policy.mode);
+        assert_eq!(
+            subjects_with_access(&engine, &policy, "self", "/data/x"),
+            vec![
+                (
+                    String::from("apps@admin"),
+                    vec![AclAction::List, AclAction::Read]
+                ),
+                // the Deny of Read leaves the List allowed by the group
+                (String::from("files@admin"), vec![AclAction::List]),
+                (
+                    String::from("jobs@admin"),
+                    vec![AclAction::List, AclAction::Read]
+                ),
+            ]
+        );
+        // the default decision does not give anyone access
+        policy.default_decision = AclDecision::Allow;
+        let index = index_for(&mut conn);
+        assert_eq!(
+            index.effective_actions(&policy, "jobs@admin", "self", "/nowhere"),
+            AclAction::ALL.to_vec()
+        );
+        assert!(subjects_with_access(&engine, &policy, "self", "/nowhere").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_checks_agree_with_single_checks() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let acls = [
+            (
+                "jobs@admin",
+                AclAction::Write,
+                "/data/.*",
+                AclDecision::Allow,
+            ),
+            (
+                "jobs@admin",
+                AclAction::Read,
+                "/data/secret.*",
+                AclDecision::Deny,
+            ),
+            ("group:lab", AclAction::Read, "/lab/.*", AclDecision::Allow),
+        ];
+        for (s, act, pth, dec) in acls {
+            save_acl(&mut conn, &new_acl(s, act, pth, dec, 0), "tenants@admin")?;
+        }
+        save_membership(&mut conn, "group:lab", "jobs@admin", "tenants@admin")?;
+        let index = index_for(&mut conn);
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let mut checks = Vec::new();
+        for sub in ["jobs@admin", "files@admin"] {
+            for pth in ["/data/a", "/data/secret", "/lab/a", "/other"] {
+                for action in AclAction::ALL {
+                    checks.push(AuthzCheckJson {
+                        subject: sub.to_string(),
+                        user: String::from("self"),
+                        action,
+                        path: pth.to_string(),
+                    });
+                }
+            }
+        }
+        let results = index.is_authz_batch(&policy, &checks, &ClaimContext::for_subject(""));
+        assert_eq!(results.len(), checks.len());
+        for (c, result) in checks.iter().zip(results) {
+            assert_eq!(
+                result,
+                index.is_authz(&policy, &c.subject, &c.user, &c.path, &c.action),
+                "{:?}",
+                c
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn failed_changes_do_not_refresh_index() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let index = RwLock::new(AclIndex::empty());
+        let acl = new_acl("jobs@admin", AclAction::Read, "/a", AclDecision::Allow, 0);
+        change_acls(&mut conn, &index, |conn| {
+            save_acl(conn, &acl, "tenants@admin")
+        })?;
+        assert_eq!(read_index(&index).acls_for_subject("jobs@admin").len(), 1);
+        let result: Result<(), _> = change_acls(&mut conn, &index, |conn| {
+            save_acl(conn, &acl, "tenants@admin")?;
+            Err(diesel::result::Error::RollbackTransaction)
+        });
+        assert!(result.is_err());
+        assert_eq!(read_index(&index).acls_for_subject("jobs@admin").len(), 1);
+        assert_eq!(retrieve_all_acls(&mut conn)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+// the derive macros shipped with diesel 1.x generate impl blocks inside of functions
+#![allow(non_local_definitions)]
+
+use actix_web::web;
+use actix_web::web::ServiceConfig;
+
+#[macro_use]
+extern crate diesel;
+#[cfg(test)]
+#[macro_use]
+extern crate diesel_migrations;
+
+pub mod analysis;
+pub mod audit;
+pub mod auth;
+pub mod claims;
+pub mod conditions;
+pub mod config;
+pub mod db;
+pub mod delegation;
+pub mod engine;
+pub mod explain;
+pub mod export;
+pub mod handlers;
+pub mod index;
+pub mod models;
+pub mod patterns;
+pub mod policy;
+pub mod posix;
+pub mod principals;
+pub mod representations;
+pub mod runas;
+pub mod schema;
+pub mod simulate;
+
+pub fn make_config(
+    app_data: web::Data<representations::AppState>,
+) -> impl FnOnce(&mut ServiceConfig) {
+    |cfg: &mut ServiceConfig| {
+        cfg.app_data(app_data).service(
+            //
+            web::scope("")
+                // status routes ----
+                .service(handlers::ready)
+                // acls routes ----
+                .service(handlers::create_acl)
+                .service(handlers::get_all_acls)
+                .service(handlers::analyze_acls_for_subject)
+                .service(handlers::export_acls)
+                .service(handlers::import_acls_document)
+                .service(handlers::simulate_acl_changes)
+                .service(handlers::get_all_acl_history)
+                .service(handlers::get_acl_by_id)
+                .service(handlers::delete_acl_by_id)
+                .service(handlers::update_acl_by_id)
+                .service(handlers::patch_acl_by_id)
+                .service(handlers::get_acl_history)
+                .service(handlers::restore_acl)
+                .service(handlers::get_acls_for_subject)
+                .service(handlers::get_acls_for_subject_user)
+                .service(handlers::is_authz_subject_user_action_path)
+                .service(handlers::is_authz_batch)
+                .service(handlers::get_effective_permissions)
+                .service(handlers::get_subjects_with_access)
+                // access requests routes ----
+                .service(handlers::create_access_request)
+                .service(handlers::get_access_requests)
+                .service(handlers::get_access_request)
+                .service(handlers::approve_access_request)
+                .service(handlers::reject_access_request)
+                // groups and roles routes ----
+                .service(handlers::create_principal)
+                .service(handlers::get_principals)
+                .service(handlers::get_principal)
+                .service(handlers::delete_principal_by_name)
+                .service(handlers::add_principal_member)
+                .service(handlers::remove_principal_member)
+                // files routes ----
+                .service(handlers::list_files_path)
+                .service(handlers::get_file_contents_path)
+                .service(handlers::post_file_contents_path),
+        );
+    }
+}
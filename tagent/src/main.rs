@@ -1,65 +1,157 @@
 use actix_web::middleware::Logger;
-use actix_web::web::ServiceConfig;
-use actix_web::{web, App, HttpServer};
-
-#[macro_use]
-extern crate diesel;
+use actix_web::{rt, web, App, HttpServer};
 
 use dotenv::dotenv;
-use log::info;
+use log::{error, info, warn};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-mod auth;
-mod config;
-mod db;
-mod handlers;
-mod models;
-mod representations;
-mod schema;
-
-fn make_config(app_data: web::Data<representations::AppState>) -> impl FnOnce(&mut ServiceConfig) {
-    |cfg: &mut ServiceConfig| {
-        cfg.app_data(app_data).service(
-            //
-            web::scope("")
-                // status routes ----
-                .service(handlers::ready)
-                // acls routes ----
-                .service(handlers::create_acl)
-                .service(handlers::get_all_acls)
-                .service(handlers::get_acl_by_id)
-                .service(handlers::delete_acl_by_id)
-                .service(handlers::update_acl_by_id)
-                .service(handlers::get_acls_for_subject)
-                .service(handlers::get_acls_for_subject_user)
-                .service(handlers::is_authz_subject_user_action_path)
-                // files routes ----
-                .service(handlers::list_files_path)
-                .service(handlers::get_file_contents_path)
-                .service(handlers::post_file_contents_path),
-        );
-    }
-}
+use tagent::{audit, config, db, engine, index, make_config, policy, representations, runas};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init();
 
-    let settings = crate::config::TagentConfig::from_sources()?;
+    let settings = config::TagentConfig::from_sources()?;
     let app_version = String::from(env!("CARGO_PKG_VERSION"));
     let root_dir = settings.root_directory.clone();
     info!("tagent version {}", app_version);
     info!("tagent running with root directory: {:?}", &root_dir);
     info!("tagent serving at {}:{}", settings.address, settings.port);
+    info!(
+        "tagent evaluating ACLs with policy mode {} and default decision {}",
+        settings.policy_mode, settings.default_decision
+    );
+    // with --dry-run, print the changes the policy file would make to the ACLs and exit
+    if std::env::args().any(|a| a == "--dry-run") {
+        let path = settings
+            .policy_file
+            .as_ref()
+            .ok_or_else(|| std::io::Error::other("--dry-run requires a policy_file setting"))?;
+        let policy = policy::PolicyFile::load(path)?;
+        let changes = policy::plan_reconciliation(&mut db::establish_connection(), &policy)
+            .map_err(|e| std::io::Error::other(format!("could not read ACLs; details: {}", e)))?;
+        for change in &changes {
+            println!("{}", change);
+        }
+        println!("{} change(s) to ACLs managed by {:?}", changes.len(), path);
+        return Ok(());
+    }
     let pub_key = settings.get_public_key().await?;
+    let acl_index = Arc::new(RwLock::new(index::AclIndex::empty()));
+    let mut conn = db::establish_connection();
+    index::refresh_index(&mut conn, &acl_index).map_err(|e| {
+        std::io::Error::other(format!("could not load ACLs from the db; details: {}", e))
+    })?;
+    if let Some(path) = &settings.policy_file {
+        let changes = policy::load_and_reconcile(path, &mut conn, &acl_index)?;
+        info!(
+            "tagent applied {} change(s) from policy file {:?}",
+            changes.len(),
+            path
+        );
+    }
+    let engine = engine::build_engine(
+        &settings.engines,
+        settings.engine_precedence,
+        settings.policy_mode,
+        &acl_index,
+        &root_dir,
+    )?;
+    info!("tagent deciding requests with {}", engine.name());
+    // an agent running as root never runs file operations as itself
+    let run_as = match &settings.run_as {
+        Some(run_as) => {
+            if !runas::can_switch_users() {
+                warn!("tagent is not running as root; file operations run as the agent's user");
+            }
+            Some(run_as.clone())
+        }
+        None if runas::can_switch_users() => {
+            let run_as = runas::RunAsSettings::default();
+            warn!(
+                "tagent is running as root without run_as; file operations run as {}",
+                run_as.fallback_user
+            );
+            Some(run_as)
+        }
+        None => None,
+    };
     let app_state = representations::AppState {
         app_version,
         root_dir,
         pub_key,
+        admin_subjects: settings.admin_subjects.clone(),
+        policy: settings.authz_policy(),
+        acl_index,
+        engine,
+        claim_variables: settings.claim_variables.clone(),
+        audit_log: audit::AuditLog::new(settings.audit_log_size),
+        access_grant_duration: chrono::Duration::from_std(Duration::from_secs(
+            settings.access_grant_duration,
+        ))
+        .unwrap_or_else(|_| chrono::Duration::max_value()),
+        delegation_claim: settings.delegation_claim.clone(),
+        run_as,
     };
 
     let actix_app_state = web::Data::new(app_state);
 
+    // periodically delete expired ACLs
+    if settings.acl_sweep_interval > 0 {
+        info!(
+            "tagent deleting expired ACLs every {} seconds",
+            settings.acl_sweep_interval
+        );
+        let sweeper_state = actix_app_state.clone();
+        let mut interval = rt::time::interval(Duration::from_secs(settings.acl_sweep_interval));
+        rt::spawn(async move {
+            loop {
+                interval.tick().await;
+                let state = sweeper_state.clone();
+                let result = web::block(move || {
+                    index::sweep_expired_acls(&mut db::establish_connection(), &state.acl_index)
+                })
+                .await;
+                match result {
+                    Ok(Ok(0)) => (),
+                    Ok(Ok(n)) => info!("deleted {} expired ACLs", n),
+                    Ok(Err(e)) => error!("could not delete expired ACLs; details: {}", e),
+                    Err(e) => error!("could not run the expired ACL sweeper; details: {}", e),
+                }
+            }
+        });
+    }
+
+    // reload the policy file on SIGHUP; if the file is invalid, the ACLs are left unchanged
+    if let Some(path) = settings.policy_file.clone() {
+        let mut hangup = rt::signal::unix::signal(rt::signal::unix::SignalKind::hangup())?;
+        let reload_state = actix_app_state.clone();
+        rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("received SIGHUP; reloading policy file {:?}", path);
+                let state = reload_state.clone();
+                let path = path.clone();
+                let result = web::block(move || {
+                    policy::load_and_reconcile(
+                        &path,
+                        &mut db::establish_connection(),
+                        &state.acl_index,
+                    )
+                })
+                .await;
+                match result {
+                    Ok(Ok(changes)) => {
+                        info!("applied {} change(s) from policy file", changes.len())
+                    }
+                    Ok(Err(e)) => error!("could not reload policy file; {}", e),
+                    Err(e) => error!("could not reload policy file; details: {}", e),
+                }
+            }
+        });
+    }
+
     HttpServer::new(move || {
         dotenv().ok();
         App::new()
@@ -1,26 +1,143 @@
+use crate::claims::{template_variables, with_placeholders, ClaimContext, BUILTIN_VARIABLES};
+use crate::conditions::{parse_cidr, split_items, AclConditions, TimeWindow, Timezone};
+use crate::db::{normalize_acl_path, normalize_path};
+use crate::patterns::Matcher;
 use crate::schema::*;
+use chrono::{DateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
 use diesel::Queryable;
+use log::{debug, error};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The actions an ACL can allow or deny.
+///
+/// Actions are ordered from lowest to highest, `List < Read < Execute < Write < Delete < Admin`,
+/// and a higher action implies every lower one: allowing an action also allows the lower
+/// actions, and denying an action also denies the higher ones. For example, an ACL allowing
+/// `List` lets a subject list a directory but not download its files, and an ACL allowing `Write`
+/// lets it upload files but not delete them.
+///
+/// The order is the order in which the variants are declared. Actions are stored in the database
+/// by name.
+///
+/// `Impersonate` is not part of the order: it only implies itself. An ACL allowing it lets the
+/// subject make requests on behalf of the ACL's users, for the ACL's paths.
+///
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    AsExpression,
+    FromSqlRow,
+)]
+#[sql_type = "Text"]
 pub enum AclAction {
+    List,
     Read,
     Execute,
     Write,
+    Delete,
+    Admin,
+    Impersonate,
+}
+
+impl AclAction {
+    // all actions on files, from lowest to highest; i.e., every action but Impersonate
+    pub const ALL: [AclAction; 6] = [
+        Self::List,
+        Self::Read,
+        Self::Execute,
+        Self::Write,
+        Self::Delete,
+        Self::Admin,
+    ];
+
+    // whether the action implies another action; i.e., the other action is not higher
+    pub fn implies(&self, other: &AclAction) -> bool {
+        match (self, other) {
+            (Self::Impersonate, _) | (_, Self::Impersonate) => self == other,
+            _ => self >= other,
+        }
+    }
 }
 
 impl fmt::Display for AclAction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
+            Self::List => write!(f, "List"),
             Self::Read => write!(f, "Read"),
             Self::Execute => write!(f, "Execute"),
             Self::Write => write!(f, "Write"),
+            Self::Delete => write!(f, "Delete"),
+            Self::Admin => write!(f, "Admin"),
+            Self::Impersonate => write!(f, "Impersonate"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl FromStr for AclAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "List" => Ok(Self::List),
+            "Read" => Ok(Self::Read),
+            "Execute" => Ok(Self::Execute),
+            "Write" => Ok(Self::Write),
+            "Delete" => Ok(Self::Delete),
+            "Admin" => Ok(Self::Admin),
+            "Impersonate" => Ok(Self::Impersonate),
+            _ => Err(format!("invalid action: {}", s)),
+        }
+    }
+}
+
+// stores an enum in a Text column by its name, using its Display and FromStr implementations;
+// reading a name the enum does not have is an error
+macro_rules! text_column_enum {
+    ($t:ty) => {
+        impl ToSql<Text, Sqlite> for $t {
+            fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+                <str as ToSql<Text, Sqlite>>::to_sql(&self.to_string(), out)
+            }
+        }
+
+        impl FromSql<Text, Sqlite> for $t {
+            fn from_sql(
+                value: Option<&<Sqlite as Backend>::RawValue>,
+            ) -> deserialize::Result<Self> {
+                let s = <String as FromSql<Text, Sqlite>>::from_sql(value)?;
+                Ok(<$t>::from_str(&s)?)
+            }
+        }
+    };
+}
+
+text_column_enum!(AclAction);
+text_column_enum!(AclDecision);
+text_column_enum!(AclOperation);
+text_column_enum!(AccessRequestState);
+text_column_enum!(PatternType);
+
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, AsExpression, FromSqlRow,
+)]
+#[sql_type = "Text"]
 pub enum AclDecision {
     Allow,
     Deny,
@@ -35,36 +152,344 @@ impl fmt::Display for AclDecision {
     }
 }
 
+impl FromStr for AclDecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Allow" => Ok(Self::Allow),
+            "Deny" => Ok(Self::Deny),
+            _ => Err(format!("invalid decision: {}", s)),
+        }
+    }
+}
+
+// how the path of an ACL is matched against the path of a request
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default, AsExpression, FromSqlRow,
+)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum PatternType {
+    // the path must be exactly equal
+    Literal,
+    // the path is a glob supporting `*`, `**` and `?`, matched against the whole path
+    Glob,
+    // the path is a regex, matched against the whole path
+    #[default]
+    Regex,
+}
+
+impl fmt::Display for PatternType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Literal => write!(f, "literal"),
+            Self::Glob => write!(f, "glob"),
+            Self::Regex => write!(f, "regex"),
+        }
+    }
+}
+
+impl FromStr for PatternType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "literal" => Ok(Self::Literal),
+            "glob" => Ok(Self::Glob),
+            "regex" => Ok(Self::Regex),
+            _ => Err(format!("invalid pattern type: {}", s)),
+        }
+    }
+}
+
+// how the decisions of multiple matching ACLs are combined
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum PolicyMode {
+    // any matching Deny ACL denies the request
+    DenyOverrides,
+    // any matching Allow ACL allows the request
+    AllowOverrides,
+    // ACLs are checked in order of ascending priority (then id); the first matching ACL decides
+    FirstMatch,
+}
+
+impl fmt::Display for PolicyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::DenyOverrides => write!(f, "DenyOverrides"),
+            Self::AllowOverrides => write!(f, "AllowOverrides"),
+            Self::FirstMatch => write!(f, "FirstMatch"),
+        }
+    }
+}
+
+// overrides the default decision for all paths starting with a prefix
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PathDefaultDecision {
+    pub prefix: String,
+    pub decision: AclDecision,
+}
+
+// the fields ACLs can be sorted by when they are listed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AclSortField {
+    #[default]
+    Id,
+    Subject,
+    User,
+    Action,
+    Path,
+    Priority,
+    CreateTime,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// the criteria for retrieving ACLs from the database; ACLs must match every filter that is set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AclFilter {
+    pub subject: Option<String>,
+    pub user: Option<String>,
+    pub action: Option<AclAction>,
+    pub decision: Option<AclDecision>,
+    // a substring of the path
+    pub path: Option<String>,
+    pub create_by: Option<String>,
+    // the status of the ACL at the time of the query
+    pub status: Option<AclStatus>,
+    // only ACLs visible to a subject, given the subject followed by its groups and roles, i.e.,
+    // the ACLs the subject created or that apply to it
+    pub visible_to: Option<Vec<String>>,
+    pub sort: AclSortField,
+    pub order: SortOrder,
+}
+
+// a single check in a batch authorization request
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuthzCheckJson {
+    pub subject: String,
+    pub user: String,
+    pub action: AclAction,
+    pub path: String,
+}
+
+// the body of a batch authorization request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthzBatchJson {
+    pub checks: Vec<AuthzCheckJson>,
+}
+
+// the settings that determine how ACLs are evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthzPolicy {
+    pub mode: PolicyMode,
+    // decision returned when no ACL matches a request
+    pub default_decision: AclDecision,
+    pub path_default_decisions: Vec<PathDefaultDecision>,
+    // the timezone in which the time windows of ACLs are evaluated
+    pub timezone: Timezone,
+}
+
+impl AuthzPolicy {
+    // the context of a request of a subject made now, whose token is not available
+    pub fn context_for(&self, sub: &str) -> ClaimContext {
+        ClaimContext::for_subject(sub).at(self.timezone.local(&Utc::now()))
+    }
+
+    // whether a request for a path is allowed, given the decision reached for it; if there is no
+    // decision then the default decision for the path applies
+    pub fn is_allowed(&self, decision: Option<AclDecision>, path: &str) -> bool {
+        let decision = decision.unwrap_or_else(|| {
+            let d = self.default_decision_for_path(path);
+            debug!(
+                "no decision was reached; returning default decision ({})",
+                d
+            );
+            d
+        });
+        decision == AclDecision::Allow
+    }
+
+    // returns the default decision that applies to a path; the longest matching prefix wins,
+    // where a prefix only matches whole path segments
+    pub fn default_decision_for_path(&self, path: &str) -> AclDecision {
+        let mut result = self.default_decision;
+        let mut longest = 0;
+        for d in &self.path_default_decisions {
+            let prefix = d.prefix.trim_end_matches('/');
+            let matches = path == prefix
+                || prefix.is_empty()
+                || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'));
+            if matches && (prefix.len() >= longest) {
+                longest = prefix.len();
+                result = d.decision;
+            }
+        }
+        result
+    }
+}
+
+// parses a timestamp in RFC 3339 format, e.g., `2022-03-21T09:00:00Z`
+pub fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("invalid timestamp {}; details: {}", timestamp, e))
+}
+
+// whether an ACL applies at a point in time
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclStatus {
+    // the ACL is enabled and within its validity period
+    Active,
+    // the ACL is enabled but its validity period has not started
+    Pending,
+    // the validity period of the ACL has ended; expired ACLs are eventually deleted
+    Expired,
+    // the ACL was disabled
+    Disabled,
+}
+
+impl fmt::Display for AclStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Active => write!(f, "active"),
+            Self::Pending => write!(f, "pending"),
+            Self::Expired => write!(f, "expired"),
+            Self::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+// the period during which an ACL applies; a missing bound is unbounded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AclValidity {
+    pub enabled: bool,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl AclValidity {
+    pub fn status_at(&self, now: &DateTime<Utc>) -> AclStatus {
+        if self.is_expired_at(now) {
+            AclStatus::Expired
+        } else if !self.enabled {
+            AclStatus::Disabled
+        } else if self.valid_from.is_some_and(|from| now < &from) {
+            AclStatus::Pending
+        } else {
+            AclStatus::Active
+        }
+    }
+
+    pub fn is_expired_at(&self, now: &DateTime<Utc>) -> bool {
+        self.valid_until.is_some_and(|until| &until <= now)
+    }
+
+    // whether the ACL applies at every time `other` applies
+    pub fn covers(&self, other: &AclValidity) -> bool {
+        let from_covers = match (self.valid_from, other.valid_from) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => a <= b,
+        };
+        let until_covers = match (self.valid_until, other.valid_until) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => a >= b,
+        };
+        self.enabled && from_covers && until_covers
+    }
+}
+
 // struct representing a database record retrieved from sqlite; the id attribute is included
-#[derive(Debug, Serialize, Deserialize, Queryable, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq)]
 pub struct DbAcl {
     pub id: i32,
     pub subject: String,
-    pub action: String,
+    pub action: AclAction,
     pub path: String,
     pub user: String,
     pub create_by: String,
     pub create_time: String,
-    pub decision: String,
+    pub decision: AclDecision,
+    pub priority: i32,
+    pub pattern_type: PatternType,
+    pub valid_from: Option<String>,
+    pub valid_until: Option<String>,
+    pub enabled: bool,
+    // the key of the policy file entry that manages the ACL; None for ACLs created through the API
+    pub policy_key: Option<String>,
+    // incremented whenever the ACL is changed; snapshots recorded before revisions existed have none
+    #[serde(default = "first_revision")]
+    pub revision: i32,
+    // comma-separated conditions of the ACL; see `AclConditions`
+    #[serde(default)]
+    pub source_cidrs: Option<String>,
+    #[serde(default)]
+    pub time_windows: Option<String>,
+}
+
+fn first_revision() -> i32 {
+    1
 }
 
 impl DbAcl {
-    // determines whether a DbAcl represents a lower action than a given action
-    pub fn is_leq_action(&self, action: &str) -> bool {
-        if self.action == "Read" {
-            // Read is less than every action
-            return true;
-        } else if self.action == "Execute" {
-            if (action == "Execute") || (action == "Write") {
-                return true;
+    // the period during which the ACL applies; an ACL with a bound that cannot be parsed never applies
+    pub fn validity(&self) -> AclValidity {
+        let parse = |bound: &Option<String>| match bound.as_deref().map(parse_timestamp) {
+            Some(Ok(t)) => Ok(Some(t)),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        };
+        match (parse(&self.valid_from), parse(&self.valid_until)) {
+            (Ok(valid_from), Ok(valid_until)) => AclValidity {
+                enabled: self.enabled,
+                valid_from,
+                valid_until,
+            },
+            (Err(e), _) | (_, Err(e)) => {
+                error!("ACL {} is treated as disabled; {}", self.id, e);
+                AclValidity {
+                    enabled: false,
+                    valid_from: None,
+                    valid_until: None,
+                }
             }
-        } else {
-            // acl action is Write, so only Write is leq..
-            if action == "Write" {
-                return true;
+        }
+    }
+
+    pub fn status_at(&self, now: &DateTime<Utc>) -> AclStatus {
+        self.validity().status_at(now)
+    }
+
+    // the conditions under which the ACL applies; None if they cannot be parsed, in which case
+    // they fail closed
+    pub fn conditions(&self) -> Option<AclConditions> {
+        match AclConditions::parse(self.source_cidrs.as_deref(), self.time_windows.as_deref()) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                error!("the conditions of ACL {} fail closed; {}", self.id, e);
+                None
             }
         }
-        false
+    }
+
+    // determines whether the ACL applies to a request for a given action; an Allow ACL applies to
+    // the actions its action implies, and a Deny ACL to the actions that imply its action
+    pub fn matches_action(&self, action: &AclAction) -> bool {
+        match self.decision {
+            AclDecision::Allow => self.action.implies(action),
+            AclDecision::Deny => action.implies(&self.action),
+        }
     }
 }
 
@@ -73,20 +498,584 @@ impl DbAcl {
 #[table_name = "acls"]
 pub struct NewAcl<'a> {
     pub subject: &'a str,
-    pub action: &'a str,
+    pub action: AclAction,
     pub path: &'a str,
     pub user: &'a str,
     pub create_by: &'a str,
     pub create_time: &'a str,
-    pub decision: &'a str,
+    pub decision: AclDecision,
+    pub priority: i32,
+    pub pattern_type: PatternType,
+    pub valid_from: Option<&'a str>,
+    pub valid_until: Option<&'a str>,
+    pub enabled: bool,
+    pub policy_key: Option<&'a str>,
+    pub source_cidrs: Option<&'a str>,
+    pub time_windows: Option<&'a str>,
+}
+
+// the kinds of changes recorded in the history of an ACL
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum AclOperation {
+    Create,
+    Update,
+    Delete,
+    // the ACL was reverted to a previous version
+    Restore,
+}
+
+impl fmt::Display for AclOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Create => write!(f, "create"),
+            Self::Update => write!(f, "update"),
+            Self::Delete => write!(f, "delete"),
+            Self::Restore => write!(f, "restore"),
+        }
+    }
+}
+
+impl FromStr for AclOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(Self::Create),
+            "update" => Ok(Self::Update),
+            "delete" => Ok(Self::Delete),
+            "restore" => Ok(Self::Restore),
+            _ => Err(format!("invalid operation: {}", s)),
+        }
+    }
+}
+
+// a change to an ACL retrieved from sqlite; the ACL before and after the change are JSON snapshots
+// of a `DbAcl`
+#[derive(Debug, Clone, Queryable, PartialEq)]
+pub struct DbAclHistory {
+    pub id: i32,
+    pub acl_id: i32,
+    pub version: i32,
+    pub operation: AclOperation,
+    pub actor: String,
+    pub time: String,
+    pub before_acl: Option<String>,
+    pub after_acl: Option<String>,
+}
+
+fn parse_snapshot(snapshot: &Option<String>) -> Option<DbAcl> {
+    match serde_json::from_str(snapshot.as_deref()?) {
+        Ok(acl) => Some(acl),
+        Err(e) => {
+            error!("could not parse ACL snapshot; details: {}", e);
+            None
+        }
+    }
+}
+
+impl DbAclHistory {
+    pub fn before(&self) -> Option<DbAcl> {
+        parse_snapshot(&self.before_acl)
+    }
+
+    pub fn after(&self) -> Option<DbAcl> {
+        parse_snapshot(&self.after_acl)
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "acl_history"]
+pub struct NewAclHistory<'a> {
+    pub acl_id: i32,
+    pub version: i32,
+    pub operation: AclOperation,
+    pub actor: &'a str,
+    pub time: &'a str,
+    pub before_acl: Option<&'a str>,
+    pub after_acl: Option<&'a str>,
 }
 
 // struct representing a user-supplied JSON object describing a new ACL to be created
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct NewAclJson {
     pub subject: String,
     pub action: AclAction,
     pub decision: AclDecision,
     pub path: String,
     pub user: String,
+    // only used to order ACLs when the policy mode is FirstMatch; lower values are checked first
+    #[serde(default)]
+    pub priority: i32,
+    // how the path is matched; defaults to regex
+    #[serde(default)]
+    pub pattern_type: PatternType,
+    // the ACL only applies between these RFC 3339 timestamps; either bound can be omitted
+    #[serde(default)]
+    pub valid_from: Option<String>,
+    #[serde(default)]
+    pub valid_until: Option<String>,
+    // disabled ACLs are kept but never apply
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // the ACL only applies to requests from these IP addresses or CIDR ranges, e.g., `10.0.0.0/8`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_cidrs: Vec<String>,
+    // the ACL only applies during these weekly time windows, e.g., `Mon-Fri 08:00-18:00`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<String>,
+}
+
+// applies a JSON merge patch to a value: objects are merged recursively, members set to null are
+// removed, and any other patch replaces the value
+fn apply_merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let patch = match patch.as_object() {
+        Some(p) => p,
+        None => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    if let Some(t) = target.as_object_mut() {
+        for (key, value) in patch {
+            if value.is_null() {
+                t.remove(key);
+            } else {
+                apply_merge_patch(
+                    t.entry(key.clone()).or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl NewAclJson {
+    // the fields of a DB ACL that can be used to recreate it
+    pub fn from_db_acl(db_acl: &DbAcl) -> Self {
+        NewAclJson {
+            subject: db_acl.subject.clone(),
+            action: db_acl.action,
+            decision: db_acl.decision,
+            path: db_acl.path.clone(),
+            user: db_acl.user.clone(),
+            priority: db_acl.priority,
+            pattern_type: db_acl.pattern_type,
+            valid_from: db_acl.valid_from.clone(),
+            valid_until: db_acl.valid_until.clone(),
+            enabled: db_acl.enabled,
+            source_cidrs: split_items(db_acl.source_cidrs.as_deref()),
+            time_windows: split_items(db_acl.time_windows.as_deref()),
+        }
+    }
+
+    // applies a JSON merge patch (RFC 7396) to the ACL
+    pub fn merge_patch(&self, patch: &serde_json::Value) -> Result<Self, String> {
+        let mut acl = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        apply_merge_patch(&mut acl, patch);
+        serde_json::from_value(acl).map_err(|e| e.to_string())
+    }
+
+    /// Check every field of the ACL, returning all of the problems found.
+    ///
+    /// The subject, path and user must not be empty, the path and user must be valid regexes
+    /// when they are matched as regexes, and the validity period and conditions must be
+    /// well-formed. Literal paths and globs must not have `..` segments. The path is checked as it
+    /// is stored, i.e., normalized (see `normalize_acl_path`).
+    ///
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        for (field, value) in [
+            ("subject", &self.subject),
+            ("path", &self.path),
+            ("user", &self.user),
+        ] {
+            if value.trim().is_empty() {
+                errors.push(FieldError::new(field, "must not be empty"));
+            }
+        }
+        for (field, value) in [("path", &self.path), ("user", &self.user)] {
+            if let Err(e) = template_variables(value) {
+                errors.push(FieldError::new(field, e));
+            }
+        }
+        if self.pattern_type != PatternType::Regex {
+            if let Err(e) = normalize_path(&self.path) {
+                errors.push(FieldError::new("path", e));
+            }
+        }
+        // template variables stand for plain values, which are escaped when they are substituted
+        for (field, matcher) in [
+            (
+                "path",
+                Matcher::for_path(
+                    &self.pattern_type,
+                    &with_placeholders(&normalize_acl_path(&self.path, &self.pattern_type)),
+                ),
+            ),
+            ("user", Matcher::for_user(&with_placeholders(&self.user))),
+        ] {
+            if let Some(e) = matcher.error() {
+                errors.push(FieldError::new(
+                    field,
+                    format!("is not a valid regex; details: {}", e),
+                ));
+            }
+        }
+        for cidr in &self.source_cidrs {
+            if let Err(e) = parse_cidr(cidr) {
+                errors.push(FieldError::new("source_cidrs", e));
+            }
+        }
+        for window in &self.time_windows {
+            if let Err(e) = TimeWindow::from_str(window) {
+                errors.push(FieldError::new("time_windows", e));
+            }
+        }
+        let mut parse =
+            |field: &str, bound: &Option<String>| match bound.as_deref().map(parse_timestamp) {
+                Some(Ok(t)) => Some(t),
+                Some(Err(e)) => {
+                    errors.push(FieldError::new(field, e));
+                    None
+                }
+                None => None,
+            };
+        let valid_from = parse("valid_from", &self.valid_from);
+        let valid_until = parse("valid_until", &self.valid_until);
+        if let (Some(from), Some(until)) = (valid_from, valid_until) {
+            if until <= from {
+                errors.push(FieldError::new(
+                    "valid_until",
+                    format!("must be later than valid_from ({})", from),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl NewAclJson {
+    /// Check that the template variables of the ACL are available, i.e., they are built in or
+    /// are mapped to a claim by `claim_variables`.
+    pub fn validate_variables(&self, claim_variables: &HashMap<String, String>) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        for (field, value) in [("path", &self.path), ("user", &self.user)] {
+            for variable in template_variables(value).unwrap_or_default() {
+                if !BUILTIN_VARIABLES.contains(&variable) && !claim_variables.contains_key(variable)
+                {
+                    errors.push(FieldError::new(
+                        field,
+                        format!("uses unknown variable ${{{}}}", variable),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// A problem with a single field of a request.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    // the same error for a field nested in another field, e.g., `acls[0].path`
+    pub fn nested(self, parent: &str) -> Self {
+        FieldError {
+            field: format!("{}.{}", parent, self.field),
+            message: self.message,
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+// the kinds of principals, other than subjects, that ACLs can apply to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrincipalKind {
+    Group,
+    Role,
+}
+
+impl PrincipalKind {
+    // the kind for the collection name used in URLs, i.e., `groups` or `roles`
+    pub fn from_collection(collection: &str) -> Option<Self> {
+        match collection {
+            "groups" => Some(Self::Group),
+            "roles" => Some(Self::Role),
+            _ => None,
+        }
+    }
+
+    // the name of a principal of this kind when used as an ACL subject or member, e.g., `group:lab`
+    pub fn principal(&self, name: &str) -> String {
+        format!("{}:{}", self, name)
+    }
+}
+
+impl fmt::Display for PrincipalKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Group => write!(f, "group"),
+            Self::Role => write!(f, "role"),
+        }
+    }
+}
+
+impl FromStr for PrincipalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "group" => Ok(Self::Group),
+            "role" => Ok(Self::Role),
+            _ => Err(format!("invalid principal kind: {}", s)),
+        }
+    }
+}
+
+// returns the kind and name of a group or role principal, e.g., `group:lab`; returns None for subjects
+pub fn parse_principal(principal: &str) -> Option<(PrincipalKind, &str)> {
+    let (kind, name) = principal.split_once(':')?;
+    Some((PrincipalKind::from_str(kind).ok()?, name))
+}
+
+// a group or role record retrieved from sqlite
+#[derive(Debug, Serialize, Deserialize, Queryable, PartialEq)]
+pub struct DbPrincipal {
+    pub id: i32,
+    pub kind: String,
+    pub name: String,
+    pub create_by: String,
+    pub create_time: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "principals"]
+pub struct NewPrincipal<'a> {
+    pub kind: &'a str,
+    pub name: &'a str,
+    pub create_by: &'a str,
+    pub create_time: &'a str,
+}
+
+// user-supplied JSON object describing a new group or role
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewPrincipalJson {
+    pub name: String,
+}
+
+// a membership record retrieved from sqlite; `member` belongs to `principal` (e.g., `group:lab`)
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, PartialEq)]
+pub struct DbMembership {
+    pub id: i32,
+    pub principal: String,
+    pub member: String,
+    pub create_by: String,
+    pub create_time: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "memberships"]
+pub struct NewMembership<'a> {
+    pub principal: &'a str,
+    pub member: &'a str,
+    pub create_by: &'a str,
+    pub create_time: &'a str,
+}
+
+// user-supplied JSON object describing a member to add to a group or role; the member is a
+// subject, or another group or role, e.g., `group:lab`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewMembershipJson {
+    pub member: String,
+}
+
+// access requests ---
+
+// the states of a request for access; a request is decided once, by approving or rejecting it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum AccessRequestState {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl fmt::Display for AccessRequestState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Pending => write!(f, "pending"),
+            Self::Approved => write!(f, "approved"),
+            Self::Rejected => write!(f, "rejected"),
+        }
+    }
+}
+
+impl FromStr for AccessRequestState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "approved" => Ok(Self::Approved),
+            "rejected" => Ok(Self::Rejected),
+            _ => Err(format!("invalid access request state: {}", s)),
+        }
+    }
+}
+
+// a request for access retrieved from sqlite; the review fields are set once the request is
+// decided, and `acl_id` is the ACL created when it was approved
+#[derive(Debug, Clone, Serialize, Queryable, PartialEq)]
+pub struct DbAccessRequest {
+    pub id: i32,
+    pub subject: String,
+    pub action: AclAction,
+    pub path: String,
+    pub pattern_type: PatternType,
+    pub user: String,
+    pub justification: String,
+    pub valid_until: Option<String>,
+    pub state: AccessRequestState,
+    pub create_time: String,
+    pub reviewed_by: Option<String>,
+    pub review_time: Option<String>,
+    pub review_comment: Option<String>,
+    pub acl_id: Option<i32>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "access_requests"]
+pub struct NewAccessRequest<'a> {
+    pub subject: &'a str,
+    pub action: AclAction,
+    pub path: &'a str,
+    pub pattern_type: PatternType,
+    pub user: &'a str,
+    pub justification: &'a str,
+    pub valid_until: Option<&'a str>,
+    pub state: AccessRequestState,
+    pub create_time: &'a str,
+}
+
+fn literal_pattern() -> PatternType {
+    PatternType::Literal
+}
+
+fn self_user() -> String {
+    String::from("self")
+}
+
+// user-supplied JSON object describing a request for access; the requester is the subject of the
+// request. Unlike ACLs, paths are matched literally unless another pattern type is given.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NewAccessRequestJson {
+    pub action: AclAction,
+    pub path: String,
+    #[serde(default = "literal_pattern")]
+    pub pattern_type: PatternType,
+    #[serde(default = "self_user")]
+    pub user: String,
+    pub justification: String,
+    // when the requested access should end, as an RFC 3339 timestamp; the reviewer can change it
+    #[serde(default)]
+    pub valid_until: Option<String>,
+}
+
+impl NewAccessRequestJson {
+    /// Check every field of the request, returning all of the problems found.
+    ///
+    /// The requested access is checked as the ACL granting it would be, and must end after `now`.
+    ///
+    pub fn validate(
+        &self,
+        subject: &str,
+        claim_variables: &HashMap<String, String>,
+        now: &DateTime<Utc>,
+    ) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.justification.trim().is_empty() {
+            errors.push(FieldError::new("justification", "must not be empty"));
+        }
+        if self.action == AclAction::Impersonate {
+            errors.push(FieldError::new("action", "Impersonate cannot be requested"));
+        }
+        let acl = self.acl_for(subject, now, self.valid_until.clone());
+        errors.extend(acl.validate());
+        errors.extend(acl.validate_variables(claim_variables));
+        errors
+    }
+
+    // the ACL granting the requested access to `subject` from `now` until `valid_until`
+    pub fn acl_for(
+        &self,
+        subject: &str,
+        now: &DateTime<Utc>,
+        valid_until: Option<String>,
+    ) -> NewAclJson {
+        NewAclJson {
+            subject: subject.to_string(),
+            action: self.action,
+            decision: AclDecision::Allow,
+            path: self.path.clone(),
+            user: self.user.clone(),
+            priority: 0,
+            pattern_type: self.pattern_type,
+            valid_from: Some(now.to_rfc3339()),
+            valid_until,
+            enabled: true,
+            source_cidrs: Vec::new(),
+            time_windows: Vec::new(),
+        }
+    }
+}
+
+impl DbAccessRequest {
+    // the request as it was made, to create the ACL granting it
+    pub fn to_request_json(&self) -> NewAccessRequestJson {
+        NewAccessRequestJson {
+            action: self.action,
+            path: self.path.clone(),
+            pattern_type: self.pattern_type,
+            user: self.user.clone(),
+            justification: self.justification.clone(),
+            valid_until: self.valid_until.clone(),
+        }
+    }
+}
+
+// user-supplied JSON object describing the decision of an access request; when approving,
+// `valid_until` overrides the end of the access requested
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AccessReviewJson {
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub valid_until: Option<String>,
 }
@@ -0,0 +1,373 @@
+use log::info;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+use crate::claims::{has_variables, substitute, ClaimContext};
+use crate::db::normalize_acl_path;
+use crate::models::PatternType;
+
+// Matching ACL paths and users
+// ============================
+
+/// Translate a glob into an anchored regex.
+///
+/// - `*` matches any characters within a path segment,
+/// - `**` matches any characters, including `/`; `**/` also matches zero segments, so
+///   `/a/**/b` matches `/a/b`,
+/// - `?` matches a single character other than `/`,
+///
+/// and every other character matches itself.
+///
+pub fn glob_to_regex(glob: &str) -> String {
+    format!("^{}$", glob_body_to_regex(glob))
+}
+
+// translates a glob, or a piece of one, into an unanchored regex
+fn glob_body_to_regex(glob: &str) -> String {
+    let mut re = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                } else {
+                    re.push_str("[^/]*");
+                }
+            }
+            '?' => re.push_str("[^/]"),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re
+}
+
+// the value of a variable substituted into a path; values containing a slash are rejected, so
+// that a variable always stands for part of a single path segment
+fn path_value(value: &str, escape: fn(&str) -> String) -> Option<String> {
+    if value.contains('/') {
+        None
+    } else {
+        Some(escape(value))
+    }
+}
+
+fn raw(s: &str) -> String {
+    s.to_string()
+}
+
+// how many regexes a `RegexCache` keeps; it starts over when it is full
+const REGEX_CACHE_SIZE: usize = 10_000;
+
+/// The regexes compiled for ACL fields with template variables, by the regex they were compiled
+/// from once the variables were substituted.
+///
+/// The values of the variables repeat from request to request, e.g., the same subjects make many
+/// requests, so keeping the regexes avoids compiling them again for every request.
+///
+#[derive(Debug, Default)]
+pub struct RegexCache {
+    regexes: Mutex<HashMap<String, Result<Regex, String>>>,
+}
+
+impl RegexCache {
+    fn compile(&self, field: &str, re: &str) -> Matcher {
+        let mut regexes = self.regexes.lock().unwrap_or_else(PoisonError::into_inner);
+        let compiled = match regexes.get(re) {
+            Some(compiled) => compiled.clone(),
+            None => {
+                if regexes.len() >= REGEX_CACHE_SIZE {
+                    regexes.clear();
+                }
+                let compiled = Regex::new(re).map_err(|e| e.to_string());
+                regexes.insert(re.to_string(), compiled.clone());
+                compiled
+            }
+        };
+        Matcher::from_compiled(field, compiled)
+    }
+
+    // the number of regexes kept
+    pub fn len(&self) -> usize {
+        self.regexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An ACL field (path or user) prepared for matching.
+///
+/// A field always matches a value that is exactly equal to it, whatever its type.
+///
+#[derive(Debug)]
+pub enum Matcher {
+    Literal(String),
+    Pattern(String, Regex),
+    // the field should be a regex but is not valid; it only matches itself
+    Invalid(String, String),
+    // the field has template variables that have no value for the request; it matches nothing
+    Unresolved(String),
+}
+
+impl Matcher {
+    fn compile(field: &str, re: &str) -> Self {
+        Self::from_compiled(field, Regex::new(re).map_err(|e| e.to_string()))
+    }
+
+    fn from_compiled(field: &str, compiled: Result<Regex, String>) -> Self {
+        match compiled {
+            Ok(re) => Self::Pattern(field.to_string(), re),
+            Err(e) => {
+                info!("acl field {} was not a valid regex; details: {}", field, e);
+                Self::Invalid(field.to_string(), e)
+            }
+        }
+    }
+
+    /// Prepare an ACL path for matching, according to the ACL's pattern type.
+    ///
+    /// Regexes are anchored, so that they have to match the whole path.
+    ///
+    pub fn for_path(pattern_type: &PatternType, path: &str) -> Self {
+        match pattern_type {
+            PatternType::Literal => Self::Literal(path.to_string()),
+            PatternType::Glob => Self::compile(path, &glob_to_regex(path)),
+            PatternType::Regex => Self::compile(path, &format!("^(?:{})$", path)),
+        }
+    }
+
+    /// Prepare an ACL path with template variables for matching a request with a given context.
+    ///
+    /// Values are regex-escaped when the path is a regex or a glob, so they only match
+    /// themselves. A path with a variable that has no value, or whose value contains a slash,
+    /// matches nothing.
+    ///
+    pub fn for_path_in(pattern_type: &PatternType, path: &str, context: &ClaimContext) -> Self {
+        Self::for_path_in_with(pattern_type, path, context, Self::compile)
+    }
+
+    /// Like `for_path_in`, reusing the regexes compiled for earlier requests.
+    pub fn for_path_in_cached(
+        pattern_type: &PatternType,
+        path: &str,
+        context: &ClaimContext,
+        cache: &RegexCache,
+    ) -> Self {
+        Self::for_path_in_with(pattern_type, path, context, |f, re| cache.compile(f, re))
+    }
+
+    fn for_path_in_with<F>(
+        pattern_type: &PatternType,
+        path: &str,
+        context: &ClaimContext,
+        compile: F,
+    ) -> Self
+    where
+        F: Fn(&str, &str) -> Self,
+    {
+        if !has_variables(path) {
+            return Self::for_path(pattern_type, path);
+        }
+        let escaped = |v: &str| path_value(v, regex::escape);
+        let substituted = match pattern_type {
+            PatternType::Literal => {
+                substitute(path, context, raw, |v| path_value(v, raw)).map(Self::Literal)
+            }
+            PatternType::Glob => substitute(path, context, glob_body_to_regex, escaped)
+                .map(|re| compile(path, &format!("^{}$", re))),
+            PatternType::Regex => substitute(path, context, raw, escaped)
+                .map(|re| compile(path, &format!("^(?:{})$", re))),
+        };
+        substituted.unwrap_or_else(|| Self::Unresolved(path.to_string()))
+    }
+
+    /// Prepare the path of a DB ACL for matching; ACLs saved before paths were normalized may not be.
+    pub fn for_db_path(pattern_type: &PatternType, path: &str) -> Self {
+        Self::for_path(pattern_type, &normalize_acl_path(path, pattern_type))
+    }
+
+    /// Prepare the path of a DB ACL with template variables for matching a request.
+    pub fn for_db_path_in(pattern_type: &PatternType, path: &str, context: &ClaimContext) -> Self {
+        Self::for_path_in(
+            pattern_type,
+            &normalize_acl_path(path, pattern_type),
+            context,
+        )
+    }
+
+    /// Like `for_db_path_in`, reusing the regexes compiled for earlier requests.
+    pub fn for_db_path_in_cached(
+        pattern_type: &PatternType,
+        path: &str,
+        context: &ClaimContext,
+        cache: &RegexCache,
+    ) -> Self {
+        Self::for_path_in_cached(
+            pattern_type,
+            &normalize_acl_path(path, pattern_type),
+            context,
+            cache,
+        )
+    }
+
+    /// Prepare an ACL user for matching; users are unanchored regexes when they contain a wildcard.
+    pub fn for_user(user: &str) -> Self {
+        if !user.contains('*') {
+            return Self::Literal(user.to_string());
+        }
+        Self::compile(user, user)
+    }
+
+    /// Prepare an ACL user with template variables for matching a request; values are
+    /// regex-escaped when the user is a regex.
+    pub fn for_user_in(user: &str, context: &ClaimContext) -> Self {
+        Self::for_user_in_with(user, context, Self::compile)
+    }
+
+    /// Like `for_user_in`, reusing the regexes compiled for earlier requests.
+    pub fn for_user_in_cached(user: &str, context: &ClaimContext, cache: &RegexCache) -> Self {
+        Self::for_user_in_with(user, context, |f, re| cache.compile(f, re))
+    }
+
+    fn for_user_in_with<F>(user: &str, context: &ClaimContext, compile: F) -> Self
+    where
+        F: Fn(&str, &str) -> Self,
+    {
+        if !has_variables(user) {
+            return Self::for_user(user);
+        }
+        let substituted = if user.contains('*') {
+            substitute(user, context, raw, |v| Some(regex::escape(v))).map(|re| compile(user, &re))
+        } else {
+            substitute(user, context, raw, |v| Some(v.to_string())).map(Self::Literal)
+        };
+        substituted.unwrap_or_else(|| Self::Unresolved(user.to_string()))
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Self::Literal(s) | Self::Invalid(s, _) => s == value,
+            Self::Pattern(s, re) => s == value || re.is_match(value),
+            Self::Unresolved(_) => false,
+        }
+    }
+
+    // the compiled regex, if the field is a valid pattern
+    pub fn regex(&self) -> Option<&Regex> {
+        match self {
+            Self::Pattern(_, re) => Some(re),
+            _ => None,
+        }
+    }
+
+    // the error compiling the field, if it is an invalid pattern
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            Self::Invalid(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn glob_matches(glob: &str, path: &str) -> bool {
+        Matcher::for_path(&PatternType::Glob, glob).is_match(path)
+    }
+
+    #[test]
+    fn globs_match_whole_paths() {
+        assert!(glob_matches("/*.txt", "/aa123.txt"));
+        assert!(!glob_matches("/*.txt", "/foo/bar/aa123.txt"));
+        assert!(!glob_matches("/*.txt", "/x.txt.bak"));
+        assert!(!glob_matches("/*.txt", "/aXtxt"));
+        assert!(glob_matches("/**/*.txt", "/foo/bar/aa123.txt"));
+        assert!(glob_matches("/**/*.txt", "/aa123.txt"));
+        assert!(glob_matches("/data/**", "/data/a/b"));
+        assert!(glob_matches("/file?.txt", "/file1.txt"));
+        assert!(!glob_matches("/file?.txt", "/file12.txt"));
+        assert!(!glob_matches("/file?.txt", "/file/.txt"));
+    }
+
+    #[test]
+    fn regexes_are_anchored() {
+        let m = Matcher::for_path(&PatternType::Regex, "/.*.txt");
+        assert!(m.is_match("/foo/bar/aa123.txt"));
+        assert!(!m.is_match("/x.txt.bak"));
+        let m = Matcher::for_path(&PatternType::Regex, "/exam.*");
+        assert!(m.is_match("/exam123.txt"));
+        assert!(!m.is_match("/foo/exam123.txt"));
+    }
+
+    #[test]
+    fn literals_and_invalid_patterns_only_match_themselves() {
+        let m = Matcher::for_path(&PatternType::Literal, "/a.*");
+        assert!(m.is_match("/a.*"));
+        assert!(!m.is_match("/a.txt"));
+        let m = Matcher::for_path(&PatternType::Regex, "/a*(");
+        assert!(m.error().is_some());
+        assert!(m.is_match("/a*("));
+        assert!(!m.is_match("/a"));
+    }
+
+    #[test]
+    fn variables_are_substituted_with_escaped_values() {
+        let context = ClaimContext::for_subject("a.b@admin");
+        let m = Matcher::for_path_in(&PatternType::Glob, "/home/${sub}/**", &context);
+        assert!(m.is_match("/home/a.b@admin/x/y"));
+        assert!(!m.is_match("/home/aXb@admin/x"));
+        let m = Matcher::for_path_in(&PatternType::Regex, "/t/${tenant}/.*", &context);
+        assert!(m.is_match("/t/admin/x"));
+        let m = Matcher::for_path_in(&PatternType::Literal, "/u/${username}", &context);
+        assert!(m.is_match("/u/a.b"));
+        assert!(!m.is_match("/u/${username}"));
+        // unknown variables and values with slashes match nothing
+        let m = Matcher::for_path_in(&PatternType::Glob, "/p/${project}/**", &context);
+        assert!(!m.is_match("/p/${project}/x"));
+        let slash = ClaimContext::for_subject("x/..@admin");
+        let m = Matcher::for_path_in(&PatternType::Glob, "/home/${sub}/**", &slash);
+        assert!(!m.is_match("/home/x/..@admin/y"));
+        let m = Matcher::for_user_in("${username}", &context);
+        assert!(m.is_match("a.b"));
+        assert!(!m.is_match("aXb"));
+    }
+
+    #[test]
+    fn substituted_regexes_are_compiled_once() {
+        let cache = RegexCache::default();
+        let glob = |sub: &str| {
+            Matcher::for_path_in_cached(
+                &PatternType::Glob,
+                "/home/${username}/**",
+                &ClaimContext::for_subject(sub),
+                &cache,
+            )
+        };
+        assert!(glob("alice@tacc").is_match("/home/alice/x"));
+        assert!(glob("alice@tacc").is_match("/home/alice/y"));
+        assert!(!glob("bob@tacc").is_match("/home/alice/x"));
+        assert_eq!(cache.len(), 2);
+        // invalid regexes are remembered too
+        let context = ClaimContext::for_subject("alice@tacc");
+        for _ in 0..2 {
+            let m =
+                Matcher::for_path_in_cached(&PatternType::Regex, "/${username}(", &context, &cache);
+            assert!(m.error().is_some());
+        }
+        assert_eq!(cache.len(), 3);
+    }
+}
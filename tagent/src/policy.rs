@@ -0,0 +1,293 @@
+use config::Config;
+use diesel::SqliteConnection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::db::{
+    delete_acl_from_db_by_id, is_same_acl, retrieve_policy_acls, save_acl_with_policy_key,
+    update_acl_in_db_by_id,
+};
+use crate::index::{change_acls, AclIndex};
+use crate::models::{DbAcl, NewAclJson};
+use crate::representations::TagentError;
+
+// Declarative ACL policy files
+// ============================
+
+/// The creator recorded for ACLs created or updated from the policy file.
+pub const POLICY_FILE_CREATOR: &str = "policy-file";
+
+/// An ACL declared in a policy file.
+///
+/// `key` identifies the ACL across reloads of the file, so that changing the other fields of an
+/// entry updates the ACL in place instead of replacing it.
+///
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PolicyAcl {
+    pub key: String,
+    #[serde(flatten)]
+    pub acl: NewAclJson,
+}
+
+/// The contents of a policy file, e.g., in YAML:
+///
+/// ```yaml
+/// acls:
+///   - key: lab-read-data
+///     subject: group:lab
+///     action: Read
+///     path: /data/**
+///     pattern_type: glob
+///     user: self
+///     decision: Allow
+/// ```
+///
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct PolicyFile {
+    #[serde(default)]
+    pub acls: Vec<PolicyAcl>,
+}
+
+impl PolicyFile {
+    /// Read a policy file; the format (YAML or JSON) is determined by the file extension.
+    pub fn load(path: &Path) -> Result<Self, TagentError> {
+        let policy = Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize::<PolicyFile>()?;
+        policy.check()?;
+        Ok(policy)
+    }
+
+    // checks that every entry has a unique key and is a valid ACL
+    pub(crate) fn check(&self) -> Result<(), TagentError> {
+        let mut keys = HashSet::new();
+        for entry in &self.acls {
+            if entry.key.is_empty() {
+                return Err(TagentError::from(
+                    "Invalid policy file; every ACL needs a key",
+                ));
+            }
+            if !keys.insert(entry.key.as_str()) {
+                return Err(TagentError::from(format!(
+                    "Invalid policy file; duplicate key {}",
+                    entry.key
+                )));
+            }
+            if let Some(e) = entry.acl.validate().first() {
+                return Err(TagentError::from(format!(
+                    "Invalid policy file; ACL {}: {}",
+                    entry.key, e
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A change to the ACLs in the database, needed to make them match the policy file.
+#[derive(Debug, PartialEq)]
+pub enum PolicyChange {
+    Create(PolicyAcl),
+    Update(i32, PolicyAcl),
+    Delete(DbAcl),
+}
+
+fn describe(acl: &NewAclJson) -> String {
+    format!(
+        "{} {} for {} as {} on {} ({})",
+        acl.decision, acl.action, acl.subject, acl.user, acl.path, acl.pattern_type
+    )
+}
+
+impl fmt::Display for PolicyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create(p) => write!(f, "+ {}: {}", p.key, describe(&p.acl)),
+            Self::Update(id, p) => write!(f, "~ {} (ACL {}): {}", p.key, id, describe(&p.acl)),
+            Self::Delete(acl) => write!(
+                f,
+                "- {} (ACL {}): {} {} for {} as {} on {} ({})",
+                acl.policy_key.as_deref().unwrap_or_default(),
+                acl.id,
+                acl.decision,
+                acl.action,
+                acl.subject,
+                acl.user,
+                acl.path,
+                acl.pattern_type
+            ),
+        }
+    }
+}
+
+/// Compute the changes that make the ACLs managed by the policy file match the file.
+///
+/// `managed` are the ACLs that were created from the policy file; ACLs created through the API
+/// are never changed.
+///
+pub fn plan_changes(policy: &PolicyFile, managed: &[DbAcl]) -> Vec<PolicyChange> {
+    let by_key: HashMap<&str, &DbAcl> = managed
+        .iter()
+        .filter_map(|a| a.policy_key.as_deref().map(|k| (k, a)))
+        .collect();
+    let mut changes = Vec::new();
+    for entry in &policy.acls {
+        match by_key.get(entry.key.as_str()) {
+            None => changes.push(PolicyChange::Create(entry.clone())),
+            Some(db_acl) if !is_same_acl(db_acl, &entry.acl) => {
+                changes.push(PolicyChange::Update(db_acl.id, entry.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+    let keys: HashSet<&str> = policy.acls.iter().map(|e| e.key.as_str()).collect();
+    for db_acl in managed {
+        if db_acl
+            .policy_key
+            .as_deref()
+            .is_some_and(|k| !keys.contains(k))
+        {
+            changes.push(PolicyChange::Delete(db_acl.clone()));
+        }
+    }
+    changes
+}
+
+/// Compute the changes `reconcile` would make, without making them.
+pub fn plan_reconciliation(
+    conn: &mut SqliteConnection,
+    policy: &PolicyFile,
+) -> Result<Vec<PolicyChange>, diesel::result::Error> {
+    Ok(plan_changes(policy, &retrieve_policy_acls(conn)?))
+}
+
+/// Make the ACLs managed by the policy file match the file, and refresh the index.
+///
+/// All changes are made in a single transaction; the changes made are returned.
+///
+pub fn reconcile(
+    conn: &mut SqliteConnection,
+    index: &RwLock<AclIndex>,
+    policy: &PolicyFile,
+) -> Result<Vec<PolicyChange>, diesel::result::Error> {
+    change_acls(conn, index, |conn| {
+        let changes = plan_reconciliation(conn, policy)?;
+        for change in &changes {
+            match change {
+                PolicyChange::Create(p) => {
+                    save_acl_with_policy_key(conn, &p.acl, POLICY_FILE_CREATOR, Some(&p.key))?;
+                }
+                PolicyChange::Update(id, p) => {
+                    update_acl_in_db_by_id(conn, *id, &p.acl, POLICY_FILE_CREATOR, None)?;
+                }
+                PolicyChange::Delete(acl) => {
+                    delete_acl_from_db_by_id(conn, acl.id, POLICY_FILE_CREATOR, None)?;
+                }
+            }
+            info!("policy file: {}", change);
+        }
+        Ok(changes)
+    })
+}
+
+/// Read the policy file and reconcile the ACLs in the database with it.
+pub fn load_and_reconcile(
+    path: &Path,
+    conn: &mut SqliteConnection,
+    index: &RwLock<AclIndex>,
+) -> Result<Vec<PolicyChange>, TagentError> {
+    let policy = PolicyFile::load(path)?;
+    reconcile(conn, index, &policy).map_err(|e| {
+        TagentError::from(format!(
+            "Could not apply policy file {:?}; details: {}",
+            path, e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::db::test::{new_acl, test_connection};
+    use crate::db::{retrieve_all_acls, save_acl};
+    use crate::index::read_index;
+    use crate::models::{AclAction, AclDecision};
+
+    fn write_policy(dir: &tempfile::TempDir, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn policy_files_are_read_as_yaml_or_json() -> std::io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let yaml = write_policy(
+            &dir,
+            "policy.yaml",
+            "acls:\n  - key: lab\n    subject: group:lab\n    action: Read\n    path: /data/**\n    pattern_type: glob\n    user: self\n    decision: Allow\n",
+        );
+        let json = write_policy(
+            &dir,
+            "policy.json",
+            r#"{"acls": [{"key": "lab", "subject": "group:lab", "action": "Read", "path": "/data/**", "pattern_type": "glob", "user": "self", "decision": "Allow"}]}"#,
+        );
+        let from_yaml = PolicyFile::load(&yaml)?;
+        assert_eq!(from_yaml, PolicyFile::load(&json)?);
+        assert_eq!(from_yaml.acls[0].acl.subject, "group:lab");
+        assert!(from_yaml.acls[0].acl.enabled);
+        let duplicate = write_policy(
+            &dir,
+            "duplicate.yaml",
+            "acls:\n  - key: a\n    subject: s\n    action: Read\n    path: /a\n    user: self\n    decision: Allow\n  - key: a\n    subject: s\n    action: Read\n    path: /b\n    user: self\n    decision: Allow\n",
+        );
+        assert!(PolicyFile::load(&duplicate).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_only_changes_file_managed_acls() -> Result<(), TagentError> {
+        let mut conn = test_connection();
+        let index = RwLock::new(AclIndex::empty());
+        let api_acl = new_acl("jobs@admin", AclAction::Read, "/api", AclDecision::Allow, 0);
+        save_acl(&mut conn, &api_acl, "tenants@admin").map_err(|e| e.to_string())?;
+        let entry = |key: &str, path: &str| PolicyAcl {
+            key: key.to_string(),
+            acl: new_acl("jobs@admin", AclAction::Read, path, AclDecision::Allow, 0),
+        };
+        let mut policy = PolicyFile {
+            acls: vec![entry("a", "/a"), entry("b", "b")],
+        };
+        let changes = reconcile(&mut conn, &index, &policy).map_err(|e| e.to_string())?;
+        assert_eq!(changes.len(), 2);
+        // reconciling again changes nothing; paths are compared once normalized
+        assert!(plan_reconciliation(&mut conn, &policy)
+            .map_err(|e| e.to_string())?
+            .is_empty());
+
+        policy.acls = vec![entry("a", "/a2")];
+        let changes = plan_reconciliation(&mut conn, &policy).map_err(|e| e.to_string())?;
+        assert!(matches!(changes[0], PolicyChange::Update(_, _)));
+        assert!(matches!(changes[1], PolicyChange::Delete(_)));
+        reconcile(&mut conn, &index, &policy).map_err(|e| e.to_string())?;
+
+        let paths: Vec<String> = retrieve_all_acls(&mut conn)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|a| a.path)
+            .collect();
+        assert_eq!(paths, vec!["/api", "/a2"]);
+        assert_eq!(read_index(&index).acls_for_subject("jobs@admin").len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::claims::ClaimContext;
+use crate::engine::PolicyEngine;
+use crate::models::{AclAction, AclDecision};
+
+// POSIX permissions
+// =================
+
+/// Permission bits, as in the mode of a file.
+pub const READ: u32 = 4;
+pub const WRITE: u32 = 2;
+pub const EXECUTE: u32 = 1;
+
+/// A local user: its uid and the gids of the groups it belongs to, including its primary group.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LocalUser {
+    pub uid: u32,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+}
+
+impl LocalUser {
+    /// Look up a user by name with NSS, i.e., in `/etc/passwd`, LDAP, etc.
+    pub fn lookup(name: &str) -> Option<Self> {
+        let c_name = CString::new(name).ok()?;
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if rc != 0 || result.is_null() {
+            return None;
+        }
+        let mut count: libc::c_int = 32;
+        loop {
+            let mut gids = vec![0 as libc::gid_t; count as usize];
+            let requested = count;
+            let rc = unsafe {
+                libc::getgrouplist(c_name.as_ptr(), pwd.pw_gid, gids.as_mut_ptr(), &mut count)
+            };
+            if rc >= 0 {
+                gids.truncate(count as usize);
+                return Some(LocalUser {
+                    uid: pwd.pw_uid,
+                    gids,
+                });
+            }
+            // the list was too short; `count` is the number needed, where it is reported
+            count = count.max(requested * 2);
+        }
+    }
+}
+
+/// The tag of an entry of a POSIX ACL, with the uid or gid of named users and groups.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+/// An entry of a POSIX ACL, e.g., `user:1001:rw-` in the format of `getfacl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PosixAclEntry {
+    pub tag: AclTag,
+    pub perms: u32,
+}
+
+// the extended attribute holding the access ACL of a file, and the version of its format
+const ACL_XATTR: &str = "system.posix_acl_access";
+const ACL_XATTR_VERSION: u32 = 2;
+
+/// Parse the access ACL of a file as stored in its `system.posix_acl_access` extended attribute:
+/// a version followed by entries of a tag, permissions and id, in little-endian order.
+pub fn parse_acl_xattr(value: &[u8]) -> Result<Vec<PosixAclEntry>, String> {
+    let u16_at = |i: usize| u16::from_le_bytes([value[i], value[i + 1]]);
+    let u32_at =
+        |i: usize| u32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]]);
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) {
+        return Err(format!("invalid ACL of {} bytes", value.len()));
+    }
+    if u32_at(0) != ACL_XATTR_VERSION {
+        return Err(format!("unsupported ACL version {}", u32_at(0)));
+    }
+    (4..value.len())
+        .step_by(8)
+        .map(|i| {
+            let id = u32_at(i + 4);
+            let tag = match u16_at(i) {
+                0x01 => AclTag::UserObj,
+                0x02 => AclTag::User(id),
+                0x04 => AclTag::GroupObj,
+                0x08 => AclTag::Group(id),
+                0x10 => AclTag::Mask,
+                0x20 => AclTag::Other,
+                t => return Err(format!("invalid ACL entry tag {:#x}", t)),
+            };
+            Ok(PosixAclEntry {
+                tag,
+                perms: u32::from(u16_at(i + 2)) & 0o7,
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn read_acl_xattr(path: &Path) -> Option<Vec<u8>> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let c_name = CString::new(ACL_XATTR).ok()?;
+    // files without an extended ACL, or on filesystems without them, only have a mode
+    let size = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return None;
+    }
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    // an ACL that changed in between grants nothing
+    if size < 0 {
+        return Some(Vec::new());
+    }
+    value.truncate(size as usize);
+    Some(value)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_acl_xattr(_path: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+/// The owner, group, mode and ACL of a file; the ACL has the entries equivalent to the mode when
+/// the file has no extended ACL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePermissions {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub acl: Vec<PosixAclEntry>,
+}
+
+impl FilePermissions {
+    pub fn from_mode(uid: u32, gid: u32, mode: u32) -> Self {
+        FilePermissions {
+            uid,
+            gid,
+            mode: mode & 0o777,
+            acl: vec![
+                PosixAclEntry {
+                    tag: AclTag::UserObj,
+                    perms: (mode >> 6) & 0o7,
+                },
+                PosixAclEntry {
+                    tag: AclTag::GroupObj,
+                    perms: (mode >> 3) & 0o7,
+                },
+                PosixAclEntry {
+                    tag: AclTag::Other,
+                    perms: mode & 0o7,
+                },
+            ],
+        }
+    }
+
+    /// Read the permissions of a file, following symbolic links.
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let mut permissions = Self::from_mode(metadata.uid(), metadata.gid(), metadata.mode());
+        if let Some(value) = read_acl_xattr(path) {
+            match parse_acl_xattr(&value) {
+                Ok(acl) => permissions.acl = acl,
+                // an ACL that cannot be parsed grants nothing
+                Err(e) => {
+                    info!("ignoring the ACL of {:?}; {}", path, e);
+                    permissions.acl = Vec::new();
+                }
+            }
+        }
+        Ok(permissions)
+    }
+
+    /// Whether `user` is granted all of `perms`, following the access check algorithm of POSIX
+    /// ACLs (see acl(5)): the owner entry applies to the owner, then a named user entry, then the
+    /// group entries of the user's groups, then the other entry. Entries other than the owner's
+    /// and other's are limited by the mask. Root is granted everything but executing files no one
+    /// can execute.
+    pub fn allows(&self, user: &LocalUser, perms: u32) -> bool {
+        if user.uid == 0 {
+            return perms & EXECUTE == 0 || self.mode & 0o111 != 0;
+        }
+        let entry = |tag: AclTag| self.acl.iter().find(|e| e.tag == tag).map(|e| e.perms);
+        let mask = entry(AclTag::Mask).unwrap_or(0o7);
+        let granted = |p: u32| p & perms == perms;
+        if user.uid == self.uid {
+            return entry(AclTag::UserObj).is_some_and(granted);
+        }
+        if let Some(p) = entry(AclTag::User(user.uid)) {
+            return granted(p & mask);
+        }
+        let groups: Vec<u32> = self
+            .acl
+            .iter()
+            .filter_map(|e| match e.tag {
+                AclTag::GroupObj if user.gids.contains(&self.gid) => Some(e.perms),
+                AclTag::Group(gid) if user.gids.contains(&gid) => Some(e.perms),
+                _ => None,
+            })
+            .collect();
+        if !groups.is_empty() {
+            return groups.into_iter().any(|p| granted(p & mask));
+        }
+        entry(AclTag::Other).is_some_and(granted)
+    }
+}
+
+pub(crate) fn default_user_variable() -> String {
+    String::from("username")
+}
+
+/// The settings of the POSIX permissions engine.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PosixSettings {
+    // the directory request paths are relative to; defaults to the root directory of the agent
+    #[serde(default)]
+    pub root_directory: Option<PathBuf>,
+    // local users by username; other usernames are looked up with NSS
+    #[serde(default)]
+    pub users: HashMap<String, LocalUser>,
+    // the template variable whose value is the username of the subject, for requests as `self`;
+    // variables can be taken from a JWT claim with `claim_variables`
+    #[serde(default = "default_user_variable")]
+    pub user_variable: String,
+    // whether permissions that are granted allow requests, instead of only constraining them
+    #[serde(default)]
+    pub grant: bool,
+}
+
+/// The engine deciding requests with the permissions of the local user the request's user is
+/// mapped to on the files under a root directory.
+///
+/// A request is denied unless the user can search (execute) every directory from the root to the
+/// file, and has the permissions the action needs: read for `List` and `Read`, execute for
+/// `Execute`, write for `Write` (on the parent directory, with search, for a file that does not
+/// exist yet) and write and search on the parent directory for `Delete`. Requests of users that
+/// are not local, or are root, are denied: root would be granted everything. The engine has no
+/// decision for `Admin` and `Impersonate`, whatever the user, since they are not file operations,
+/// nor for files that do not exist otherwise. When permissions are granted,
+/// the engine allows the request if `grant` is set, and has no decision otherwise, so that it
+/// only constrains the decisions of other engines.
+///
+pub struct PosixEngine {
+    root: PathBuf,
+    settings: PosixSettings,
+}
+
+impl PosixEngine {
+    pub fn new(root_dir: &Path, settings: PosixSettings) -> Self {
+        PosixEngine {
+            root: settings
+                .root_directory
+                .clone()
+                .unwrap_or_else(|| root_dir.to_path_buf()),
+            settings,
+        }
+    }
+
+    // the local user a request is made as; `self` is the user of the subject. Root is never a
+    // user requests are made as
+    fn local_user(&self, user: &str, context: &ClaimContext) -> Option<LocalUser> {
+        let name = if user == "self" {
+            context.get(&self.settings.user_variable)?
+        } else {
+            user
+        };
+        match self.settings.users.get(name) {
+            Some(u) => Some(u.clone()),
+            None => LocalUser::lookup(name),
+        }
+        .filter(|u| u.uid != 0)
+    }
+
+    // the file under the root that a request path refers to; None if the path leaves the root
+    fn file_for(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(c) => file.push(c),
+                Component::RootDir | Component::CurDir => (),
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(file)
+    }
+
+    // whether the user can search every directory from the root to `dir`, inclusive
+    fn can_reach(&self, user: &LocalUser, dir: &Path) -> bool {
+        dir.ancestors()
+            .take_while(|d| d.starts_with(&self.root))
+            .all(|d| FilePermissions::read(d).is_ok_and(|p| p.allows(user, EXECUTE)))
+    }
+
+    // whether the user has `perms` on `file`, which it can reach; None if the file does not exist
+    fn check(&self, user: &LocalUser, file: &Path, perms: u32) -> Option<bool> {
+        let permissions = FilePermissions::read(file).ok()?;
+        let parent = file.parent().unwrap_or(file);
+        Some(permissions.allows(user, perms) && (file == self.root || self.can_reach(user, parent)))
+    }
+}
+
+impl PolicyEngine for PosixEngine {
+    fn name(&self) -> String {
+        format!("POSIX permissions under {:?}", self.root)
+    }
+
+    fn authorize(
+        &self,
+        user: &str,
+        action: &AclAction,
+        path: &str,
+        context: &ClaimContext,
+    ) -> Option<AclDecision> {
+        // managing ACLs and acting for users are not file operations; this is decided before the
+        // user is resolved, so that subjects without a local account are not denied them
+        if matches!(action, AclAction::Admin | AclAction::Impersonate) {
+            return None;
+        }
+        // requests whose user or file cannot be determined fail closed
+        let (local_user, file) = match (self.local_user(user, context), self.file_for(path)) {
+            (Some(u), Some(f)) => (u, f),
+            (u, _) => {
+                debug!(
+                    "denying {} as {}; {}",
+                    path,
+                    user,
+                    if u.is_none() {
+                        "no local user other than root"
+                    } else {
+                        "the path leaves the root directory"
+                    }
+                );
+                return Some(AclDecision::Deny);
+            }
+        };
+        let parent = file.parent().map(Path::to_path_buf);
+        let allowed = match action {
+            AclAction::List | AclAction::Read => self.check(&local_user, &file, READ),
+            AclAction::Execute => self.check(&local_user, &file, EXECUTE),
+            AclAction::Write if file.exists() => self.check(&local_user, &file, WRITE),
+            AclAction::Write | AclAction::Delete if file != self.root => {
+                self.check(&local_user, &parent?, WRITE | EXECUTE)
+            }
+            AclAction::Write | AclAction::Delete | AclAction::Admin | AclAction::Impersonate => {
+                None
+            }
+        }?;
+        debug!(
+            "POSIX permissions of uid {} on {:?} for {}: {}",
+            local_user.uid, file, action, allowed
+        );
+        match (allowed, self.settings.grant) {
+            (false, _) => Some(AclDecision::Deny),
+            (true, true) => Some(AclDecision::Allow),
+            (true, false) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn entry(tag: AclTag, perms: u32) -> PosixAclEntry {
+        PosixAclEntry { tag, perms }
+    }
+
+    fn user(uid: u32, gids: &[u32]) -> LocalUser {
+        LocalUser {
+            uid,
+            gids: gids.to_vec(),
+        }
+    }
+
+    #[test]
+    fn extended_acls_are_checked_like_getfacl_shows_them() {
+        // user::rw-, user:1002:rwx, group::r--, group:300:rw-, mask::r-x, other::---
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perms, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 7, 1002),
+            (0x04, 4, u32::MAX),
+            (0x08, 6, 300),
+            (0x10, 5, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            value.extend(tag.to_le_bytes());
+            value.extend(perms.to_le_bytes());
+            value.extend(id.to_le_bytes());
+        }
+        let acl = parse_acl_xattr(&value).unwrap();
+        assert_eq!(acl[1], entry(AclTag::User(1002), 7));
+        let file = FilePermissions {
+            acl,
+            ..FilePermissions::from_mode(1001, 200, 0o650)
+        };
+
+        assert!(file.allows(&user(1001, &[]), READ | WRITE));
+        assert!(!file.allows(&user(1001, &[]), EXECUTE));
+        // the mask limits named users and groups
+        assert!(file.allows(&user(1002, &[]), READ | EXECUTE));
+        assert!(!file.allows(&user(1002, &[]), WRITE));
+        assert!(file.allows(&user(1003, &[300]), READ));
+        assert!(!file.allows(&user(1003, &[300]), WRITE));
+        assert!(file.allows(&user(1003, &[200]), READ));
+        assert!(!file.allows(&user(1003, &[]), READ));
+        assert!(file.allows(&user(0, &[]), READ | WRITE));
+
+        assert!(parse_acl_xattr(&value[..10]).is_err());
+    }
+
+    #[test]
+    fn files_are_checked_with_the_mapped_user() -> std::io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let private = dir.path().join("private");
+        std::fs::create_dir(&private)?;
+        std::fs::write(dir.path().join("a.txt"), "a")?;
+        std::fs::write(private.join("b.txt"), "b")?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755))?;
+        std::fs::set_permissions(
+            dir.path().join("a.txt"),
+            std::fs::Permissions::from_mode(0o644),
+        )?;
+        std::fs::set_permissions(
+            private.join("b.txt"),
+            std::fs::Permissions::from_mode(0o644),
+        )?;
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o700))?;
+        // alice is neither the owner nor in the group of the files
+        let settings = PosixSettings {
+            root_directory: None,
+            users: HashMap::from([(String::from("alice"), user(64000, &[64000]))]),
+            user_variable: default_user_variable(),
+            grant: false,
+        };
+        let engine = PosixEngine::new(dir.path(), settings);
+        let context = ClaimContext::for_subject("alice@tacc");
+        let decide = |act: AclAction, pth: &str| engine.authorize("self", &act, pth, &context);
+
+        assert_eq!(decide(AclAction::Read, "/a.txt"), None);
+        assert_eq!(decide(AclAction::Write, "/a.txt"), Some(AclDecision::Deny));
+        assert_eq!(
+            decide(AclAction::Write, "/new.txt"),
+            Some(AclDecision::Deny)
+        );
+        assert_eq!(
+            decide(AclAction::Read, "/private/b.txt"),
+            Some(AclDecision::Deny)
+        );
+        assert_eq!(decide(AclAction::Read, "/missing.txt"), None);
+        assert_eq!(
+            decide(AclAction::Read, "/../etc/passwd"),
+            Some(AclDecision::Deny)
+        );
+        assert_eq!(
+            engine.authorize(
+                "self",
+                &AclAction::Read,
+                "/a.txt",
+                &ClaimContext::for_subject("nosuchuser@tacc")
+            ),
+            Some(AclDecision::Deny)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn root_is_never_granted() -> std::io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        std::fs::write(dir.path().join("a.txt"), "a")?;
+        let settings = PosixSettings {
+            root_directory: None,
+            users: HashMap::from([(String::from("admin"), user(0, &[0]))]),
+            user_variable: default_user_variable(),
+            grant: true,
+        };
+        let engine = PosixEngine::new(dir.path(), settings);
+        for (sub, usr) in [
+            ("admin@tacc", "self"),
+            ("root@tacc", "self"),
+            ("jobs@tacc", "root"),
+        ] {
+            for act in [AclAction::Read, AclAction::Write, AclAction::Delete] {
+                assert_eq!(
+                    engine.authorize(usr, &act, "/a.txt", &ClaimContext::for_subject(sub)),
+                    Some(AclDecision::Deny)
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::models::DbMembership;
+
+// Group and role memberships
+// ==========================
+
+/// The memberships of all groups and roles, used to expand a subject into the principals whose
+/// ACLs apply to it.
+///
+/// Groups and roles can be members of other groups and roles, so memberships form a graph;
+/// expansion follows the graph transitively and stops at principals it has already visited, so
+/// cycles in the memberships never cause a loop.
+///
+#[derive(Debug, Default)]
+pub struct Memberships {
+    // maps a member to the principals it is directly a member of
+    parents: HashMap<String, Vec<String>>,
+}
+
+impl Memberships {
+    pub fn new(memberships: Vec<DbMembership>) -> Self {
+        let mut parents: HashMap<String, Vec<String>> = HashMap::new();
+        for m in memberships {
+            parents.entry(m.member).or_default().push(m.principal);
+        }
+        Memberships { parents }
+    }
+
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Returns the subject followed by every group and role it is a member of, directly or
+    /// through nested memberships. Each principal is returned once.
+    pub fn principals_for(&self, sub: &str) -> Vec<String> {
+        let mut result = vec![sub.to_string()];
+        let mut visited: HashSet<&str> = HashSet::from([sub]);
+        let mut queue: VecDeque<&str> = VecDeque::from([sub]);
+        while let Some(member) = queue.pop_front() {
+            for parent in self.parents.get(member).into_iter().flatten() {
+                // a principal reached twice is either shared by several paths or part of a cycle
+                if !visited.insert(parent) {
+                    continue;
+                }
+                result.push(parent.clone());
+                queue.push_back(parent);
+            }
+        }
+        result
+    }
+
+    // returns every subject, group and role that is a member of at least one group or role
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.parents.keys().map(|m| m.as_str())
+    }
+
+    // whether adding `member` to `principal` would make `principal` a member of itself
+    pub fn would_create_cycle(&self, principal: &str, member: &str) -> bool {
+        principal == member || self.principals_for(principal).iter().any(|p| p == member)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn membership(principal: &str, member: &str) -> DbMembership {
+        DbMembership {
+            id: 0,
+            principal: principal.to_string(),
+            member: member.to_string(),
+            create_by: String::from("tenants@admin"),
+            create_time: String::from("2022-03-16T12:00:00+00:00"),
+        }
+    }
+
+    #[test]
+    fn nested_memberships_are_expanded() {
+        let m = Memberships::new(vec![
+            membership("group:lab", "jobs@admin"),
+            membership("group:dept", "group:lab"),
+            membership("role:reader", "group:dept"),
+            membership("group:other", "files@admin"),
+        ]);
+        assert_eq!(
+            m.principals_for("jobs@admin"),
+            vec!["jobs@admin", "group:lab", "group:dept", "role:reader"]
+        );
+        assert_eq!(m.principals_for("apps@admin"), vec!["apps@admin"]);
+    }
+
+    #[test]
+    fn cycles_terminate_and_are_detected() {
+        let m = Memberships::new(vec![
+            membership("group:a", "jobs@admin"),
+            membership("group:b", "group:a"),
+            membership("group:a", "group:b"),
+        ]);
+        assert_eq!(
+            m.principals_for("jobs@admin"),
+            vec!["jobs@admin", "group:a", "group:b"]
+        );
+        let m = Memberships::new(vec![membership("group:b", "group:a")]);
+        assert!(m.would_create_cycle("group:a", "group:b"));
+        assert!(m.would_create_cycle("group:a", "group:a"));
+        assert!(!m.would_create_cycle("group:b", "group:a"));
+    }
+}
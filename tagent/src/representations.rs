@@ -1,13 +1,64 @@
-use super::models::DbAcl;
-use actix_web::{HttpResponse, ResponseError};
+use super::analysis::AclFinding;
+use super::audit::AuditLog;
+use super::conditions::split_items;
+use super::engine::PolicyEngine;
+use super::explain::DecisionExplanation;
+use super::export::{DocumentFormat, ImportMode, ImportSummary};
+use super::index::{change_acls, AclIndex};
+use super::models::{
+    AccessRequestState, AclAction, AclDecision, AclFilter, AclOperation, AclSortField, AclStatus,
+    AuthzCheckJson, AuthzPolicy, DbAccessRequest, DbAcl, DbAclHistory, DbMembership, DbPrincipal,
+    FieldError, PatternType, SortOrder,
+};
+use super::runas::RunAsSettings;
+use super::simulate::{DecisionFlip, ProposedChanges};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{Duration, Utc};
+use diesel::SqliteConnection;
 use jwt_simple::algorithms::RS256PublicKey;
-use serde::Serialize;
-use std::{fmt, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
 pub struct AppState {
     pub app_version: String,
     pub root_dir: PathBuf,
     pub pub_key: RS256PublicKey,
+    pub admin_subjects: Vec<String>,
+    pub policy: AuthzPolicy,
+    // all ACLs, compiled for evaluation; refreshed whenever ACLs change
+    pub acl_index: Arc<RwLock<AclIndex>>,
+    // decides authorization requests; evaluates `acl_index`, possibly combined with other engines
+    pub engine: Box<dyn PolicyEngine>,
+    // the template variables of ACLs that are taken from JWT claims, by variable name
+    pub claim_variables: HashMap<String, String>,
+    // the most recent authorization checks
+    pub audit_log: AuditLog,
+    // how long approved access requests grant access, unless the request or approval says otherwise
+    pub access_grant_duration: Duration,
+    // the JWT claim naming the user a token acts for, if any
+    pub delegation_claim: Option<String>,
+    // the local users file operations run as, if any
+    pub run_as: Option<RunAsSettings>,
+}
+
+impl AppState {
+    /// Change ACLs, groups or roles in a database transaction and refresh `acl_index`; see
+    /// `index::change_acls`.
+    pub fn change_acls<T, F>(
+        &self,
+        conn: &mut SqliteConnection,
+        change: F,
+    ) -> Result<T, diesel::result::Error>
+    where
+        F: FnOnce(&mut SqliteConnection) -> Result<T, diesel::result::Error>,
+    {
+        change_acls(conn, &self.acl_index, change)
+    }
 }
 
 // Ready Endpoint ----------
@@ -29,6 +80,9 @@ pub struct ErrorRsp {
     pub status: String,
     pub result: String,
     pub version: String,
+    // the problems with individual fields of the request, if any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
 }
 
 // The Error type that can convert to a actix_web::ResponseError
@@ -36,11 +90,54 @@ pub struct ErrorRsp {
 pub struct TagentError {
     message: String,
     version: String,
+    status: StatusCode,
+    errors: Vec<FieldError>,
 }
 
 impl TagentError {
     pub fn new(message: String, version: String) -> Self {
-        TagentError { message, version }
+        Self::new_with_status(message, version, StatusCode::BAD_REQUEST)
+    }
+
+    pub fn new_with_status(message: String, version: String, status: StatusCode) -> Self {
+        TagentError {
+            message,
+            version,
+            status,
+            errors: Vec::new(),
+        }
+    }
+
+    // error returned when fields of a request are not valid
+    pub fn invalid_fields(message: String, version: String, errors: Vec<FieldError>) -> Self {
+        TagentError {
+            errors,
+            ..Self::new(message, version)
+        }
+    }
+
+    pub fn not_found(message: String, version: String) -> Self {
+        Self::new_with_status(message, version, StatusCode::NOT_FOUND)
+    }
+
+    // error returned when a conditional request's precondition, e.g., If-Match, does not hold
+    pub fn precondition_failed(message: String, version: String) -> Self {
+        Self::new_with_status(message, version, StatusCode::PRECONDITION_FAILED)
+    }
+
+    // error returned when a request cannot be made in the current state of the resource, e.g.,
+    // deciding an access request that was already decided
+    pub fn conflict(message: String, version: String) -> Self {
+        Self::new_with_status(message, version, StatusCode::CONFLICT)
+    }
+
+    // error returned when an authenticated subject is not permitted to make a request
+    pub fn unauthorized(message: String, version: String) -> Self {
+        Self::new_with_status(message, version, StatusCode::UNAUTHORIZED)
+    }
+
+    pub fn forbidden(message: String, version: String) -> Self {
+        Self::new_with_status(message, version, StatusCode::FORBIDDEN)
     }
 
     pub fn new_with_version(message: String) -> Self {
@@ -62,13 +159,10 @@ impl From<String> for TagentError {
 
 impl From<TagentError> for std::io::Error {
     fn from(tagent_error: TagentError) -> Self {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!(
-                "TagentError (version: {}): {}",
-                tagent_error.message, tagent_error.version
-            ),
-        )
+        std::io::Error::other(format!(
+            "TagentError (version: {}): {}",
+            tagent_error.message, tagent_error.version
+        ))
     }
 }
 
@@ -91,6 +185,10 @@ impl fmt::Display for TagentError {
 }
 
 impl ResponseError for TagentError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
     fn error_response(&self) -> HttpResponse {
         let m = &self.message;
         let v = &self.version;
@@ -99,9 +197,10 @@ impl ResponseError for TagentError {
             message: m.to_string(),
             version: v.to_string(),
             result: String::from("none"),
+            errors: self.errors.clone(),
         };
         let body = serde_json::to_value(&r).unwrap().to_string();
-        HttpResponse::BadRequest().body(body)
+        HttpResponse::build(self.status_code()).body(body)
     }
 }
 
@@ -116,17 +215,142 @@ pub struct AclStringRsp {
     pub version: String,
 }
 
+// response for the endpoints that create or update an ACL; warnings contains the problems found
+// when analyzing the ACLs of the subject after the change
+#[derive(Serialize)]
+pub struct AclChangeRsp {
+    pub message: String,
+    pub status: String,
+    pub result: Acl,
+    pub warnings: Vec<AclFinding>,
+    pub version: String,
+}
+
+#[derive(Serialize)]
+pub struct AclAnalysisRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: Vec<AclFinding>,
+}
+
+// response for the isauthz endpoint; includes the policy mode used to reach the decision, and the
+// evaluation trace when one was requested
+#[derive(Serialize)]
+pub struct AclAuthzRsp {
+    pub message: String,
+    pub status: String,
+    pub result: String,
+    pub mode: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<DecisionExplanation>,
+}
+
+// the result of a single check in a batch authorization request
+#[derive(Debug, Serialize)]
+pub struct AuthzCheckResult {
+    pub subject: String,
+    pub user: String,
+    pub action: AclAction,
+    pub path: String,
+    pub result: bool,
+}
+
+// response for the batch isauthz endpoint; the results are in the order of the checks
+#[derive(Serialize)]
+pub struct AclAuthzBatchRsp {
+    pub message: String,
+    pub status: String,
+    pub mode: String,
+    pub version: String,
+    pub result: Vec<AuthzCheckResult>,
+}
+
+// query parameters of the isauthz endpoint
+#[derive(Debug, Deserialize)]
+pub struct AclAuthzQuery {
+    // return the evaluation trace along with the decision
+    #[serde(default)]
+    pub explain: bool,
+}
+
+// the actions a subject is allowed on a path, from lowest to highest
+#[derive(Debug, Serialize)]
+pub struct EffectivePermissions {
+    pub subject: String,
+    pub user: String,
+    pub path: String,
+    pub actions: Vec<AclAction>,
+    // the highest action allowed, if any
+    pub highest: Option<AclAction>,
+}
+
+impl EffectivePermissions {
+    pub fn new(subject: &str, user: &str, path: &str, actions: Vec<AclAction>) -> Self {
+        EffectivePermissions {
+            subject: subject.to_string(),
+            user: user.to_string(),
+            path: path.to_string(),
+            highest: actions.last().copied(),
+            actions,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EffectivePermissionsRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: EffectivePermissions,
+}
+
+// response for the who-can endpoint; one entry for every subject with access to the path
+#[derive(Serialize)]
+pub struct WhoCanRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: Vec<EffectivePermissions>,
+}
+
+// query parameters of the who-can endpoint
+#[derive(Debug, Deserialize)]
+pub struct WhoCanQuery {
+    // the user to check; defaults to "self"
+    pub user: Option<String>,
+    // only list the subjects allowed this action
+    pub action: Option<AclAction>,
+}
+
 // A representation of an ACL that can be used in JSON responses that contain an ACL result or a Vector of ACLs
 #[derive(Debug, Serialize)]
 pub struct Acl {
     pub id: i32,
     pub subject: String,
-    pub action: String,
+    pub action: AclAction,
     pub path: String,
+    pub pattern_type: PatternType,
     pub user: String,
-    pub decision: String,
+    pub decision: AclDecision,
+    pub priority: i32,
+    pub valid_from: Option<String>,
+    pub valid_until: Option<String>,
+    pub enabled: bool,
+    // the conditions of the ACL, if any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source_cidrs: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<String>,
+    // whether the ACL currently applies
+    pub status: AclStatus,
+    // the key of the policy file entry that manages the ACL, if any
+    pub policy_key: Option<String>,
     pub create_by: String,
     pub create_time: String,
+    // incremented whenever the ACL is changed; the ACL's ETag
+    pub revision: i32,
 }
 
 impl Acl {
@@ -134,24 +358,175 @@ impl Acl {
         Acl {
             id: db_acl.id,
             subject: db_acl.subject.clone(),
-            action: db_acl.action.clone(),
+            action: db_acl.action,
             path: db_acl.path.clone(),
+            pattern_type: db_acl.pattern_type,
             user: db_acl.user.clone(),
-            decision: db_acl.decision.clone(),
+            decision: db_acl.decision,
+            priority: db_acl.priority,
+            valid_from: db_acl.valid_from.clone(),
+            valid_until: db_acl.valid_until.clone(),
+            enabled: db_acl.enabled,
+            source_cidrs: split_items(db_acl.source_cidrs.as_deref()),
+            time_windows: split_items(db_acl.time_windows.as_deref()),
+            status: db_acl.status_at(&Utc::now()),
+            policy_key: db_acl.policy_key.clone(),
             create_by: db_acl.create_by.clone(),
             create_time: db_acl.create_time.clone(),
+            revision: db_acl.revision,
+        }
+    }
+}
+
+// query parameters of the endpoints that list ACLs; every filter that is set has to match
+#[derive(Debug, Deserialize)]
+pub struct AclListingQuery {
+    // only list ACLs with this status
+    pub status: Option<AclStatus>,
+    pub subject: Option<String>,
+    pub user: Option<String>,
+    pub action: Option<AclAction>,
+    pub decision: Option<AclDecision>,
+    // only list ACLs whose path contains this string
+    pub path: Option<String>,
+    pub create_by: Option<String>,
+    #[serde(default)]
+    pub sort: AclSortField,
+    #[serde(default)]
+    pub order: SortOrder,
+    // the number of matching ACLs to skip, and the maximum number to return
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl AclListingQuery {
+    pub fn to_filter(&self) -> AclFilter {
+        AclFilter {
+            subject: self.subject.clone(),
+            user: self.user.clone(),
+            action: self.action,
+            decision: self.decision,
+            path: self.path.clone(),
+            create_by: self.create_by.clone(),
+            status: self.status,
+            visible_to: None,
+            sort: self.sort,
+            order: self.order,
         }
     }
 }
 
+// query parameters of the export endpoint
+#[derive(Debug, Deserialize)]
+pub struct AclExportQuery {
+    #[serde(default)]
+    pub format: DocumentFormat,
+}
+
+// query parameters of the import endpoint
+#[derive(Debug, Deserialize)]
+pub struct AclImportQuery {
+    #[serde(default)]
+    pub mode: ImportMode,
+}
+
+// the body of a request to simulate changes to ACLs; the decisions of the probes, and of up to
+// `sample` of the most recent authorization checks, are compared before and after the changes
+#[derive(Debug, Deserialize)]
+pub struct SimulationJson {
+    #[serde(default)]
+    pub changes: ProposedChanges,
+    #[serde(default)]
+    pub probes: Vec<AuthzCheckJson>,
+    #[serde(default)]
+    pub sample: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimulationResult {
+    // the number of requests evaluated
+    pub evaluated: usize,
+    pub flips: Vec<DecisionFlip>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimulationRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: SimulationResult,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AclImportRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: ImportSummary,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AclListingRsp {
     pub message: String,
     pub status: String,
     pub version: String,
+    // the number of ACLs matching the query, before the offset and limit are applied
+    pub total: usize,
+    pub offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
     pub result: Vec<Acl>,
 }
 
+// a change in the history of an ACL, with the ACL before and after the change
+#[derive(Debug, Serialize)]
+pub struct AclHistoryEntry {
+    pub id: i32,
+    pub acl_id: i32,
+    pub version: i32,
+    pub operation: AclOperation,
+    pub actor: String,
+    pub time: String,
+    pub before: Option<DbAcl>,
+    pub after: Option<DbAcl>,
+}
+
+impl AclHistoryEntry {
+    pub fn from_db_history(entry: &DbAclHistory) -> Self {
+        Self {
+            id: entry.id,
+            acl_id: entry.acl_id,
+            version: entry.version,
+            operation: entry.operation,
+            actor: entry.actor.clone(),
+            time: entry.time.clone(),
+            before: entry.before(),
+            after: entry.after(),
+        }
+    }
+}
+
+// query parameters of the endpoint listing the changes to all ACLs
+#[derive(Debug, Deserialize)]
+pub struct AclHistoryQuery {
+    // only list the changes made by this actor
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AclHistoryRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    // the number of changes before the offset and limit are applied
+    pub total: usize,
+    pub result: Vec<AclHistoryEntry>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AclByIdRsp {
     pub message: String,
@@ -160,6 +535,82 @@ pub struct AclByIdRsp {
     pub result: Acl,
 }
 
+// Access Request Endpoints ----------
+
+// query parameters of the endpoint listing access requests
+#[derive(Debug, Deserialize)]
+pub struct AccessRequestQuery {
+    pub state: Option<AccessRequestState>,
+    // only list the requests made by this subject
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessRequestRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: DbAccessRequest,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessRequestListingRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    // the number of requests matching the query, before the offset and limit are applied
+    pub total: usize,
+    pub result: Vec<DbAccessRequest>,
+}
+
+// Group and Role Endpoints ----------
+
+// A representation of a group or role with its direct members; `principal` is the name ACLs use
+// to refer to it, e.g., `group:lab`
+#[derive(Debug, Serialize)]
+pub struct Principal {
+    pub id: i32,
+    pub kind: String,
+    pub name: String,
+    pub principal: String,
+    pub members: Vec<String>,
+    pub create_by: String,
+    pub create_time: String,
+}
+
+impl Principal {
+    pub fn from_db_principal(db_principal: &DbPrincipal, members: &[DbMembership]) -> Self {
+        Principal {
+            id: db_principal.id,
+            kind: db_principal.kind.clone(),
+            name: db_principal.name.clone(),
+            principal: format!("{}:{}", db_principal.kind, db_principal.name),
+            members: members.iter().map(|m| m.member.clone()).collect(),
+            create_by: db_principal.create_by.clone(),
+            create_time: db_principal.create_time.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrincipalListingRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: Vec<Principal>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrincipalRsp {
+    pub message: String,
+    pub status: String,
+    pub version: String,
+    pub result: Principal,
+}
+
 // Files Endpoints ----------
 
 #[derive(Serialize)]
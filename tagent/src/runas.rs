@@ -0,0 +1,281 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+
+use crate::posix::{default_user_variable, LocalUser};
+
+// Running file operations as local users
+// ======================================
+
+fn default_fallback_user() -> String {
+    String::from("nobody")
+}
+
+/// The settings for running file operations as the local users requests are made for.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RunAsSettings {
+    // local users by username; other usernames are looked up with NSS
+    #[serde(default)]
+    pub users: HashMap<String, LocalUser>,
+    // the template variable whose value is the username of the subject, for requests it makes for
+    // itself; variables can be taken from a JWT claim with `claim_variables`
+    #[serde(default = "default_user_variable")]
+    pub user_variable: String,
+    // the user that operations run as when the user of a request is not known or not local
+    #[serde(default = "default_fallback_user")]
+    pub fallback_user: String,
+}
+
+impl Default for RunAsSettings {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            user_variable: default_user_variable(),
+            fallback_user: default_fallback_user(),
+        }
+    }
+}
+
+impl RunAsSettings {
+    fn local_user(&self, name: &str) -> Option<LocalUser> {
+        match self.users.get(name) {
+            Some(u) => Some(u.clone()),
+            None => LocalUser::lookup(name),
+        }
+    }
+
+    /// The local user to run the file operations of a user as; the fallback user when the user is
+    /// not known, has no local account, or is root. Fails if the fallback user cannot be found
+    /// either, so that operations never run with the privileges of the agent instead.
+    pub fn identity_for(&self, name: Option<&str>) -> Result<LocalUser, String> {
+        if let Some(user) = name.and_then(|n| self.local_user(n)) {
+            if user.uid != 0 {
+                return Ok(user);
+            }
+        }
+        debug!(
+            "user {:?} is not a local user; running as {}",
+            name, self.fallback_user
+        );
+        self.local_user(&self.fallback_user)
+            .filter(|u| u.uid != 0)
+            .ok_or_else(|| {
+                format!(
+                    "fallback user {} is not a local user other than root",
+                    self.fallback_user
+                )
+            })
+    }
+}
+
+/// Whether the agent can switch to other users, i.e., whether it runs as root.
+pub fn can_switch_users() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// the identity the file system checks the operations of a thread against
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq)]
+struct FsIdentity {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+#[cfg(target_os = "linux")]
+impl FsIdentity {
+    fn current() -> io::Result<Self> {
+        // setfsuid and setfsgid change nothing given an invalid id, and return the current one
+        let uid = unsafe { libc::setfsuid(libc::uid_t::MAX) } as libc::uid_t;
+        let gid = unsafe { libc::setfsgid(libc::gid_t::MAX) } as libc::gid_t;
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut groups = vec![0 as libc::gid_t; count as usize];
+        let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        groups.truncate(count as usize);
+        Ok(FsIdentity { uid, gid, groups })
+    }
+
+    // switch the calling thread to the identity; the groups are set first, while the thread still
+    // has the privilege to
+    fn switch(&self) -> io::Result<()> {
+        // glibc's setgroups changes the groups of every thread of the process; the system call
+        // only changes those of the calling thread
+        let rc =
+            unsafe { libc::syscall(libc::SYS_setgroups, self.groups.len(), self.groups.as_ptr()) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            libc::setfsgid(self.gid);
+            libc::setfsuid(self.uid);
+        }
+        // setfsuid and setfsgid do not report errors, so check that the ids took effect
+        if FsIdentity::current()? != *self {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("could not switch to uid {} and gid {}", self.uid, self.gid),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// switches the calling thread back to the agent's identity when dropped
+#[cfg(target_os = "linux")]
+struct Restore(FsIdentity);
+
+#[cfg(target_os = "linux")]
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.switch() {
+            // the thread would go on to serve other requests as the wrong user
+            log::error!(
+                "could not restore the identity of the agent; details: {}",
+                e
+            );
+            std::process::abort();
+        }
+    }
+}
+
+/// Run a file operation with the file system identity of a local user: the files it creates are
+/// owned by the user and its primary group, i.e., the first of its gids, and its permissions are
+/// those of the user and all of its groups.
+///
+/// The identity is switched with `setfsuid`, `setfsgid` and `setgroups` for the calling thread
+/// only, so the operation must not move to another thread; run it with `web::block`. The identity
+/// of the agent is restored when the operation returns. When the agent does not run as root it
+/// cannot switch users, and the operation runs as the agent.
+///
+#[cfg(target_os = "linux")]
+pub fn run_as<T, F>(user: &LocalUser, op: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    if !can_switch_users() {
+        return op();
+    }
+    let gid = *user.gids.first().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("user {} has no group", user.uid),
+        )
+    })?;
+    let _restore = Restore(FsIdentity::current()?);
+    FsIdentity {
+        uid: user.uid,
+        gid,
+        groups: user.gids.clone(),
+    }
+    .switch()?;
+    op()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run_as<T, F>(_user: &LocalUser, op: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    if !can_switch_users() {
+        return op();
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "running file operations as other users is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+
+    fn settings() -> RunAsSettings {
+        RunAsSettings {
+            users: HashMap::from([
+                (
+                    String::from("jdoe"),
+                    LocalUser {
+                        uid: 1001,
+                        gids: vec![1001, 2000],
+                    },
+                ),
+                (
+                    String::from("guest"),
+                    LocalUser {
+                        uid: 65534,
+                        gids: vec![65534],
+                    },
+                ),
+            ]),
+            user_variable: default_user_variable(),
+            fallback_user: String::from("guest"),
+        }
+    }
+
+    #[test]
+    fn unknown_users_run_as_the_fallback_user() {
+        let settings = settings();
+        assert_eq!(settings.identity_for(Some("jdoe")).map(|u| u.uid), Ok(1001));
+        assert_eq!(
+            settings.identity_for(Some("root")).map(|u| u.uid),
+            Ok(65534)
+        );
+        assert_eq!(
+            settings
+                .identity_for(Some("no-such-user-here"))
+                .map(|u| u.uid),
+            Ok(65534)
+        );
+        assert_eq!(settings.identity_for(None).map(|u| u.uid), Ok(65534));
+        let missing = RunAsSettings {
+            fallback_user: String::from("no-such-user-here"),
+            ..settings.clone()
+        };
+        assert!(missing.identity_for(None).is_err());
+        let root = RunAsSettings {
+            fallback_user: String::from("root"),
+            ..settings
+        };
+        assert!(root.identity_for(None).is_err());
+    }
+
+    #[test]
+    fn operations_run_as_the_user() -> io::Result<()> {
+        if !can_switch_users() {
+            return Ok(());
+        }
+        let dir = tempfile::TempDir::new()?;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o777))?;
+        let private = dir.path().join("private");
+        std::fs::create_dir(&private)?;
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o700))?;
+        let guest = LocalUser {
+            uid: 65534,
+            gids: vec![65534],
+        };
+
+        let shared = dir.path().join("shared.txt");
+        run_as(&guest, || std::fs::File::create(&shared))?;
+        let metadata = std::fs::metadata(&shared)?;
+        assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534));
+        let denied = run_as(&guest, || std::fs::File::create(private.join("a.txt")));
+        assert_eq!(
+            denied.map(|_| ()).map_err(|e| e.kind()),
+            Err(io::ErrorKind::PermissionDenied)
+        );
+        // the agent is itself again
+        let own = private.join("b.txt");
+        std::fs::File::create(&own)?;
+        assert_eq!(std::fs::metadata(&own)?.uid(), unsafe { libc::geteuid() });
+        Ok(())
+    }
+}
@@ -1,3 +1,22 @@
+table! {
+    access_requests (id) {
+        id -> Integer,
+        subject -> Text,
+        action -> Text,
+        path -> Text,
+        pattern_type -> Text,
+        user -> Text,
+        justification -> Text,
+        valid_until -> Nullable<Text>,
+        state -> Text,
+        create_time -> Text,
+        reviewed_by -> Nullable<Text>,
+        review_time -> Nullable<Text>,
+        review_comment -> Nullable<Text>,
+        acl_id -> Nullable<Integer>,
+    }
+}
+
 table! {
     acls (id) {
         id -> Integer,
@@ -8,5 +27,49 @@ table! {
         create_by -> Text,
         create_time -> Text,
         decision -> Text,
+        priority -> Integer,
+        pattern_type -> Text,
+        valid_from -> Nullable<Text>,
+        valid_until -> Nullable<Text>,
+        enabled -> Bool,
+        policy_key -> Nullable<Text>,
+        revision -> Integer,
+        source_cidrs -> Nullable<Text>,
+        time_windows -> Nullable<Text>,
+    }
+}
+
+table! {
+    acl_history (id) {
+        id -> Integer,
+        acl_id -> Integer,
+        version -> Integer,
+        operation -> Text,
+        actor -> Text,
+        time -> Text,
+        before_acl -> Nullable<Text>,
+        after_acl -> Nullable<Text>,
+    }
+}
+
+table! {
+    memberships (id) {
+        id -> Integer,
+        principal -> Text,
+        member -> Text,
+        create_by -> Text,
+        create_time -> Text,
+    }
+}
+
+table! {
+    principals (id) {
+        id -> Integer,
+        kind -> Text,
+        name -> Text,
+        create_by -> Text,
+        create_time -> Text,
     }
 }
+
+allow_tables_to_appear_in_same_query!(access_requests, acl_history, acls, memberships, principals,);
@@ -0,0 +1,335 @@
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::audit::AuditEntry;
+use crate::claims::ClaimContext;
+use crate::db::{
+    delete_acl_from_db_by_id, retrieve_all_acls, retrieve_all_memberships, rolled_back, save_acl,
+    update_acl_in_db_by_id, AclChange,
+};
+use crate::engine::PolicyEngine;
+use crate::index::AclIndex;
+use crate::models::{AclAction, AclDecision, AuthzCheckJson, AuthzPolicy, FieldError, NewAclJson};
+
+// Simulating changes to ACLs
+// ==========================
+
+/// A proposed update of an ACL.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProposedUpdate {
+    pub id: i32,
+    pub acl: NewAclJson,
+}
+
+/// A set of proposed changes to ACLs; they are applied in order: creates, updates, then deletes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ProposedChanges {
+    #[serde(default)]
+    pub create: Vec<NewAclJson>,
+    #[serde(default)]
+    pub update: Vec<ProposedUpdate>,
+    #[serde(default)]
+    pub delete: Vec<i32>,
+}
+
+impl ProposedChanges {
+    /// Check the proposed ACLs as they would be checked when created or updated; returns all of
+    /// the problems found, with the fields prefixed with the position of the change, e.g.,
+    /// `update[1].acl.path`.
+    pub fn check(&self, claim_variables: &HashMap<String, String>) -> Vec<FieldError> {
+        let acls = self
+            .create
+            .iter()
+            .enumerate()
+            .map(|(i, acl)| (format!("create[{}]", i), acl))
+            .chain(
+                self.update
+                    .iter()
+                    .enumerate()
+                    .map(|(i, u)| (format!("update[{}].acl", i), &u.acl)),
+            );
+        let mut errors = Vec::new();
+        for (parent, acl) in acls {
+            errors.extend(
+                acl.validate()
+                    .into_iter()
+                    .chain(acl.validate_variables(claim_variables))
+                    .map(|e| e.nested(&parent)),
+            );
+        }
+        errors
+    }
+}
+
+/// The ACL indexes before and after a set of proposed changes.
+pub struct Simulation {
+    pub current: AclIndex,
+    pub proposed: AclIndex,
+}
+
+// builds the index of the ACLs and memberships in the db
+fn index_of(conn: &mut SqliteConnection) -> Result<AclIndex, diesel::result::Error> {
+    Ok(AclIndex::new(
+        retrieve_all_acls(conn)?,
+        retrieve_all_memberships(conn)?,
+    ))
+}
+
+/// Apply proposed changes to the ACLs in a transaction that is rolled back, returning the indexes
+/// of the ACLs before and after the changes.
+///
+/// The changes are made exactly as they would be by the API, so the proposed index is the one the
+/// server would use after making them. Updates and deletes of ACLs that do not exist are reported
+/// as errors of the `id` field of the change.
+///
+pub fn simulate_changes(
+    conn: &mut SqliteConnection,
+    changes: &ProposedChanges,
+    actor: &str,
+) -> Result<Result<Simulation, Vec<FieldError>>, diesel::result::Error> {
+    rolled_back(conn, |conn| {
+        let current = index_of(conn)?;
+        let mut errors = Vec::new();
+        for acl in &changes.create {
+            save_acl(conn, acl, actor)?;
+        }
+        for (i, update) in changes.update.iter().enumerate() {
+            if update_acl_in_db_by_id(conn, update.id, &update.acl, actor, None)?
+                == AclChange::NotFound
+            {
+                errors.push(not_found(&format!("update[{}].id", i), update.id));
+            }
+        }
+        for (i, id) in changes.delete.iter().enumerate() {
+            if delete_acl_from_db_by_id(conn, *id, actor, None)? == AclChange::NotFound {
+                errors.push(not_found(&format!("delete[{}]", i), *id));
+            }
+        }
+        if !errors.is_empty() {
+            return Ok(Err(errors));
+        }
+        Ok(Ok(Simulation {
+            current,
+            proposed: index_of(conn)?,
+        }))
+    })
+}
+
+fn not_found(field: &str, id: i32) -> FieldError {
+    FieldError::new(field, format!("there is no ACL with id {}", id))
+}
+
+/// A request to evaluate against the current and proposed ACLs, with the context it is evaluated
+/// in; `sampled` is true for requests taken from the audit log.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub check: AuthzCheckJson,
+    pub context: ClaimContext,
+    pub sampled: bool,
+}
+
+impl Probe {
+    pub fn from_audit(entry: AuditEntry) -> Self {
+        Probe {
+            check: entry.check,
+            context: entry.context,
+            sampled: true,
+        }
+    }
+}
+
+/// A request whose decision would change.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DecisionFlip {
+    pub subject: String,
+    pub user: String,
+    pub action: AclAction,
+    pub path: String,
+    pub before: AclDecision,
+    pub after: AclDecision,
+    pub sampled: bool,
+}
+
+fn decision(allowed: bool) -> AclDecision {
+    if allowed {
+        AclDecision::Allow
+    } else {
+        AclDecision::Deny
+    }
+}
+
+impl Simulation {
+    /// Evaluate the probes through `engine`, deciding with the current and then the proposed
+    /// ACLs; returns the probes whose decision would change, in order.
+    pub fn flips(
+        &self,
+        engine: &dyn PolicyEngine,
+        policy: &AuthzPolicy,
+        probes: &[Probe],
+    ) -> Vec<DecisionFlip> {
+        probes
+            .iter()
+            .filter_map(|p| {
+                let c = &p.check;
+                let before = policy.is_allowed(
+                    engine.authorize_with(&self.current, &c.user, &c.action, &c.path, &p.context),
+                    &c.path,
+                );
+                let after = policy.is_allowed(
+                    engine.authorize_with(&self.proposed, &c.user, &c.action, &c.path, &p.context),
+                    &c.path,
+                );
+                if before == after {
+                    return None;
+                }
+                Some(DecisionFlip {
+                    subject: c.subject.clone(),
+                    user: c.user.clone(),
+                    action: c.action,
+                    path: c.path.clone(),
+                    before: decision(before),
+                    after: decision(after),
+                    sampled: p.sampled,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test::{new_acl, test_connection, test_policy};
+    use crate::engine::{build_engine, AclEngine, EngineConfig, EnginePrecedence};
+    use crate::models::PolicyMode;
+    use crate::representations::TagentError;
+    use std::io::Write;
+    use std::sync::{Arc, RwLock};
+
+    fn probe(policy: &AuthzPolicy, sub: &str, action: AclAction, path: &str) -> Probe {
+        Probe {
+            check: AuthzCheckJson {
+                subject: sub.to_string(),
+                user: String::from("self"),
+                action,
+                path: path.to_string(),
+            },
+            context: policy.context_for(sub),
+            sampled: false,
+        }
+    }
+
+    #[test]
+    fn flips_are_reported_without_changing_acls() -> Result<(), diesel::result::Error> {
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let broad = save_acl(
+            &mut conn,
+            &new_acl(
+                "jobs@admin",
+                AclAction::Write,
+                "/data/.*",
+                AclDecision::Allow,
+                0,
+            ),
+            "tenants@admin",
+        )?;
+        let changes = ProposedChanges {
+            create: vec![new_acl(
+                "apps@admin",
+                AclAction::Read,
+                "/data/.*",
+                AclDecision::Allow,
+                0,
+            )],
+            update: vec![ProposedUpdate {
+                id: broad,
+                acl: new_acl(
+                    "jobs@admin",
+                    AclAction::Read,
+                    "/data/.*",
+                    AclDecision::Allow,
+                    0,
+                ),
+            }],
+            delete: Vec::new(),
+        };
+        let probes = [
+            probe(&policy, "jobs@admin", AclAction::Write, "/data/a"),
+            probe(&policy, "jobs@admin", AclAction::Read, "/data/a"),
+            probe(&policy, "apps@admin", AclAction::List, "/data/a"),
+        ];
+        let simulation = simulate_changes(&mut conn, &changes, "tenants@admin")?.unwrap();
+        let engine = AclEngine::new(Arc::new(RwLock::new(AclIndex::empty())), policy.mode);
+        let flips = simulation.flips(&engine, &policy, &probes);
+        let flipped: Vec<(&str, AclAction, AclDecision)> = flips
+            .iter()
+            .map(|f| (f.subject.as_str(), f.action, f.after))
+            .collect();
+        assert_eq!(
+            flipped,
+            vec![
+                ("jobs@admin", AclAction::Write, AclDecision::Deny),
+                ("apps@admin", AclAction::List, AclDecision::Allow)
+            ]
+        );
+        // nothing was committed
+        let acls = retrieve_all_acls(&mut conn)?;
+        assert_eq!(acls.len(), 1);
+        assert_eq!(acls[0].action, AclAction::Write);
+
+        let missing = ProposedChanges {
+            delete: vec![broad, 99],
+            ..ProposedChanges::default()
+        };
+        let errors = simulate_changes(&mut conn, &missing, "tenants@admin")?.err();
+        assert_eq!(
+            errors.map(|e| e.into_iter().map(|e| e.field).collect::<Vec<_>>()),
+            Some(vec![String::from("delete[1]")])
+        );
+        assert_eq!(retrieve_all_acls(&mut conn)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn flips_account_for_other_engines() -> Result<(), TagentError> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("static.yaml");
+        std::fs::File::create(&path)?.write_all(
+            b"acls:\n  - key: lab\n    subject: apps@admin\n    action: Read\n    path: /data/**\n    pattern_type: glob\n    user: self\n    decision: Deny\n",
+        )?;
+        let mut conn = test_connection();
+        let policy = test_policy(PolicyMode::DenyOverrides);
+        let index = Arc::new(RwLock::new(AclIndex::empty()));
+        let engine = build_engine(
+            &[EngineConfig::Acls, EngineConfig::Static { path }],
+            EnginePrecedence::DenyOverrides,
+            policy.mode,
+            &index,
+            dir.path(),
+        )?;
+        let changes = ProposedChanges {
+            create: vec![new_acl(
+                "apps@admin",
+                AclAction::Read,
+                "/.*",
+                AclDecision::Allow,
+                0,
+            )],
+            ..ProposedChanges::default()
+        };
+        let probes = [
+            probe(&policy, "apps@admin", AclAction::Read, "/data/a"),
+            probe(&policy, "apps@admin", AclAction::Read, "/tmp/a"),
+        ];
+        let simulation = simulate_changes(&mut conn, &changes, "tenants@admin")
+            .map_err(|e| e.to_string())?
+            .unwrap();
+        // the static ACL still denies reading /data, whatever the stored ACLs
+        let flips = simulation.flips(engine.as_ref(), &policy, &probes);
+        let flipped: Vec<&str> = flips.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(flipped, vec!["/tmp/a"]);
+        Ok(())
+    }
+}